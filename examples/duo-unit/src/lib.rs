@@ -6,7 +6,7 @@
 
 #[cfg(test)]
 mod tests {
-    use anyhow::{anyhow, Result};
+    use anyhow::Result;
     use libfalcon::{unit::gb, Runner};
 
     #[tokio::test]
@@ -19,35 +19,12 @@ mod tests {
 
         d.launch().await?;
 
-        // set ipv6 link local addresses
-        d.exec(violin, "ipadm create-addr -t -T addrconf vioif0/v6")
-            .await?;
-        d.exec(piano, "ipadm create-addr -t -T addrconf vioif0/v6")
-            .await?;
-
-        // get piano addresses
-        let piano_addr = d
-            .exec(piano, "ipadm show-addr -p -o ADDR vioif0/v6")
-            .await?;
-
-        // wait for piano address to become ready
-        let mut retries = 0;
-        loop {
-            let state =
-                d.exec(piano, "ipadm show-addr -po state vioif0/v6").await?;
-            if state == "ok" {
-                break;
-            }
-            retries += 1;
-            if retries >= 10 {
-                return Err(anyhow!("timed out waiting for vioif0/v6"));
-            }
-            std::thread::sleep(std::time::Duration::from_secs(1))
-        }
+        // set ipv6 link local addresses, waiting for them to become ready
+        d.enable_v6_linklocal(violin, "vioif0").await?;
+        let piano_addr = d.enable_v6_linklocal(piano, "vioif0").await?;
 
         // do a ping
-        let ping_cmd =
-            format!("ping {} 1", piano_addr.strip_suffix("/10").unwrap());
+        let ping_cmd = format!("ping {} 1", piano_addr);
         d.exec(violin, ping_cmd.as_str()).await?;
 
         Ok(())
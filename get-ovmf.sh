@@ -2,12 +2,14 @@
 
 set -e
 
+base_url=${FALCON_ASSET_BASE_URL:-https://oxide-falcon-assets.s3.us-west-2.amazonaws.com}
+
 mkdir -p .img
 pushd .img
 
 if [[ ! -f OVMF_CODE.fd ]]; then
     echo "Pulling OVMF_CODE.fd"
-    curl -OL https://oxide-falcon-assets.s3.us-west-2.amazonaws.com/OVMF_CODE.fd
+    curl -OL $base_url/OVMF_CODE.fd
 fi
 
 echo "Copying OVMF to /var/ovmf"
@@ -0,0 +1,44 @@
+// Derive the propolis revision falcon is built against from the propolis
+// dependencies pinned in the workspace Cargo.toml, so `PROPOLIS_REV` cannot
+// drift from the client and server configuration crates actually in use.
+
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    let manifest = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("..")
+        .join("Cargo.toml");
+    println!("cargo:rerun-if-changed={}", manifest.display());
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let text = fs::read_to_string(&manifest).unwrap_or_else(|e| {
+        panic!("read workspace manifest {}: {}", manifest.display(), e)
+    });
+
+    let mut revs: Vec<&str> = text
+        .lines()
+        .filter(|l| l.contains("github.com/oxidecomputer/propolis"))
+        .filter_map(|l| {
+            let rest = &l[l.find("rev")? + "rev".len()..];
+            let rest = rest.trim_start().strip_prefix('=')?.trim_start();
+            let rest = rest.strip_prefix('"')?;
+            Some(&rest[..rest.find('"')?])
+        })
+        .collect();
+    revs.sort_unstable();
+    revs.dedup();
+
+    match revs.as_slice() {
+        [rev] => println!("cargo:rustc-env=FALCON_PROPOLIS_REV={rev}"),
+        [] => panic!(
+            "no propolis git dependency with a rev in {}",
+            manifest.display()
+        ),
+        _ => panic!(
+            "propolis dependencies in {} pin different revs: {}",
+            manifest.display(),
+            revs.join(", ")
+        ),
+    }
+}
@@ -5,28 +5,33 @@
 // Copyright 2022 Oxide Computer Company
 
 use std::fs;
-use std::process::Command;
 use std::{
     io::{stdout, Write},
     net::{IpAddr, Ipv4Addr, SocketAddr},
     os::unix::prelude::AsRawFd,
+    time::Duration,
 };
 
 use anyhow::{anyhow, Context};
 use camino::{Utf8Path, Utf8PathBuf};
 use clap::ArgAction;
 use colored::*;
-use futures::{SinkExt, StreamExt};
+use futures::{future::join_all, SinkExt, StreamExt};
 use propolis_client::{types::InstanceStateRequested, Client};
-use ron::de::from_str;
-use slog::{o, warn, Drain, Level, Logger};
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::Serialize;
+use slog::{o, Drain, Level, Logger};
 use tabwriter::TabWriter;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio_tungstenite::tungstenite::Message;
 
 use clap::Parser;
 
-use crate::{dataset, error::Error, Deployment, Runner, DEFAULT_FALCON_DIR};
+use crate::serial::{console_url, ConsoleOffset};
+use crate::{
+    dataset, error::Error, topology_path, zfs, Deployment, Endpoint, LinkRef,
+    LogFormat, Node, Runner, DEFAULT_FALCON_DIR,
+};
 
 pub enum RunMode {
     Unspec,
@@ -37,26 +42,30 @@ pub enum RunMode {
 #[derive(Parser)]
 #[clap(version = "0.1")]
 #[clap(infer_subcommands = true, styles = oxide_cli_style())]
-struct Opts {
+pub struct Opts {
     #[clap(short, long, action = ArgAction::Count)]
-    verbose: u8,
+    pub verbose: u8,
 
     #[clap(subcommand)]
-    subcmd: SubCommand,
+    pub subcmd: SubCommand,
 }
 
 #[derive(Parser)]
-enum SubCommand {
+pub enum SubCommand {
     #[clap(about = "run topology preflight")]
     Preflight(CmdPreflight),
     #[clap(about = "launch topology")]
     Launch(CmdLaunch),
     #[clap(about = "destroy topology")]
     Destroy(CmdDestroy),
+    #[clap(about = "stop topology, keeping node disks")]
+    Stop(CmdStop),
     #[clap(about = "get a serial console session for the specified vm")]
     Serial(CmdSerial),
     #[clap(about = "display topology information")]
     Info(CmdInfo),
+    #[clap(about = "show which vms are running")]
+    Status(CmdStatus),
     #[clap(about = "reboot a vm")]
     Reboot(CmdReboot),
     #[clap(about = "stop a vm's hypervisor")]
@@ -69,131 +78,391 @@ enum SubCommand {
     Netdestroy(CmdNetDestroy),
     #[clap(about = "snapshot a node")]
     Snapshot(CmdSnapshot),
+    #[clap(about = "restore a stopped node's disk from a snapshot")]
+    Restore(CmdRestore),
     #[clap(about = "execute a command on a node")]
     Exec(CmdExec),
+    #[clap(about = "print the propolis-server log of a node")]
+    Plog(CmdPlog),
+    #[clap(about = "kill propolis instances orphaned by falcon")]
+    Reap(CmdReap),
+    #[clap(about = "delete base images not used by the topology")]
+    Prune(CmdPrune),
+    #[clap(about = "delete links and disks no known deployment owns")]
+    Gc(CmdGc),
+    #[clap(about = "list locally installed base images")]
+    Images(CmdImages),
 }
 
 #[derive(Parser)]
 #[clap(infer_subcommands = true)]
-struct CmdLaunch {
+pub struct CmdLaunch {
     /// The propolis-server binary to use
     #[clap(short, long)]
-    propolis: Option<String>,
+    pub propolis: Option<String>,
+
+    /// The RUST_LOG filter to run propolis-server with, e.g. debug
+    #[clap(long, value_name = "FILTER")]
+    pub propolis_log: Option<String>,
+
+    /// Print what would be launched without launching anything
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// Accept a propolis-server of a revision falcon was not built against
+    #[clap(long)]
+    pub skip_version_check: bool,
+
+    /// Launch even if the nodes need more memory than the host has free
+    #[clap(long)]
+    pub overcommit: bool,
+
+    /// The path of the falcon output directory
+    #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
+    pub falcon_dir: Utf8PathBuf,
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+pub struct CmdPreflight {
+    /// Accept a propolis-server of a revision falcon was not built against
+    #[clap(long)]
+    pub skip_version_check: bool,
+
+    /// Launch even if the nodes need more memory than the host has free
+    #[clap(long)]
+    pub overcommit: bool,
 
     /// The path of the falcon output directory
     #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
-    falcon_dir: Utf8PathBuf,
+    pub falcon_dir: Utf8PathBuf,
 }
 
 #[derive(Parser)]
 #[clap(infer_subcommands = true)]
-struct CmdPreflight {
+pub struct CmdDestroy {
+    /// Stop guests gracefully first, waiting up to the given number of
+    /// seconds for each before killing it
+    #[clap(long, value_name = "SECS", num_args = 0..=1, default_missing_value = "60")]
+    pub graceful: Option<u64>,
+
     /// The path of the falcon output directory
     #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
-    falcon_dir: Utf8PathBuf,
+    pub falcon_dir: Utf8PathBuf,
 }
 
 #[derive(Parser)]
 #[clap(infer_subcommands = true)]
-struct CmdDestroy {
+pub struct CmdStop {
+    /// Stop guests gracefully first, waiting up to the given number of
+    /// seconds for each before killing it
+    #[clap(long, value_name = "SECS", num_args = 0..=1, default_missing_value = "60")]
+    pub graceful: Option<u64>,
+
     /// The path of the falcon output directory
     #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
-    falcon_dir: Utf8PathBuf,
+    pub falcon_dir: Utf8PathBuf,
 }
 
 #[derive(Parser)]
 #[clap(infer_subcommands = true)]
-struct CmdSerial {
+pub struct CmdSerial {
     /// Name of the VM to establish a serial connection to
-    vm_name: String,
+    pub vm_name: String,
+
+    /// Replay the console history buffered by propolis, e.g. boot messages
+    #[clap(long)]
+    pub from_start: bool,
 
     /// The path of the falcon output directory
     #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
-    falcon_dir: Utf8PathBuf,
+    pub falcon_dir: Utf8PathBuf,
 }
 
 #[derive(Parser)]
 #[clap(infer_subcommands = true)]
-struct CmdReboot {
+pub struct CmdReboot {
     /// Name of the VM to reboot
-    vm_name: String,
+    pub vm_name: Option<String>,
+
+    /// Reboot all vms in the topology, in boot priority order
+    #[clap(short, long)]
+    pub all: bool,
 
     /// The path of the falcon output directory
     #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
-    falcon_dir: Utf8PathBuf,
+    pub falcon_dir: Utf8PathBuf,
 }
 
 #[derive(Parser)]
 #[clap(infer_subcommands = true)]
-struct CmdHyperstop {
+pub struct CmdHyperstop {
     /// Name of the vm to stop
-    vm_name: Option<String>,
+    pub vm_name: Option<String>,
 
     /// Stop all vms in the topology
     #[clap(short, long)]
-    all: bool,
+    pub all: bool,
+
+    /// Stop the guest gracefully first, waiting up to the given number of
+    /// seconds before killing it
+    #[clap(long, value_name = "SECS", num_args = 0..=1, default_missing_value = "60")]
+    pub graceful: Option<u64>,
 
     /// The path of the falcon output directory
     #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
-    falcon_dir: Utf8PathBuf,
+    pub falcon_dir: Utf8PathBuf,
 }
 
 #[derive(Parser)]
 #[clap(infer_subcommands = true)]
-struct CmdHyperstart {
+pub struct CmdHyperstart {
     /// The propolis-server binary to use
     #[clap(short, long)]
-    propolis: Option<String>,
+    pub propolis: Option<String>,
 
     /// Name of the vm to start
-    vm_name: Option<String>,
+    pub vm_name: Option<String>,
 
-    /// Start all vms in the topology
+    /// Start all vms in the topology, in boot priority order
     #[clap(short, long)]
-    all: bool,
+    pub all: bool,
 
     /// The path of the falcon output directory
     #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
-    falcon_dir: Utf8PathBuf,
+    pub falcon_dir: Utf8PathBuf,
 }
 
 #[derive(Parser)]
 #[clap(infer_subcommands = true)]
-struct CmdNetCreate {}
+pub struct CmdNetCreate {
+    /// Only create the link between the two named nodes
+    #[clap(long, num_args = 2, value_names = ["A", "B"])]
+    pub link: Option<Vec<String>>,
+}
 
 #[derive(Parser)]
 #[clap(infer_subcommands = true)]
-struct CmdNetDestroy {}
+pub struct CmdNetDestroy {
+    /// Only destroy the link between the two named nodes
+    #[clap(long, num_args = 2, value_names = ["A", "B"])]
+    pub link: Option<Vec<String>>,
+}
 
 #[derive(Parser)]
 #[clap(infer_subcommands = true)]
-struct CmdSnapshot {
-    /// Name of the VM to snaphost
-    vm_name: String,
+pub struct CmdSnapshot {
+    /// Name of the VM to snaphost. With --all, the prefix of the new images
+    pub vm_name: String,
 
     /// What to name the new snapshot
-    snapshot_name: String,
+    #[clap(required_unless_present = "all", conflicts_with = "all")]
+    pub snapshot_name: Option<String>,
+
+    /// Snapshot all nodes, naming each image {vm_name}_{node}
+    #[clap(long)]
+    pub all: bool,
 
     /// The path of the falcon output directory
     #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
-    falcon_dir: Utf8PathBuf,
+    pub falcon_dir: Utf8PathBuf,
 }
 
 #[derive(Parser)]
 #[clap(infer_subcommands = true)]
-struct CmdInfo {}
+pub struct CmdRestore {
+    /// Name of the VM to restore
+    pub vm_name: String,
+
+    /// Snapshot of the VM's disk, or image created by snapshot, to restore
+    pub snapshot_name: String,
+
+    /// The path of the falcon output directory
+    #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
+    pub falcon_dir: Utf8PathBuf,
+}
 
 #[derive(Parser)]
 #[clap(infer_subcommands = true)]
-struct CmdExec {
+pub struct CmdInfo {
+    /// Print the topology as a Graphviz DOT graph
+    #[clap(long, conflicts_with = "json")]
+    pub dot: bool,
+
+    /// Print the topology as JSON
+    #[clap(long)]
+    pub json: bool,
+}
+
+/// The JSON representation of a deployment printed by `info --json`. This is
+/// kept separate from the topology types so the output stays stable as those
+/// change.
+#[derive(Serialize)]
+struct InfoView {
+    name: String,
+    nodes: Vec<NodeView>,
+    links: Vec<[EndpointView; 2]>,
+    ext_links: Vec<ExtLinkView>,
+}
+
+#[derive(Serialize)]
+struct NodeView {
+    name: String,
+    image: String,
+    radix: usize,
+    uuid: uuid::Uuid,
+    mounts: Vec<MountView>,
+}
+
+#[derive(Serialize)]
+struct MountView {
+    source: String,
+    destination: String,
+}
+
+#[derive(Serialize)]
+struct EndpointView {
     node: String,
-    command: String,
+    index: usize,
+    kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    guest_ifname: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ExtLinkView {
+    endpoint: EndpointView,
+    host_ifx: String,
+}
+
+impl InfoView {
+    fn new(d: &Deployment) -> Self {
+        let endpoint = |e: &Endpoint| EndpointView {
+            node: d.nodes[e.node.index].name.clone(),
+            index: e.index,
+            kind: e.kind.kind_name().into(),
+            guest_ifname: d.guest_ifname(e),
+        };
+        InfoView {
+            name: d.name.clone(),
+            nodes: d
+                .nodes
+                .iter()
+                .map(|n| NodeView {
+                    name: n.name.clone(),
+                    image: n.image.clone(),
+                    radix: n.radix,
+                    uuid: n.id,
+                    mounts: n
+                        .mounts
+                        .iter()
+                        .map(|m| MountView {
+                            source: m.source.to_string(),
+                            destination: m.destination.to_string(),
+                        })
+                        .collect(),
+                })
+                .collect(),
+            links: d
+                .links
+                .iter()
+                .map(|l| [endpoint(&l.endpoints[0]), endpoint(&l.endpoints[1])])
+                .collect(),
+            ext_links: d
+                .ext_links
+                .iter()
+                .map(|x| ExtLinkView {
+                    endpoint: endpoint(&x.endpoint),
+                    host_ifx: x.host_ifx.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+pub struct CmdStatus {
+    /// The path of the falcon output directory
+    #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
+    pub falcon_dir: Utf8PathBuf,
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+pub struct CmdExec {
+    pub node: String,
+    pub command: String,
+
+    /// Fail if the command has not completed within this many seconds
+    #[clap(short, long)]
+    pub timeout: Option<u64>,
 
     /// The path of the falcon output directory
     #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
-    falcon_dir: Utf8PathBuf,
+    pub falcon_dir: Utf8PathBuf,
 }
 
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+pub struct CmdPlog {
+    /// Name of the node whose log to print
+    pub node: String,
+
+    /// Print stderr of propolis-server instead of stdout
+    #[clap(short, long)]
+    pub err: bool,
+
+    /// Keep printing the log as it grows
+    #[clap(long)]
+    pub follow: bool,
+
+    /// The path of the falcon output directory
+    #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
+    pub falcon_dir: Utf8PathBuf,
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+pub struct CmdReap {
+    /// Actually kill orphaned instances instead of just listing them
+    #[clap(short, long)]
+    pub yes: bool,
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+pub struct CmdGc {
+    /// Actually delete the orphaned links and disks instead of just listing
+    /// them
+    #[clap(short, long)]
+    pub yes: bool,
+
+    /// Falcon directories of other deployments whose links and disks to keep
+    #[clap(short, long)]
+    pub keep: Vec<Utf8PathBuf>,
+
+    /// The path of the falcon output directory
+    #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
+    pub falcon_dir: Utf8PathBuf,
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+pub struct CmdPrune {
+    /// Images to keep even if the topology does not use them
+    #[clap(short, long)]
+    pub keep: Vec<String>,
+
+    /// Actually delete the images instead of just listing them
+    #[clap(short, long)]
+    pub yes: bool,
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+pub struct CmdImages {}
+
 /// Entry point for a command line application. Will parse command line
 /// arguments and take actions accordingly.
 ///
@@ -216,9 +485,40 @@ pub async fn run(r: &mut Runner) -> Result<RunMode, Error> {
     r.persistent = true;
 
     let opts: Opts = Opts::parse();
+    dispatch(r, opts).await
+}
+
+/// Carry out the command described by `opts` against `r`, as [`run`] does
+/// after parsing the command line. This is for programs with their own
+/// argument parsing, such as test binaries, that want to drive falcon's
+/// commands without it reading the process arguments. `opts` can be built
+/// directly, or parsed from arguments of the caller's choosing with
+/// [`Parser::parse_from`]. Unlike `run`, this does not make the runner
+/// persistent, so a launched deployment is destroyed when the runner is
+/// dropped unless the caller sets [`Runner::persistent`].
+///
+/// # Examples
+/// ```no_run
+/// use clap::Parser;
+/// use libfalcon::{cli::{dispatch, Opts}, Runner};
+///
+/// # async fn launch() -> Result<(), libfalcon::error::Error> {
+/// let mut r = Runner::new("duo");
+/// let violin = r.node("violin", "helios-2.5", 1, 1024);
+/// let piano = r.node("piano", "helios-2.5", 1, 1024);
+/// r.link(violin, piano);
+///
+/// r.persistent = true;
+/// dispatch(&mut r, Opts::parse_from(["falcon", "launch"])).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn dispatch(r: &mut Runner, opts: Opts) -> Result<RunMode, Error> {
     match opts.subcmd {
         SubCommand::Preflight(p) => {
-            r.falcon_dir = p.falcon_dir;
+            r.falcon_dir = resolve_falcon_dir(&p.falcon_dir)?;
+            r.set_skip_version_check(p.skip_version_check);
+            r.set_overcommit(p.overcommit);
             preflight(r).await;
             Ok(RunMode::Unspec)
         }
@@ -226,31 +526,90 @@ pub async fn run(r: &mut Runner) -> Result<RunMode, Error> {
             if let Some(path) = l.propolis {
                 r.propolis_binary = path
             }
-            r.falcon_dir = l.falcon_dir;
+            if let Some(filter) = l.propolis_log {
+                r.set_propolis_log(filter);
+            }
+            r.falcon_dir = resolve_falcon_dir(&l.falcon_dir)?;
+            r.set_skip_version_check(l.skip_version_check);
+            r.set_overcommit(l.overcommit);
+            if l.dry_run {
+                let pretty = PrettyConfig::new();
+                println!("{}", to_string_pretty(&r.plan()?, pretty)?);
+                return Ok(RunMode::Unspec);
+            }
             launch(r).await;
             Ok(RunMode::Launch)
         }
         SubCommand::Destroy(d) => {
-            r.falcon_dir = d.falcon_dir;
+            r.falcon_dir = resolve_falcon_dir(&d.falcon_dir)?;
+            r.load_topology()?;
+            if let Some(secs) = d.graceful {
+                for n in r.all_nodes() {
+                    r.shutdown(n, Duration::from_secs(secs)).await?;
+                }
+            }
             destroy(r);
             Ok(RunMode::Destroy)
         }
+        SubCommand::Stop(s) => {
+            r.falcon_dir = resolve_falcon_dir(&s.falcon_dir)?;
+            r.load_topology()?;
+            if let Some(secs) = s.graceful {
+                for n in r.all_nodes() {
+                    r.shutdown(n, Duration::from_secs(secs)).await?;
+                }
+            }
+            r.stop()?;
+            Ok(RunMode::Unspec)
+        }
         SubCommand::Serial(ref c) => {
-            console(&c.vm_name, &c.falcon_dir).await?;
+            let falcon_dir = resolve_falcon_dir(&c.falcon_dir)?;
+            let offset = c.from_start.then_some(ConsoleOffset::FromStart(0));
+            console(&c.vm_name, &falcon_dir, offset).await?;
+            Ok(RunMode::Unspec)
+        }
+        SubCommand::Info(ref c) => {
+            if c.dot {
+                print!("{}", r.deployment.to_dot());
+            } else if c.json {
+                let view = InfoView::new(&r.deployment);
+                println!("{}", serde_json::to_string_pretty(&view)?);
+            } else {
+                info(r)?;
+            }
             Ok(RunMode::Unspec)
         }
-        SubCommand::Info(_) => {
-            info(r)?;
+        SubCommand::Status(ref c) => {
+            let falcon_dir = resolve_falcon_dir(&c.falcon_dir)?;
+            status(r, &falcon_dir).await?;
             Ok(RunMode::Unspec)
         }
         SubCommand::Reboot(ref c) => {
-            reboot(&c.vm_name, &c.falcon_dir).await?;
+            let falcon_dir = resolve_falcon_dir(&c.falcon_dir)?;
+            if c.all {
+                for group in r.deployment.boot_order() {
+                    for x in group {
+                        reboot(&x.name, &falcon_dir).await?;
+                    }
+                }
+            } else {
+                match c.vm_name {
+                    None => {
+                        return Err(Error::Cli(
+                            "vm name required unless --all flag is used".into(),
+                        ))
+                    }
+                    Some(ref n) => reboot(n, &falcon_dir).await?,
+                }
+            }
             Ok(RunMode::Unspec)
         }
         SubCommand::Hyperstop(ref c) => {
+            let falcon_dir = resolve_falcon_dir(&c.falcon_dir)?;
+            let graceful = c.graceful.map(Duration::from_secs);
             if c.all {
                 for x in &r.deployment.nodes {
-                    hyperstop(&x.name, &c.falcon_dir).await?;
+                    hyperstop(&x.name, &falcon_dir, graceful).await?;
                 }
             } else {
                 match c.vm_name {
@@ -259,20 +618,31 @@ pub async fn run(r: &mut Runner) -> Result<RunMode, Error> {
                             "vm name required unless --all flag is used".into(),
                         ))
                     }
-                    Some(ref n) => hyperstop(n, &c.falcon_dir).await?,
+                    Some(ref n) => hyperstop(n, &falcon_dir, graceful).await?,
                 }
             }
             Ok(RunMode::Unspec)
         }
         SubCommand::Hyperstart(ref c) => {
+            let falcon_dir = resolve_falcon_dir(&c.falcon_dir)?;
             let propolis_binary = match c.propolis {
                 Some(ref path) => path.clone(),
                 None => "propolis-server".into(),
             };
             if c.all {
-                for x in &r.deployment.nodes {
-                    hyperstart(&x.name, propolis_binary.clone(), &c.falcon_dir)
-                        .await?;
+                // nodes of the same boot priority are started concurrently
+                for group in r.deployment.boot_order() {
+                    let fs = group.iter().map(|x| {
+                        hyperstart(
+                            r,
+                            &x.name,
+                            propolis_binary.clone(),
+                            &falcon_dir,
+                        )
+                    });
+                    for x in join_all(fs).await {
+                        x?;
+                    }
                 }
             } else {
                 match c.vm_name {
@@ -282,27 +652,56 @@ pub async fn run(r: &mut Runner) -> Result<RunMode, Error> {
                         ))
                     }
                     Some(ref n) => {
-                        hyperstart(n, propolis_binary, &c.falcon_dir).await?
+                        hyperstart(r, n, propolis_binary, &falcon_dir).await?
                     }
                 }
             }
             Ok(RunMode::Unspec)
         }
-        SubCommand::Netcreate(_) => {
-            netcreate(r).await;
+        SubCommand::Netcreate(c) => {
+            netcreate(r, c.link).await;
             Ok(RunMode::Unspec)
         }
-        SubCommand::Netdestroy(_) => {
-            netdestroy(r);
+        SubCommand::Netdestroy(c) => {
+            netdestroy(r, c.link);
             Ok(RunMode::Unspec)
         }
         SubCommand::Snapshot(s) => {
             snapshot(s)?;
             Ok(RunMode::Unspec)
         }
+        SubCommand::Restore(ref c) => {
+            r.falcon_dir = resolve_falcon_dir(&c.falcon_dir)?;
+            let n = r
+                .find_node(&c.vm_name)
+                .ok_or_else(|| Error::NotFound(c.vm_name.clone()))?;
+            r.restore(n, &c.snapshot_name)?;
+            Ok(RunMode::Unspec)
+        }
         SubCommand::Exec(ref c) => {
-            r.falcon_dir = c.falcon_dir.clone();
-            exec(r, &c.node, &c.command).await?;
+            r.falcon_dir = resolve_falcon_dir(&c.falcon_dir)?;
+            exec(r, &c.node, &c.command, c.timeout).await?;
+            Ok(RunMode::Unspec)
+        }
+        SubCommand::Plog(ref c) => {
+            node_log(c).await?;
+            Ok(RunMode::Unspec)
+        }
+        SubCommand::Reap(ref c) => {
+            reap(r, c.yes).await?;
+            Ok(RunMode::Unspec)
+        }
+        SubCommand::Gc(ref c) => {
+            r.falcon_dir = resolve_falcon_dir(&c.falcon_dir)?;
+            gc(r, c)?;
+            Ok(RunMode::Unspec)
+        }
+        SubCommand::Prune(ref c) => {
+            prune(r, c)?;
+            Ok(RunMode::Unspec)
+        }
+        SubCommand::Images(_) => {
+            images(r)?;
             Ok(RunMode::Unspec)
         }
     }
@@ -357,6 +756,83 @@ fn info(r: &Runner) -> anyhow::Result<()> {
     Ok(())
 }
 
+async fn status(r: &Runner, falcon_dir: &Utf8Path) -> anyhow::Result<()> {
+    let mut tw = TabWriter::new(stdout());
+
+    writeln!(
+        &mut tw,
+        "{}\t{}\t{}\t{}\t{}",
+        "Name".dimmed(),
+        "Status".dimmed(),
+        "PID".dimmed(),
+        "Port".dimmed(),
+        "VNC".dimmed(),
+    )?;
+    writeln!(
+        &mut tw,
+        "{}\t{}\t{}\t{}\t{}",
+        "----".bright_black(),
+        "------".bright_black(),
+        "---".bright_black(),
+        "----".bright_black(),
+        "---".bright_black(),
+    )?;
+    for x in &r.deployment.nodes {
+        let read = |ext: &str| {
+            fs::read_to_string(falcon_dir.join(format!("{}.{}", x.name, ext)))
+                .ok()
+                .map(|s| s.trim().to_string())
+        };
+        let pid = read("pid").and_then(|p| p.parse::<i32>().ok());
+        let port = read("port").and_then(|p| p.parse::<u16>().ok());
+        let vnc = read("vnc").and_then(|p| p.parse::<u16>().ok());
+
+        let alive = match pid {
+            Some(pid) => unsafe { libc::kill(pid, 0) == 0 },
+            None => false,
+        };
+        let status = match (alive, port) {
+            (false, _) => "Stopped".red(),
+            (true, Some(port)) if instance_responds(port).await => {
+                "Running".green()
+            }
+            (true, _) => "Unreachable".yellow(),
+        };
+
+        let show = |v: Option<String>| v.unwrap_or_else(|| "-".into());
+        writeln!(
+            &mut tw,
+            "{}\t{}\t{}\t{}\t{}",
+            x.name,
+            status,
+            show(pid.map(|p| p.to_string())),
+            show(port.map(|p| p.to_string())),
+            show(
+                vnc.filter(|_| x.vnc || x.vnc_port.is_some())
+                    .map(|p| { format!("[::1]:{p}") })
+            ),
+        )?;
+    }
+    tw.flush()?;
+
+    Ok(())
+}
+
+/// Determine whether the propolis server listening on `port` can report the
+/// state of its instance.
+async fn instance_responds(port: u16) -> bool {
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+    let client = match reqwest::ClientBuilder::new()
+        .timeout(Duration::from_secs(5))
+        .build()
+    {
+        Ok(c) => c,
+        Err(_) => return false,
+    };
+    let client = Client::new_with_client(&format!("http://{}", addr), client);
+    client.instance_get().send().await.is_ok()
+}
+
 async fn preflight(r: &Runner) {
     if let Err(e) = r.preflight().await {
         eprintln!("error: {}", e)
@@ -369,25 +845,51 @@ async fn launch(r: &Runner) {
     }
 }
 
-async fn netcreate(r: &Runner) {
-    if let Err(e) = r.net_launch().await {
+async fn netcreate(r: &Runner, link: Option<Vec<String>>) {
+    let result = match link {
+        Some(names) => resolve_link(r, &names).and_then(|l| r.link_create(l)),
+        None => r.net_launch().await,
+    };
+    if let Err(e) = result {
         eprintln!("error: {}", e)
     }
 }
 
-fn netdestroy(r: &Runner) {
-    if let Err(e) = r.net_destroy() {
+fn netdestroy(r: &Runner, link: Option<Vec<String>>) {
+    let result = match link {
+        Some(names) => resolve_link(r, &names).and_then(|l| r.link_destroy(l)),
+        None => r.net_destroy(),
+    };
+    if let Err(e) = result {
         eprintln!("error: {}", e)
     }
 }
 
+fn resolve_link(r: &Runner, names: &[String]) -> Result<LinkRef, Error> {
+    let node = |name: &String| {
+        r.find_node(name)
+            .ok_or_else(|| Error::NotFound(name.clone()))
+    };
+    let (a, b) = (node(&names[0])?, node(&names[1])?);
+    r.find_link(a, b).ok_or_else(|| {
+        Error::NotFound(format!("link between {} and {}", names[0], names[1]))
+    })
+}
+
 fn snapshot(cmd: CmdSnapshot) -> Result<(), Error> {
     // read topology
-    let mut path = cmd.falcon_dir.to_path_buf();
-    path.push("topology.ron");
-    let topo_ron = fs::read_to_string(&path)?;
-    let d: Deployment = from_str(&topo_ron)?;
-    path.pop();
+    let falcon_dir = resolve_falcon_dir(&cmd.falcon_dir)?;
+    let d = Deployment::from_file(&topology_path(&falcon_dir))?;
+
+    let dataset = dataset();
+
+    if cmd.all {
+        for n in &d.nodes {
+            let name = format!("{}_{}", cmd.vm_name, n.name);
+            snapshot_node(&dataset, &d, n, &name)?;
+        }
+        return Ok(());
+    }
 
     // get node from topology
     let mut node = None;
@@ -402,57 +904,64 @@ fn snapshot(cmd: CmdSnapshot) -> Result<(), Error> {
         Some(node) => node,
     };
 
-    let dataset = dataset();
+    // clap ensures the snapshot name is present without --all
+    let snapshot_name = cmd.snapshot_name.unwrap_or_default();
+    snapshot_node(&dataset, &d, node, &snapshot_name)
+}
 
+/// Turn the disk of `node` into a new base image called `snapshot_name`.
+fn snapshot_node(
+    dataset: &str,
+    d: &Deployment,
+    node: &Node,
+    snapshot_name: &str,
+) -> Result<(), Error> {
     let source = format!("{}/topo/{}/{}", dataset, d.name, node.name);
     let source_snapshot = format!("{}@base", source);
 
-    let dest = format!("{}/img/{}", dataset, cmd.snapshot_name,);
-    let dest_snapshot = format!("{}@base", source);
+    let dest = format!("{}/img/{}", dataset, snapshot_name);
+    let dest_snapshot = format!("{}@base", dest);
 
     // first take a snapshot of the node clone
-    let out = Command::new("zfs")
-        .args(["snapshot", source_snapshot.as_ref()])
-        .output()?;
-    if !out.status.success() {
-        return Err(Error::Zfs(String::from_utf8(out.stderr)?));
-    }
+    zfs::snapshot(&source_snapshot)?;
 
     // next clone the source snapshot to a new base image
-    let out = Command::new("zfs")
-        .args(["clone", source_snapshot.as_ref(), dest.as_ref()])
-        .output()?;
-
-    if !out.status.success() {
-        return Err(Error::Zfs(String::from_utf8(out.stderr)?));
-    }
+    zfs::clone(&source_snapshot, &dest)?;
 
     // promote the base image to uncouple from source snapshot
-    let out = Command::new("zfs")
-        .args(["promote", dest.as_ref()])
-        .output()?;
-    if !out.status.success() {
-        return Err(Error::Zfs(String::from_utf8(out.stderr)?));
-    }
+    zfs::promote(&dest)?;
 
-    // finally create base snapshot for new image
-    let out = Command::new("zfs")
-        .args(["snapshot", dest_snapshot.as_ref()])
-        .output()?;
-    if !out.status.success() {
-        return Err(Error::Zfs(String::from_utf8(out.stderr)?));
+    // finally create base snapshot for new image, promotion moves the source
+    // snapshot over to the new image so it usually exists already
+    if !zfs::exists(&dest_snapshot)? {
+        zfs::snapshot(&dest_snapshot)?;
     }
 
     Ok(())
 }
 
+/// Make `dir` absolute, resolving symlinks if it exists, so the falcon
+/// directory is the same one no matter where falcon is run from.
+fn resolve_falcon_dir(dir: &Utf8Path) -> Result<Utf8PathBuf, Error> {
+    if dir.exists() {
+        return Ok(dir.canonicalize_utf8()?);
+    }
+    let cwd = Utf8PathBuf::try_from(std::env::current_dir()?)
+        .map_err(|e| Error::PathError(e.to_string()))?;
+    Ok(cwd.join(dir))
+}
+
 fn destroy(r: &Runner) {
     if let Err(e) = r.destroy() {
         println!("{}", e)
     }
 }
 
-async fn console(name: &str, falcon_dir: &Utf8Path) -> Result<(), Error> {
+async fn console(
+    name: &str,
+    falcon_dir: &Utf8Path,
+    offset: Option<ConsoleOffset>,
+) -> Result<(), Error> {
     println!(
         "{}\n{}\n{}",
         "Entering VM console.".blue(),
@@ -465,14 +974,17 @@ async fn console(name: &str, falcon_dir: &Utf8Path) -> Result<(), Error> {
     path.pop();
 
     let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
-    serial(addr).await?;
+    serial(addr, offset).await?;
 
     Ok(())
 }
 
 // TODO copy pasta from propolis/cli/src/main.rs
-async fn serial(addr: SocketAddr) -> anyhow::Result<()> {
-    let path = format!("ws://{}/instance/serial", addr);
+async fn serial(
+    addr: SocketAddr,
+    offset: Option<ConsoleOffset>,
+) -> anyhow::Result<()> {
+    let path = console_url(addr, offset);
     let (mut ws, _) = tokio_tungstenite::connect_async(path)
         .await
         .with_context(|| anyhow!("failed to create serial websocket stream"))?;
@@ -609,14 +1121,25 @@ impl Drop for RawTermiosGuard {
     }
 }
 
-/// Create a top-level logger that outputs to stderr
+/// Create a top-level logger that outputs to stderr, in the format selected by
+/// [`LogFormat::from_env`].
 fn create_logger() -> Logger {
-    let decorator = slog_term::TermDecorator::new().stderr().build();
-    let drain = slog_term::FullFormat::new(decorator).build().fuse();
     let level = Level::Debug;
-    let drain = slog::LevelFilter(drain, level).fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
-    Logger::root(drain, o!())
+    match LogFormat::from_env() {
+        LogFormat::Term => {
+            let decorator = slog_term::TermDecorator::new().stderr().build();
+            let drain = slog_term::FullFormat::new(decorator).build().fuse();
+            let drain = slog::LevelFilter(drain, level).fuse();
+            let drain = slog_async::Async::new(drain).build().fuse();
+            Logger::root(drain, o!())
+        }
+        LogFormat::Json => {
+            let drain = slog_json::Json::default(std::io::stderr()).fuse();
+            let drain = slog::LevelFilter(drain, level).fuse();
+            let drain = slog_async::Async::new(drain).build().fuse();
+            Logger::root(drain, o!())
+        }
+    }
 }
 
 async fn reboot(name: &str, falcon_dir: &Utf8Path) -> Result<(), Error> {
@@ -639,92 +1162,231 @@ async fn reboot(name: &str, falcon_dir: &Utf8Path) -> Result<(), Error> {
     Ok(())
 }
 
-async fn hyperstop(name: &str, falcon_dir: &Utf8Path) -> Result<(), Error> {
+async fn hyperstop(
+    name: &str,
+    falcon_dir: &Utf8Path,
+    graceful: Option<Duration>,
+) -> Result<(), Error> {
     let log = create_logger();
-
-    let mut path = falcon_dir.to_path_buf();
-    path.push(format!("{name}.pid"));
-
-    // read pid
-    match fs::read_to_string(&path) {
-        Ok(pid) => match pid.trim_end().parse() {
-            Ok(pid) => {
-                unsafe {
-                    libc::kill(pid, libc::SIGKILL);
-                }
-                fs::remove_file(&path)?;
-            }
-            Err(e) => warn!(log, "could not parse pidfile for {}: {}", name, e),
-        },
-        Err(e) => {
-            warn!(log, "could not get pidfile for {}: {}", name, e);
-        }
-    };
-    path.pop();
-
-    // get instance uuid
-    path.push(format!("{name}.uuid"));
-    let uuid = match fs::read_to_string(&path) {
-        Ok(u) => u,
-        Err(e) => {
-            warn!(log, "get propolis uuid for {}: {}", name, e);
-            return Ok(());
-        }
-    };
-    path.pop();
-
-    // destroy bhyve vm
-    let vm_arg = format!("--vm={}", uuid);
-    match Command::new("bhyvectl")
-        .args(["--destroy", vm_arg.as_ref()])
-        .output()
-    {
-        Ok(_) => {}
-        Err(e) => {
-            warn!(log, "delete bhyve vm for {}: {}", name, e);
-            return Ok(());
-        }
+    if let Some(timeout) = graceful {
+        crate::graceful_stop(&log, name, falcon_dir, timeout).await;
     }
-
-    Ok(())
+    crate::hyperstop(&log, name, falcon_dir)
 }
 
 async fn hyperstart(
+    r: &Runner,
     name: &str,
     propolis_binary: String,
     falcon_dir: &Utf8Path,
 ) -> Result<(), Error> {
     // read topology
-    let mut path = falcon_dir.to_path_buf();
-    path.push("topology.ron");
-    let topo_ron = fs::read_to_string(&path)?;
-    let d: Deployment = from_str(&topo_ron)?;
-    path.pop();
+    let mut path = topology_path(falcon_dir);
+    let mut d = Deployment::from_file(&path)?;
 
-    let mut node = None;
-    for n in &d.nodes {
-        if n.name == name {
-            node = Some(n);
-        }
-    }
-
-    let node = match node {
+    let i = match d.nodes.iter().position(|n| n.name == name) {
         None => return Err(Error::NotFound(name.into())),
-        Some(node) => node,
+        Some(i) => i,
     };
 
+    // pick up cores and memory changed since the node was launched, and save
+    // them so the topology reflects what is running
+    if let Some(current) = r.deployment.nodes.iter().find(|n| n.name == name) {
+        let node = &mut d.nodes[i];
+        if (current.cores, current.memory) != (node.cores, node.memory) {
+            node.cores = current.cores;
+            node.memory = current.memory;
+            d.validate()?;
+            d.write_file(&path)?;
+        }
+    }
+    path.pop();
+    let node = &d.nodes[i];
+
     path.push(format!("{name}.uuid"));
     let id: uuid::Uuid = fs::read_to_string(&path)?.trim_end().parse()?;
     path.pop();
     let log = create_logger();
 
-    crate::launch_vm(&log, &propolis_binary, &id, node, falcon_dir).await?;
+    // the node's own binary takes precedence over the deployment's
+    let propolis_binary =
+        node.propolis_binary.as_deref().unwrap_or(&propolis_binary);
+    let propolis = crate::PropolisLaunch {
+        binary: propolis_binary,
+        extra_args: &d.propolis_args,
+        log_filter: d.propolis_log.as_deref(),
+        timeout: d.launch_timeout,
+        ensure_retry: d.ensure_retry,
+    };
+    crate::launch_vm(&log, &id, node, falcon_dir, &propolis).await?;
 
     Ok(())
 }
 
-async fn exec(r: &Runner, node: &str, command: &str) -> Result<(), Error> {
-    println!("{}", r.do_exec(node, command).await?);
+async fn reap(r: &Runner, yes: bool) -> Result<(), Error> {
+    let orphans = r.find_orphaned_propolis()?;
+    if orphans.is_empty() {
+        println!("no orphaned propolis instances found");
+        return Ok(());
+    }
+
+    let mut tw = TabWriter::new(stdout());
+    writeln!(
+        &mut tw,
+        "{}\t{}\t{}",
+        "PID".dimmed(),
+        "Config".dimmed(),
+        "UUID".dimmed(),
+    )?;
+    writeln!(
+        &mut tw,
+        "{}\t{}\t{}",
+        "---".bright_black(),
+        "------".bright_black(),
+        "----".bright_black(),
+    )?;
+    for o in &orphans {
+        let uuid = match o.uuid {
+            Some(u) => u.to_string(),
+            None => "?".into(),
+        };
+        writeln!(&mut tw, "{}\t{}\t{}", o.pid, o.config, uuid)?;
+    }
+    tw.flush()?;
+
+    if !yes {
+        println!("rerun with --yes to kill these instances");
+        return Ok(());
+    }
+
+    for o in &orphans {
+        r.reap_orphan(o).await?;
+    }
+
+    Ok(())
+}
+
+async fn node_log(c: &CmdPlog) -> Result<(), Error> {
+    let ext = if c.err { "err" } else { "out" };
+    let path =
+        resolve_falcon_dir(&c.falcon_dir)?.join(format!("{}.{}", c.node, ext));
+    if !path.exists() {
+        return Err(Error::NotFound(path.to_string()));
+    }
+
+    let mut file = tokio::fs::File::open(&path).await?;
+    let mut stdout = tokio::io::stdout();
+    let mut buf = [0u8; 8192];
+    let mut pos = 0;
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n > 0 {
+            pos += n as u64;
+            stdout.write_all(&buf[..n]).await?;
+            continue;
+        }
+        stdout.flush().await?;
+        if !c.follow {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // start over if the log was truncated, e.g. by a relaunch
+        if tokio::fs::metadata(&path).await?.len() < pos {
+            file = tokio::fs::File::open(&path).await?;
+            pos = 0;
+        }
+    }
+}
+
+fn images(r: &Runner) -> anyhow::Result<()> {
+    let mut tw = TabWriter::new(stdout());
+    writeln!(
+        &mut tw,
+        "{}\t{}\t{}\t{}",
+        "Name".dimmed(),
+        "Size".dimmed(),
+        "Age".dimmed(),
+        "Used".dimmed(),
+    )?;
+    writeln!(
+        &mut tw,
+        "{}\t{}\t{}\t{}",
+        "----".bright_black(),
+        "----".bright_black(),
+        "---".bright_black(),
+        "----".bright_black(),
+    )?;
+    for x in r.list_images()? {
+        let age = x.created.elapsed().unwrap_or_default().as_secs();
+        let age = match age {
+            0..=3599 => format!("{}m", age / 60),
+            3600..=86399 => format!("{}h", age / 3600),
+            _ => format!("{}d", age / 86400),
+        };
+        let used = if x.used { "yes".green() } else { "no".normal() };
+        writeln!(
+            &mut tw,
+            "{}\t{:.1}G\t{}\t{}",
+            x.name,
+            x.size as f64 / (1u64 << 30) as f64,
+            age,
+            used,
+        )?;
+    }
+    tw.flush()?;
+    Ok(())
+}
+
+fn gc(r: &Runner, c: &CmdGc) -> Result<(), Error> {
+    let garbage = r.find_garbage(&c.keep)?;
+    if garbage.is_empty() {
+        println!("no orphaned links or disks found");
+        return Ok(());
+    }
+    for link in garbage.links() {
+        println!("link {}", link);
+    }
+    for dataset in &garbage.datasets {
+        println!("dataset {}", dataset);
+    }
+    if !c.yes {
+        println!("rerun with --yes to delete these");
+        return Ok(());
+    }
+    r.gc(&garbage)
+}
+
+fn prune(r: &Runner, c: &CmdPrune) -> Result<(), Error> {
+    let keep: Vec<&str> = c.keep.iter().map(|x| x.as_str()).collect();
+    if !c.yes {
+        let images = r.prunable_images(&keep)?;
+        if images.is_empty() {
+            println!("no images to prune");
+            return Ok(());
+        }
+        for image in &images {
+            println!("{}", image);
+        }
+        println!("rerun with --yes to delete these images");
+        return Ok(());
+    }
+    for image in r.prune_images(&keep)? {
+        println!("deleted {}", image);
+    }
+    Ok(())
+}
+
+async fn exec(
+    r: &Runner,
+    node: &str,
+    command: &str,
+    timeout: Option<u64>,
+) -> Result<(), Error> {
+    let timeout = timeout.map(std::time::Duration::from_secs);
+    // print output as it arrives rather than when the command is done
+    r.do_exec(node, command, timeout, &mut |line| println!("{line}"))
+        .await?;
     Ok(())
 }
 
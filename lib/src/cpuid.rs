@@ -0,0 +1,131 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+
+/// The CPU vendor a CPUID table describes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CpuidVendor {
+    Amd,
+    Intel,
+}
+
+/// The register of a CPUID leaf a value is returned in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CpuidRegister {
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+/// A single CPUID leaf, or subleaf for leaves that have them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CpuidEntry {
+    /// The leaf, the value of eax the CPUID instruction is executed with.
+    pub leaf: u32,
+    /// The subleaf, the value of ecx the CPUID instruction is executed with,
+    /// for leaves that have subleaves.
+    pub subleaf: Option<u32>,
+    /// The values of eax, ebx, ecx and edx returned for the leaf.
+    pub values: [u32; 4],
+}
+
+/// A full CPUID table presented to a guest in place of the one propolis
+/// derives from the host. Leaves that are not in the table read as zero.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CpuidEntries {
+    pub vendor: CpuidVendor,
+    pub entries: Vec<CpuidEntry>,
+}
+
+/// Feature bits known by name to [`CpuidEntries::disable_feature`].
+const FEATURES: &[(&str, u32, Option<u32>, CpuidRegister, u32)] = &[
+    ("sse3", 1, None, CpuidRegister::Ecx, 0),
+    ("pclmulqdq", 1, None, CpuidRegister::Ecx, 1),
+    ("ssse3", 1, None, CpuidRegister::Ecx, 9),
+    ("fma", 1, None, CpuidRegister::Ecx, 12),
+    ("sse4.1", 1, None, CpuidRegister::Ecx, 19),
+    ("sse4.2", 1, None, CpuidRegister::Ecx, 20),
+    ("x2apic", 1, None, CpuidRegister::Ecx, 21),
+    ("popcnt", 1, None, CpuidRegister::Ecx, 23),
+    ("aes", 1, None, CpuidRegister::Ecx, 25),
+    ("xsave", 1, None, CpuidRegister::Ecx, 26),
+    ("avx", 1, None, CpuidRegister::Ecx, 28),
+    ("rdrand", 1, None, CpuidRegister::Ecx, 30),
+    ("hypervisor", 1, None, CpuidRegister::Ecx, 31),
+    ("bmi1", 7, Some(0), CpuidRegister::Ebx, 3),
+    ("avx2", 7, Some(0), CpuidRegister::Ebx, 5),
+    ("bmi2", 7, Some(0), CpuidRegister::Ebx, 8),
+    ("avx512f", 7, Some(0), CpuidRegister::Ebx, 16),
+    ("rdseed", 7, Some(0), CpuidRegister::Ebx, 18),
+    ("adx", 7, Some(0), CpuidRegister::Ebx, 19),
+    ("sha", 7, Some(0), CpuidRegister::Ebx, 29),
+];
+
+impl CpuidEntries {
+    pub fn new(vendor: CpuidVendor, entries: Vec<CpuidEntry>) -> Self {
+        CpuidEntries { vendor, entries }
+    }
+
+    /// Clear `bit` of `reg` in the given leaf. Clearing a bit of a leaf that
+    /// is not in the table has no effect, as the whole leaf already reads as
+    /// zero.
+    pub fn clear_bit(
+        &mut self,
+        leaf: u32,
+        subleaf: Option<u32>,
+        reg: CpuidRegister,
+        bit: u32,
+    ) {
+        let i = match reg {
+            CpuidRegister::Eax => 0,
+            CpuidRegister::Ebx => 1,
+            CpuidRegister::Ecx => 2,
+            CpuidRegister::Edx => 3,
+        };
+        for e in self.entries.iter_mut() {
+            if e.leaf == leaf && e.subleaf == subleaf {
+                e.values[i] &= !(1 << bit);
+            }
+        }
+    }
+
+    /// Hide the named feature, e.g. `avx2`, from the guest. Only a handful of
+    /// common features are known by name, [`CpuidEntries::clear_bit`] can be
+    /// used for anything else.
+    pub fn disable_feature(&mut self, name: &str) -> Result<(), Error> {
+        let (_, leaf, subleaf, reg, bit) =
+            FEATURES.iter().find(|f| f.0 == name).ok_or_else(|| {
+                Error::NotFound(format!("cpuid feature {}", name))
+            })?;
+        self.clear_bit(*leaf, *subleaf, *reg, *bit);
+        Ok(())
+    }
+
+    /// Render the table as a propolis cpuid profile. Leaves are keyed by
+    /// their hex leaf number, followed by `-` and the hex subleaf for leaves
+    /// that have subleaves.
+    pub(crate) fn to_profile(&self) -> propolis_server_config::CpuidProfile {
+        let vendor = match self.vendor {
+            CpuidVendor::Amd => propolis_server_config::CpuVendor::Amd,
+            CpuidVendor::Intel => propolis_server_config::CpuVendor::Intel,
+        };
+        let leaf = self
+            .entries
+            .iter()
+            .map(|e| {
+                let key = match e.subleaf {
+                    Some(subleaf) => format!("{:x}-{:x}", e.leaf, subleaf),
+                    None => format!("{:x}", e.leaf),
+                };
+                (key, e.values)
+            })
+            .collect();
+        propolis_server_config::CpuidProfile { vendor, leaf }
+    }
+}
@@ -4,7 +4,9 @@
 
 // Copyright 2022 Oxide Computer Company
 
-use std::{ffi, io, str};
+use std::collections::BTreeMap;
+use std::process::ExitStatus;
+use std::{ffi, fmt, io, str};
 use thiserror::Error;
 
 /// Error conditions that can be emitted by Falcon
@@ -22,6 +24,10 @@ pub enum Error {
     FUtf8(#[from] std::string::FromUtf8Error),
     #[error("exec: {0}")]
     Exec(String),
+    /// The guest on the named node panicked, with the panic text captured from
+    /// its serial console.
+    #[error("{0}: guest panic: {1}")]
+    GuestPanic(String, String),
     QueryError(#[from] smf::QueryError),
     #[error("path: {0}")]
     PathError(String),
@@ -29,10 +35,16 @@ pub enum Error {
     Wrap(String),
     #[error("netadm: {0}")]
     Libnet(#[from] libnet::Error),
+    /// A link operation that kept failing, with the number of attempts made
+    /// and the error of the last one.
+    #[error("netadm: {0}: failed after {1} attempts: {2}")]
+    LibnetRetry(String, u32, libnet::Error),
     #[error("cli: {0}")]
     Cli(String),
     Ron(#[from] ron::Error),
     TomL(#[from] toml::ser::Error),
+    TomlDe(#[from] toml::de::Error),
+    Json(#[from] serde_json::Error),
     AddrParse(#[from] std::net::AddrParseError),
     Propolis(#[from] propolis_client::Error),
     PropolisTypes(
@@ -46,4 +58,52 @@ pub enum Error {
     #[error("no ports available")]
     NoPorts,
     Zfs(String),
+    ZfsCommand(ZfsCommandError),
+    ExecAll(ExecAllError),
+    #[error("invalid topology: {}", .0.join("; "))]
+    Validation(Vec<String>),
+}
+
+/// The result of running a command on several nodes where the command failed
+/// on at least one node.
+#[derive(Debug)]
+pub struct ExecAllError {
+    /// Output of the nodes the command succeeded on, keyed by node name.
+    pub output: BTreeMap<String, String>,
+    /// Errors of the nodes the command failed on, keyed by node name.
+    pub failed: BTreeMap<String, Error>,
+}
+
+impl fmt::Display for ExecAllError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "exec failed on {} node(s):", self.failed.len())?;
+        for (node, e) in &self.failed {
+            write!(f, " {}: {};", node, e)?;
+        }
+        Ok(())
+    }
+}
+
+/// A zfs command that did not succeed.
+#[derive(Debug)]
+pub struct ZfsCommandError {
+    /// The command line that was run, starting with the zfs binary.
+    pub argv: Vec<String>,
+    /// The exit status of zfs.
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl fmt::Display for ZfsCommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}` failed ({})", self.argv.join(" "), self.status)?;
+        if !self.stderr.trim().is_empty() {
+            write!(f, ": {}", self.stderr.trim())?;
+        }
+        if !self.stdout.trim().is_empty() {
+            write!(f, " stdout: {}", self.stdout.trim())?;
+        }
+        Ok(())
+    }
 }
@@ -7,29 +7,42 @@
 #[cfg(test)]
 mod test;
 mod util;
+mod zfs;
 
 pub mod cli;
+pub mod cpuid;
 pub mod error;
 pub mod serial;
+pub mod template;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
 pub mod unit;
 
 use anyhow::Context;
+use base64::Engine;
 use camino::{Utf8Path, Utf8PathBuf};
 use error::Error;
 use futures::future::join_all;
 use futures::StreamExt;
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{
+    MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle,
+};
+use liblzma::read::XzDecoder;
+use liblzma::stream::MtStreamBuilder;
+use oxnet::IpNet;
 use propolis_client::types::InstanceMetadata;
 use propolis_server_config::{BlockDevice, BlockOpts, Device};
 use ron::ser::{to_string_pretty, PrettyConfig};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use slog::Drain;
 use slog::{debug, error, info, warn, Logger};
-use std::collections::BTreeMap;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::convert::TryInto;
 use std::fs::{self, OpenOptions};
-use std::io::BufWriter;
-use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::io::{BufWriter, IsTerminal};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
@@ -37,8 +50,9 @@ use std::str::FromStr;
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
 use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::TcpStream;
 use tokio::time::{sleep, Duration, Instant};
-use xz2::read::XzDecoder;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 
 #[macro_export]
 macro_rules! node {
@@ -74,11 +88,56 @@ macro_rules! cmd {
 }
 
 pub const DEFAULT_FALCON_DIR: &str = ".falcon";
-const ZFS_BIN: &str = "/usr/sbin/zfs";
 const DLADM_BIN: &str = "/usr/sbin/dladm";
 const DD_BIN: &str = "/usr/bin/dd";
+const DEFAULT_FILE_DIR: &str = "/var/falcon/dsk";
 const RM_BIN: &str = "/usr/bin/rm";
 const TRUNCATE_BIN: &str = "/usr/bin/truncate";
+const PS_BIN: &str = "/usr/bin/ps";
+const PWDX_BIN: &str = "/usr/bin/pwdx";
+const KSTAT_BIN: &str = "/usr/bin/kstat";
+const DEFAULT_ASSET_BASE_URL: &str =
+    "https://oxide-falcon-assets.s3.us-west-2.amazonaws.com";
+
+/// How many bytes of a file are transferred per command by `push_file`. This is
+/// a multiple of 3 so each chunk base64 encodes without padding.
+const PUSH_CHUNK_SIZE: usize = 768;
+
+/// How long each stage of starting a propolis instance may take by default.
+const DEFAULT_LAUNCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long each throughput measurement runs for.
+const THROUGHPUT_SECS: u64 = 10;
+
+/// How long to wait for duplicate address detection of a link-local address.
+const V6_DAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many times downloading a base image is attempted before giving up.
+const IMAGE_DOWNLOAD_ATTEMPTS: usize = 10;
+
+/// How many distinct base images are installed at once during preflight.
+const MAX_CONCURRENT_IMAGE_INSTALLS: usize = 4;
+
+const MIB: f64 = (1 << 20) as f64;
+
+/// Memory the threads decompressing an image may use before liblzma falls
+/// back to decoding it with a single thread.
+const XZ_THREADING_MEMLIMIT: u64 = 1 << 32;
+
+/// How long a graceful shutdown waits for a guest to stop by default.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many lines from the end of a propolis stderr log are included in
+/// startup errors.
+const PROPOLIS_ERR_TAIL_LINES: usize = 20;
+
+/// The propolis revision falcon's propolis client and server configuration are
+/// built against. This is generated by the build script from the propolis
+/// revision pinned in the workspace Cargo.toml.
+pub const PROPOLIS_REV: &str = env!("FALCON_PROPOLIS_REV");
+
+/// Propolis features falcon knows how to configure for a node.
+pub const PROPOLIS_FEATURES: &[&str] = &["viona", "p9fs", "sidemux", "softnpu"];
 
 pub struct Runner {
     /// The deployment object that describes the Falcon topology
@@ -96,10 +155,240 @@ pub struct Runner {
     /// The root dataset to use for falcon activities
     pub dataset: String,
 
+    /// The directory file backed disks are created in. Base images always live
+    /// in `{dataset}/img` and zvol backed disks in `{dataset}/topo`, but file
+    /// backed disks are copied out of the zfs dataset into
+    /// `{file_dir}/{deployment}`.
+    pub file_dir: String,
+
     /// The location of the ".falcon" directory for a given deployment
     ///
     /// This directory is created by falcon and stores configuration.
     pub falcon_dir: Utf8PathBuf,
+
+    /// Whether to strip ANSI escape sequences from the output of commands run
+    /// with `exec`. On by default.
+    pub strip_ansi: bool,
+
+    /// How the end of commands run with `exec` is detected.
+    pub exec_strategy: serial::ExecStrategy,
+
+    /// The format `preflight` writes the topology to the falcon directory in.
+    pub topology_format: TopologyFormat,
+
+    /// How long to wait for each prompt while logging into a node's serial
+    /// console.
+    pub login_timeout: Duration,
+
+    /// How many more times to try logging into a node before giving up.
+    pub login_retries: u32,
+
+    /// Patterns that indicate a guest panic on the serial console. Empty, the
+    /// default, disables panic detection.
+    pub panic_patterns: Vec<String>,
+
+    /// The URL base images and their metadata are downloaded from.
+    pub asset_base_url: String,
+
+    /// Whether downloaded base images are checked against the sha256 digest
+    /// published next to them. See [`Runner::set_verify_images`].
+    pub verify_images: bool,
+
+    /// Whether to log the serial console of each node to
+    /// `{node}.console.log` in the falcon directory.
+    pub serial_logging: bool,
+
+    /// Whether every node gets `/etc/hosts` entries for the static link
+    /// addresses of its peers. See [`Runner::enable_peer_hosts`].
+    pub peer_hosts: bool,
+
+    /// Credentials used to log into the serial console of nodes that do not
+    /// have their own.
+    pub login: serial::Credentials,
+
+    /// Whether to draw progress bars for image downloads, extraction and
+    /// copies. See [`Runner::set_progress`].
+    pub progress: bool,
+
+    /// ZFS properties of base image zvols and node disks. See
+    /// [`Runner::set_zfs_opts`].
+    pub zfs_opts: ZfsOpts,
+
+    /// Whether preflight accepts a propolis-server binary of a revision other
+    /// than [`PROPOLIS_REV`]. See [`Runner::set_skip_version_check`].
+    pub skip_version_check: bool,
+
+    /// Whether preflight accepts nodes that need more memory than the host
+    /// has available. See [`Runner::set_overcommit`].
+    pub overcommit: bool,
+
+    /// How link operations are retried. See [`Runner::set_libnet_retry`].
+    pub libnet_retry: LibnetRetry,
+}
+
+/// ZFS properties of the zvols falcon creates for base images and node disks.
+/// The defaults are those falcon has always used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZfsOpts {
+    /// The `compression` property of base images and node disks, e.g. `lz4`.
+    /// `None` inherits the property from the parent dataset.
+    pub compression: Option<String>,
+    /// The `volblocksize` of base images and extra disks, e.g. `4k` or
+    /// `16384`. Node disks are clones of their base image and share its block
+    /// size. The block size of an image cannot change once it is installed.
+    pub volblocksize: String,
+    /// The `sync` property of node disks.
+    pub sync: String,
+}
+
+impl Default for ZfsOpts {
+    fn default() -> Self {
+        ZfsOpts {
+            compression: None,
+            volblocksize: "4k".into(),
+            sync: "disabled".into(),
+        }
+    }
+}
+
+impl ZfsOpts {
+    /// Check every property has a value zfs accepts, returning an
+    /// [`Error::Validation`] listing every problem found.
+    pub fn validate(&self) -> Result<(), Error> {
+        let mut problems = Vec::new();
+        if let Some(c) = &self.compression {
+            let valid = match c.strip_prefix("gzip-") {
+                Some(level) => matches!(
+                    level,
+                    "1" | "2" | "3" | "4" | "5" | "6" | "7" | "8" | "9"
+                ),
+                None => {
+                    matches!(
+                        c.as_str(),
+                        "on" | "off" | "lzjb" | "gzip" | "zle" | "lz4"
+                    )
+                }
+            };
+            if !valid {
+                problems.push(format!("invalid zfs compression `{c}`"));
+            }
+        }
+        if self.volblocksize_bytes().is_none() {
+            problems.push(format!(
+                "invalid zfs volblocksize `{}`, expected a power of two \
+                 from 512 to 128k",
+                self.volblocksize
+            ));
+        }
+        if !matches!(self.sync.as_str(), "standard" | "always" | "disabled") {
+            problems.push(format!("invalid zfs sync `{}`", self.sync));
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Validation(problems))
+        }
+    }
+
+    /// The volblocksize in bytes, if it is valid.
+    pub fn volblocksize_bytes(&self) -> Option<usize> {
+        let s = self.volblocksize.as_str();
+        let (digits, scale) = match s.strip_suffix(['k', 'K']) {
+            Some(digits) => (digits, 1024),
+            None => (s, 1),
+        };
+        let size = digits.parse::<usize>().ok()?.checked_mul(scale)?;
+        (size.is_power_of_two() && (512..=128 * 1024).contains(&size))
+            .then_some(size)
+    }
+
+    /// `zfs create` options setting the properties of a new zvol.
+    fn create_args(&self) -> Vec<String> {
+        let mut args = vec![
+            "-o".to_string(),
+            format!("volblocksize={}", self.volblocksize),
+        ];
+        if let Some(c) = &self.compression {
+            args.extend(["-o".to_string(), format!("compression={c}")]);
+        }
+        args
+    }
+}
+
+/// How link operations that fail are retried, e.g. deleting a vnic that a
+/// propolis instance that is shutting down still holds. The delay between
+/// attempts starts at `initial_backoff` and doubles after each attempt, up to
+/// `max_backoff`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LibnetRetry {
+    /// How many times an operation is attempted before giving up. At least one
+    /// attempt is always made.
+    pub attempts: u32,
+    /// The delay after the first failed attempt.
+    pub initial_backoff: Duration,
+    /// The longest delay between attempts.
+    pub max_backoff: Duration,
+}
+
+impl Default for LibnetRetry {
+    fn default() -> Self {
+        LibnetRetry {
+            attempts: 12,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(4),
+        }
+    }
+}
+
+impl LibnetRetry {
+    /// The delay after failed attempt `attempt`, counting from zero.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        1u32.checked_shl(attempt)
+            .and_then(|m| self.initial_backoff.checked_mul(m))
+            .map_or(self.max_backoff, |d| d.min(self.max_backoff))
+    }
+}
+
+/// How the instance ensure request, which asks a propolis-server that was just
+/// started to create its vm, is retried while propolis is coming up. Attempts
+/// stop at the launch timeout, see [`Runner::set_launch_timeout`], even if
+/// `attempts` allows more.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EnsureRetry {
+    /// How many times the request is made before giving up. `None` retries
+    /// until the launch timeout. At least one attempt is always made.
+    pub attempts: Option<u32>,
+    /// The delay between attempts.
+    pub interval: Duration,
+}
+
+impl Default for EnsureRetry {
+    fn default() -> Self {
+        EnsureRetry {
+            attempts: None,
+            interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// How log output is formatted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable terminal output. This is the default.
+    Term,
+    /// One JSON object per line, for log aggregators.
+    Json,
+}
+
+impl LogFormat {
+    /// The log format selected by the `FALCON_LOG_FORMAT` environment
+    /// variable, either `term` or `json`. Anything else selects `term`.
+    pub fn from_env() -> Self {
+        match std::env::var("FALCON_LOG_FORMAT").as_deref() {
+            Ok("json") => LogFormat::Json,
+            _ => LogFormat::Term,
+        }
+    }
 }
 
 /// A Deployment is the top level Falcon object. It contains a set of nodes and
@@ -118,6 +407,35 @@ pub struct Deployment {
 
     /// External links connected to a host data link such as a phy or a vnic.
     pub ext_links: Vec<ExtLink>,
+
+    /// Shared segments interconnecting any number of nodes.
+    #[serde(default)]
+    pub bridges: Vec<Bridge>,
+
+    /// Prefix used to derive MAC addresses for viona endpoints that were not
+    /// given an explicit MAC.
+    #[serde(default)]
+    pub mac_prefix: Option<[u8; 3]>,
+
+    /// The `RUST_LOG` filter propolis-server is run with. See
+    /// [`Runner::set_propolis_log`].
+    #[serde(default)]
+    pub propolis_log: Option<String>,
+
+    /// Extra arguments passed to every propolis-server. See
+    /// [`Runner::set_propolis_args`].
+    #[serde(default)]
+    pub propolis_args: Vec<String>,
+
+    /// How long each stage of starting a propolis instance may take. See
+    /// [`Runner::set_launch_timeout`].
+    #[serde(default = "launch_timeout")]
+    pub launch_timeout: Duration,
+
+    /// How asking a new propolis instance to create its vm is retried. See
+    /// [`Runner::set_ensure_retry`].
+    #[serde(default)]
+    pub ensure_retry: EnsureRetry,
 }
 
 impl Default for Deployment {
@@ -127,18 +445,169 @@ impl Default for Deployment {
             nodes: Vec::new(),
             links: Vec::new(),
             ext_links: Vec::new(),
+            bridges: Vec::new(),
+            mac_prefix: None,
+            propolis_log: None,
+            propolis_args: Vec::new(),
+            launch_timeout: launch_timeout(),
+            ensure_retry: EnsureRetry::default(),
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+/// Metadata published alongside a base image as `{image}_0.meta.toml`,
+/// describing what the image requires from propolis in order to boot.
+///
+/// ```toml
+/// propolis_revs = ["fae5334bcad5e864794332c6fed5e6bb9ec88831"]
+/// features = ["p9fs"]
+/// ```
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ImageMetadata {
+    /// Propolis revisions the image is known to boot on. Empty means any.
+    #[serde(default)]
+    pub propolis_revs: Vec<String>,
+    /// Propolis features the image requires, see [`PROPOLIS_FEATURES`].
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+impl ImageMetadata {
+    /// Check the image against the propolis revision and features falcon is
+    /// built for. An image that has not been verified against [`PROPOLIS_REV`]
+    /// may still boot, so a revision mismatch is only a warning. A required
+    /// feature falcon does not support is an error.
+    pub fn check(&self, image: &str, log: &Logger) -> Result<(), Error> {
+        if !self.propolis_revs.is_empty()
+            && !self.propolis_revs.iter().any(|x| x == PROPOLIS_REV)
+        {
+            warn!(
+                log,
+                "image {} is not known to boot on propolis {}, \
+                 known good revisions: {}",
+                image,
+                PROPOLIS_REV,
+                self.propolis_revs.join(", "),
+            );
+        }
+        let missing: Vec<&str> = self
+            .features
+            .iter()
+            .map(|x| x.as_str())
+            .filter(|x| !PROPOLIS_FEATURES.contains(x))
+            .collect();
+        if !missing.is_empty() {
+            return Err(Error::Exec(format!(
+                "image {} requires propolis features not supported by \
+                 propolis {}: {}",
+                image,
+                PROPOLIS_REV,
+                missing.join(", "),
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum PrimaryDiskBacking {
     /// Use a zvol cloned from the image source.
     Zvol,
-    /// Use a file copied from the image source.
+    /// Use a file copied from the image source, in [`Runner::file_dir`].
     File,
 }
 
+/// The serialization format of topology files.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub enum TopologyFormat {
+    #[default]
+    Ron,
+    Toml,
+}
+
+impl TopologyFormat {
+    /// The name of the topology file of this format in the falcon directory.
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            TopologyFormat::Ron => "topology.ron",
+            TopologyFormat::Toml => "topology.toml",
+        }
+    }
+
+    /// The format of a topology file by its extension. Anything other than
+    /// `.toml` is RON.
+    pub fn of(path: &Utf8Path) -> TopologyFormat {
+        match path.extension() {
+            Some("toml") => TopologyFormat::Toml,
+            _ => TopologyFormat::Ron,
+        }
+    }
+}
+
+/// The topology file in `falcon_dir`, `topology.toml` if there is one and
+/// `topology.ron` otherwise.
+pub fn topology_path(falcon_dir: &Utf8Path) -> Utf8PathBuf {
+    let toml = falcon_dir.join(TopologyFormat::Toml.file_name());
+    if toml.exists() {
+        toml
+    } else {
+        falcon_dir.join(TopologyFormat::Ron.file_name())
+    }
+}
+
+/// Stands in for a null in TOML topologies where a null cannot just be left
+/// out, e.g. in arrays and enum variants.
+pub const TOML_NONE: &str = "__none__";
+
+fn json_to_toml(value: serde_json::Value) -> Option<toml::Value> {
+    use serde_json::Value;
+    Some(match value {
+        Value::Null => toml::Value::String(TOML_NONE.into()),
+        Value::Bool(b) => toml::Value::Boolean(b),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => toml::Value::Integer(i),
+            None => toml::Value::Float(n.as_f64()?),
+        },
+        Value::String(s) => toml::Value::String(s),
+        Value::Array(a) => toml::Value::Array(
+            a.into_iter().map(json_to_toml).collect::<Option<_>>()?,
+        ),
+        Value::Object(o) => {
+            // An absent field is a null, except for the value of a variant
+            // such as `{"Viona": null}`, which must be kept.
+            let keep_null = o.len() == 1;
+            let mut table = toml::map::Map::new();
+            for (k, v) in o {
+                if v.is_null() && !keep_null {
+                    continue;
+                }
+                table.insert(k, json_to_toml(v)?);
+            }
+            toml::Value::Table(table)
+        }
+    })
+}
+
+fn toml_to_json(value: toml::Value) -> serde_json::Value {
+    use serde_json::Value;
+    match value {
+        toml::Value::String(s) if s == TOML_NONE => Value::Null,
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => Value::from(i),
+        toml::Value::Float(f) => Value::from(f),
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Datetime(d) => Value::String(d.to_string()),
+        toml::Value::Array(a) => {
+            Value::Array(a.into_iter().map(toml_to_json).collect())
+        }
+        toml::Value::Table(t) => Value::Object(
+            t.into_iter().map(|(k, v)| (k, toml_to_json(v))).collect(),
+        ),
+    }
+}
+
 /// A node in a falcon network.
 #[derive(Serialize, Deserialize)]
 pub struct Node {
@@ -166,9 +635,152 @@ pub struct Node {
     pub primary_disk_backing: PrimaryDiskBacking,
     /// VNC port to use
     pub vnc_port: Option<u16>,
+    /// Whether the node has a VNC server, on `vnc_port` if set or on a free
+    /// port picked at launch otherwise
+    #[serde(default)]
+    pub vnc: bool,
+    /// Host-side commands to run around launching the node
+    #[serde(default)]
+    pub host_hooks: HostHooks,
+    /// How long to wait after logging in before running setup commands
+    #[serde(default)]
+    pub settle_time: Duration,
+    /// Existing host storage attached to the node as additional disks
+    #[serde(default)]
+    pub host_disks: Vec<HostDisk>,
+    /// Additional empty disks created for the node
+    #[serde(default)]
+    pub extra_disks: Vec<ExtraDisk>,
+    /// Whether the node's chipset exposes PCIe rather than conventional PCI
+    #[serde(default)]
+    pub enable_pcie: bool,
+    /// CPUID table presented to the guest instead of the host derived one
+    #[serde(default)]
+    pub cpuid: Option<cpuid::CpuidEntries>,
+    /// Credentials for the serial console, overriding the deployment's
+    #[serde(default)]
+    pub login: Option<serial::Credentials>,
+    /// ISO images attached to the node as read-only disks
+    #[serde(default)]
+    pub isos: Vec<Utf8PathBuf>,
+    /// Script run in the guest once setup has completed at launch
+    #[serde(default)]
+    pub init_script: Option<String>,
+    /// Nodes with a higher boot priority are started before those with a
+    /// lower one
+    #[serde(default)]
+    pub boot_priority: u32,
+    /// Version of the image to use, the latest published one if unset
+    #[serde(default)]
+    pub image_version: Option<String>,
+    /// Metadata propolis is given for the instance, nil ids and `falcon` sled
+    /// details if unset
+    #[serde(default)]
+    pub instance_metadata: Option<InstanceMetadata>,
+    /// P4 program and rules loaded into the SoftNPU of this node
+    #[serde(default)]
+    pub softnpu: Option<SoftNpuConfig>,
+    /// The operating system of the node's image
+    #[serde(default)]
+    pub guest_os: serial::GuestOs,
+    /// Environment variables exported in the node's shell at login
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// propolis-server binary to launch this node with instead of the
+    /// deployment's
+    #[serde(default)]
+    pub propolis_binary: Option<String>,
+}
+
+/// The dataplane of a node with SoftNPU ports, see
+/// [`Runner::set_softnpu_config`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SoftNpuConfig {
+    /// The compiled P4 program on the host, e.g. `libsidecar_lite.so`.
+    pub program: Utf8PathBuf,
+    /// A script on the host that is run in the node once the program is
+    /// loaded, e.g. to add initial rules with `scadm`.
+    pub config: Option<Utf8PathBuf>,
+}
+
+impl SoftNpuConfig {
+    /// Where the program is found in the guest.
+    fn guest_program(&self) -> Utf8PathBuf {
+        guest_softnpu_path(SOFTNPU_PROGRAM_DIR, &self.program)
+    }
+
+    /// Where the config script is found in the guest.
+    fn guest_config(&self) -> Option<Utf8PathBuf> {
+        self.config
+            .as_ref()
+            .map(|c| guest_softnpu_path(SOFTNPU_CONFIG_DIR, c))
+    }
+}
+
+/// The guest file marking that a node's init script has run successfully, so
+/// relaunching the node does not run it again.
+const INIT_DONE_MARKER: &str = "/var/lib/falcon/init.done";
+
+/// The guest directory a SoftNPU program is copied to.
+const SOFTNPU_PROGRAM_DIR: &str = "/opt/falcon/softnpu/program";
+
+/// The guest directory a SoftNPU config is copied to.
+const SOFTNPU_CONFIG_DIR: &str = "/opt/falcon/softnpu/config";
+
+fn guest_softnpu_path(dir: &str, host: &Utf8Path) -> Utf8PathBuf {
+    Utf8Path::new(dir).join(host.file_name().unwrap_or_default())
+}
+
+/// An additional empty disk created for a node and attached as a virtio block
+/// device. Unlike host disks, the backing of an extra disk is owned by falcon
+/// and is deleted when the deployment is destroyed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExtraDisk {
+    /// Size of the disk in GB.
+    pub size_gb: usize,
+    /// How to create the backing of the disk.
+    pub backing: PrimaryDiskBacking,
+}
+
+/// Existing host storage, either an image file or a block device, attached to
+/// a node as a virtio block device. Falcon does not own this storage. It is
+/// never created or deleted by falcon, and is released when the node's
+/// propolis instance exits.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostDisk {
+    /// Path to the image file or block device on the host.
+    pub path: Utf8PathBuf,
+    /// Whether the guest is prevented from writing to the disk.
+    pub read_only: bool,
+}
+
+/// Host-side shell commands that are run around launching a node. Each command
+/// is run with `sh -c` in falcon's working directory, with the following
+/// environment variables set in addition to falcon's own environment.
+///
+/// - `FALCON_DEPLOYMENT`: the name of the deployment
+/// - `FALCON_NODE`: the name of the node
+/// - `FALCON_UUID`: the propolis instance uuid of the node
+/// - `FALCON_PORT`: the propolis server port of the node (post-launch only)
+///
+/// The pre-launch hook runs before the propolis-server process is started. The
+/// post-launch hook runs once the node is up and its initial setup has
+/// completed. Hooks run without blocking the launch of other nodes. A hook
+/// that fails to run, exits non-zero or runs longer than the runner's launch
+/// timeout fails the launch of the node, and a hook that times out is killed.
+/// Hooks are only run by `launch`, not by `hyperstart`. Hooks are shell
+/// commands only, as they are recorded in the topology; Rust closures are
+/// not supported, callers needing one can run it around
+/// [`Runner::launch`] instead.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct HostHooks {
+    /// Command to run before the node is launched.
+    pub pre_launch: Option<String>,
+    /// Command to run after the node is launched.
+    pub post_launch: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub enum GuestMountMechanism {
     P9kp,
     Mount,
@@ -185,6 +797,37 @@ pub struct Mount {
 
     /// Mechanism to mount in the guest.
     pub mechanism: GuestMountMechanism,
+
+    /// Size of p9fs messages in bytes. This is used both as the chunk size of
+    /// the propolis p9fs device and as the msize of the guest mount.
+    #[serde(default = "default_p9fs_chunk_size")]
+    pub chunk_size: u32,
+}
+
+/// Default size of p9fs messages.
+pub const DEFAULT_P9FS_CHUNK_SIZE: u32 = 65536;
+
+fn default_p9fs_chunk_size() -> u32 {
+    DEFAULT_P9FS_CHUNK_SIZE
+}
+
+/// Options for mounting a host directory into a node, see
+/// [`Runner::mount_with_opts`].
+#[derive(Debug, Clone)]
+pub struct MountOptions {
+    /// Mechanism to mount in the guest.
+    pub mechanism: GuestMountMechanism,
+    /// Size of p9fs messages in bytes.
+    pub chunk_size: u32,
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        MountOptions {
+            mechanism: GuestMountMechanism::P9kp,
+            chunk_size: DEFAULT_P9FS_CHUNK_SIZE,
+        }
+    }
 }
 
 /// Node references are passed back to clients when nodes are created. These are
@@ -196,6 +839,108 @@ pub struct NodeRef {
     index: usize,
 }
 
+/// An IP address configured on an interface inside a guest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IfAddr {
+    /// Name of the guest interface the address is configured on e.g., vioif0.
+    pub ifname: String,
+    /// The configured address and its prefix length.
+    pub addr: IpNet,
+}
+
+/// A base image installed under `{dataset}/img`, see [`Runner::list_images`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageInfo {
+    /// Name of the image, including its version if it was pinned.
+    pub name: String,
+    /// Size of the image data in bytes.
+    pub size: u64,
+    /// When the image was installed.
+    pub created: std::time::SystemTime,
+    /// Whether a node of the deployment uses the image.
+    pub used: bool,
+}
+
+/// What was started for a node, see [`Runner::launch_detailed`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeLaunch {
+    /// The bhyve instance uuid.
+    pub uuid: uuid::Uuid,
+    /// The port the propolis-server API listens on.
+    pub port: u16,
+    /// Process id of the propolis-server process.
+    pub pid: u32,
+    /// The port the VNC server listens on, if VNC is enabled.
+    pub vnc_port: Option<u16>,
+}
+
+/// The nodes started by [`Runner::launch_detailed`].
+#[derive(Debug, Clone, Default)]
+pub struct LaunchSummary {
+    /// What was started for each node, keyed by node name.
+    pub nodes: BTreeMap<String, NodeLaunch>,
+}
+
+impl LaunchSummary {
+    /// What was started for node `n` of deployment `d`.
+    pub fn node(&self, d: &Deployment, n: NodeRef) -> Option<&NodeLaunch> {
+        self.nodes.get(&d.nodes[n.index].name)
+    }
+}
+
+/// The result of measuring the throughput between two nodes, see
+/// [`Runner::measure_throughput`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThroughputReport {
+    /// Megabits per second sent by the client.
+    pub sent_mbps: f64,
+    /// Megabits per second received by the server.
+    pub received_mbps: f64,
+    /// TCP retransmits seen by the client, if the guest reports them.
+    pub retransmits: Option<u64>,
+}
+
+/// A propolis-server process launched by falcon whose falcon state no longer
+/// references it.
+#[derive(Debug, Clone)]
+pub struct OrphanInfo {
+    /// Process id of the propolis-server process.
+    pub pid: i32,
+    /// The propolis config file the process was started with.
+    pub config: Utf8PathBuf,
+    /// The bhyve instance uuid, if it could still be determined.
+    pub uuid: Option<uuid::Uuid>,
+}
+
+/// Links and datasets named like falcon's that no known deployment owns, see
+/// [`Runner::find_garbage`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Garbage {
+    /// Vnics of node endpoints.
+    pub vnics: Vec<String>,
+    /// Simnets underlying node endpoints.
+    pub simnets: Vec<String>,
+    /// Etherstubs of bridges.
+    pub etherstubs: Vec<String>,
+    /// Datasets holding node disks, `{dataset}/topo/{deployment}`.
+    pub datasets: Vec<String>,
+}
+
+impl Garbage {
+    pub fn is_empty(&self) -> bool {
+        self.links().next().is_none() && self.datasets.is_empty()
+    }
+
+    /// All the links, in the order they can be deleted in: vnics, then the
+    /// simnets and etherstubs they may be created over.
+    pub fn links(&self) -> impl Iterator<Item = &String> {
+        self.vnics
+            .iter()
+            .chain(self.simnets.iter())
+            .chain(self.etherstubs.iter())
+    }
+}
+
 /// Links connect nodes through a pair of Endpoints. Links are strictly point to
 /// point. They are meant to represent a single cable between machines. The only
 /// future exception to this may be for breakout cables that have a 1 to N
@@ -203,6 +948,60 @@ pub struct NodeRef {
 #[derive(Serialize, Deserialize)]
 pub struct Link {
     pub endpoints: [Endpoint; 2],
+    /// Properties of the simulated wire between the endpoints
+    #[serde(default)]
+    pub props: LinkProperties,
+    /// Static addresses configured on the guest interfaces of the endpoints
+    #[serde(default)]
+    pub addrs: Vec<LinkAddr>,
+}
+
+/// A static address configured on the guest interface of one endpoint of a
+/// link when its node is launched.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkAddr {
+    /// The endpoint of the link the address belongs to, 0 or 1.
+    pub endpoint: usize,
+    /// The address and its prefix length.
+    pub addr: IpNet,
+}
+
+/// Properties of the simulated wire of a link. These are applied to both ends
+/// of the link when it is created.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LinkProperties {
+    /// One way latency to add to the link in milliseconds. Simnet links on
+    /// current illumos releases have no delay property, so this is recorded in
+    /// the topology but not yet applied. A warning is logged when the link is
+    /// created with a latency set.
+    pub latency_ms: Option<u32>,
+    /// Bandwidth limit for the link in megabits per second. This is applied as
+    /// the `maxbw` property of the vnic on each end of the link. The minimum
+    /// bandwidth illumos supports is 1.2 Mbps, smaller values are rejected
+    /// by dladm when the link is created.
+    pub bandwidth_mbps: Option<u64>,
+}
+
+/// A bridge is a shared layer 2 segment, like a switch, connecting any number
+/// of nodes. It is realized as an etherstub with a vnic on top of it for each
+/// node. The etherstub is a virtual switch that only forwards frames to the
+/// vnic whose MAC address they are destined to, so promiscuous filtering is
+/// turned off on each vnic as it is for links. Vnics take the MTU of the
+/// etherstub, which is 9000 rather than the 1500 of simnet links, so guests
+/// may negotiate larger frames on a bridge than on a link.
+#[derive(Serialize, Deserialize)]
+pub struct Bridge {
+    pub endpoints: Vec<Endpoint>,
+}
+
+/// Opaque handle to a bridge. Used by clients to perform API functions on
+/// bridges owned by a Deployment. This is not a [`LinkRef`], as the functions
+/// taking a `LinkRef` address a link's simnet pair or one of its two
+/// endpoints, which a bridge does not have.
+#[derive(Copy, Clone, Serialize, Deserialize)]
+pub struct BridgeRef {
+    /// The index of the referenced bridge in `Deployment::bridges`
+    _index: usize,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -236,6 +1035,14 @@ impl EndpointKind {
             Self::SoftNPU(_) => "sn",
         }
     }
+
+    fn kind_name(&self) -> &'static str {
+        match self {
+            Self::Viona(_) => "viona",
+            Self::Sidemux(_, _) => "sidemux",
+            Self::SoftNPU(_) => "softnpu",
+        }
+    }
 }
 
 /// Endpoints are owned by a Link and reference nodes through a references.
@@ -252,6 +1059,37 @@ pub struct Endpoint {
     kind: EndpointKind,
 }
 
+impl Endpoint {
+    /// The node this endpoint is attached to.
+    pub fn node(&self) -> NodeRef {
+        self.node
+    }
+
+    /// The index of this endpoint within its node.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// What kind of virtual device this endpoint is realized as.
+    pub fn kind(&self) -> &EndpointKind {
+        &self.kind
+    }
+}
+
+/// What launching a deployment would do, as computed by [`Runner::plan`].
+#[derive(Debug, Serialize)]
+pub struct Plan {
+    /// Base image snapshots node disks are cloned from. These are downloaded
+    /// if they do not exist yet.
+    pub images: Vec<String>,
+    /// Disk backings that are created for nodes, zvols or files.
+    pub disks: Vec<String>,
+    /// Data links that are created on the host.
+    pub links: Vec<String>,
+    /// The propolis configuration of each node.
+    pub configs: BTreeMap<String, propolis_server_config::Config>,
+}
+
 /// Opaque handle to a link. Used by clients to perform API functions on
 /// links owned by a Deployment.
 #[derive(Copy, Clone, Serialize, Deserialize)]
@@ -260,36 +1098,216 @@ pub struct LinkRef {
     _index: usize,
 }
 
-impl Runner {
+/// Builds a [`Runner`] with settings that are otherwise fixed at construction,
+/// such as its logger and dataset, along with the settings that can also be
+/// changed later with `Runner::set_*` methods. Anything not set keeps the
+/// default [`Runner::new`] would use.
+pub struct RunnerBuilder {
+    name: String,
+    falcon_dir: Option<Utf8PathBuf>,
+    dataset: Option<String>,
+    file_dir: Option<String>,
+    propolis_binary: Option<String>,
+    log: Option<Logger>,
+    log_format: Option<LogFormat>,
+    persistent: bool,
+    asset_base_url: Option<String>,
+    verify_images: Option<bool>,
+    launch_timeout: Option<Duration>,
+}
+
+impl RunnerBuilder {
     pub fn new(name: &str) -> Self {
+        RunnerBuilder {
+            name: name.into(),
+            falcon_dir: None,
+            dataset: None,
+            file_dir: None,
+            propolis_binary: None,
+            log: None,
+            log_format: None,
+            persistent: false,
+            asset_base_url: None,
+            verify_images: None,
+            launch_timeout: None,
+        }
+    }
+
+    /// The directory falcon keeps deployment state in.
+    pub fn falcon_dir(mut self, dir: impl Into<Utf8PathBuf>) -> Self {
+        self.falcon_dir = Some(dir.into());
+        self
+    }
+
+    /// The ZFS dataset images and node disks are created under.
+    pub fn dataset(mut self, dataset: impl Into<String>) -> Self {
+        self.dataset = Some(dataset.into());
+        self
+    }
+
+    /// The directory file backed disks are created in.
+    pub fn file_dir(mut self, dir: impl Into<String>) -> Self {
+        self.file_dir = Some(dir.into());
+        self
+    }
+
+    /// The propolis-server binary to launch nodes with.
+    pub fn propolis_binary(mut self, binary: impl Into<String>) -> Self {
+        self.propolis_binary = Some(binary.into());
+        self
+    }
+
+    /// Log through `log` instead of a logger created by falcon.
+    pub fn log(mut self, log: Logger) -> Self {
+        self.log = Some(log);
+        self
+    }
+
+    /// The format of the logger created by falcon. This has no effect if a
+    /// logger is provided with [`RunnerBuilder::log`].
+    pub fn log_format(mut self, format: LogFormat) -> Self {
+        self.log_format = Some(format);
+        self
+    }
+
+    /// Whether the deployment is left in place when the runner is dropped.
+    pub fn persistent(mut self, persistent: bool) -> Self {
+        self.persistent = persistent;
+        self
+    }
+
+    /// The URL base images are downloaded from.
+    pub fn asset_base_url(mut self, url: impl Into<String>) -> Self {
+        self.asset_base_url = Some(url.into());
+        self
+    }
+
+    /// Whether downloaded base images are checked against their published
+    /// sha256 digest.
+    pub fn verify_images(mut self, verify: bool) -> Self {
+        self.verify_images = Some(verify);
+        self
+    }
+
+    /// How long each stage of starting a propolis instance may take.
+    pub fn launch_timeout(mut self, timeout: Duration) -> Self {
+        self.launch_timeout = Some(timeout);
+        self
+    }
+
+    pub fn build(self) -> Runner {
+        let name = self.name.as_str();
         namecheck!(name, "deployment");
 
-        match std::env::var("RUST_LOG") {
-            Ok(s) => {
-                if s.is_empty() {
-                    unsafe {
-                        std::env::set_var("RUST_LOG", "info");
-                    }
-                }
+        let keys = slog::o!("deployment" => name.to_string());
+        let log = match self.log {
+            Some(log) => log.new(keys),
+            None => {
+                let format =
+                    self.log_format.unwrap_or_else(LogFormat::from_env);
+                default_logger(format, keys)
             }
-            _ => unsafe {
-                std::env::set_var("RUST_LOG", "info");
-            },
-        }
+        };
 
-        let decorator = slog_term::TermDecorator::new().build();
-        let drain = slog_term::FullFormat::new(decorator).build().fuse();
-        let drain = slog_envlogger::new(drain).fuse();
-        let drain = slog_async::Async::new(drain).build().fuse();
+        let mut deployment = Deployment::new(name);
+        if let Some(timeout) = self.launch_timeout {
+            deployment.launch_timeout = timeout;
+        }
 
         Runner {
-            deployment: Deployment::new(name),
-            log: slog::Logger::root(drain, slog::o!()),
-            persistent: false,
-            propolis_binary: "propolis-server".into(),
-            dataset: dataset(),
-            falcon_dir: DEFAULT_FALCON_DIR.into(),
+            deployment,
+            log,
+            persistent: self.persistent,
+            propolis_binary: self
+                .propolis_binary
+                .unwrap_or_else(|| "propolis-server".into()),
+            dataset: self.dataset.unwrap_or_else(dataset),
+            file_dir: self.file_dir.unwrap_or_else(file_dir),
+            falcon_dir: self
+                .falcon_dir
+                .unwrap_or_else(|| DEFAULT_FALCON_DIR.into()),
+            strip_ansi: true,
+            exec_strategy: serial::ExecStrategy::default(),
+            topology_format: TopologyFormat::default(),
+            login_timeout: Duration::from_millis(serial::LOGIN_TIMEOUT_MS),
+            login_retries: 0,
+            panic_patterns: Vec::new(),
+            asset_base_url: self.asset_base_url.unwrap_or_else(asset_base_url),
+            verify_images: self.verify_images.unwrap_or_else(verify_images),
+            serial_logging: false,
+            peer_hosts: false,
+            login: serial::Credentials::default(),
+            progress: progress(),
+            zfs_opts: ZfsOpts::default(),
+            libnet_retry: LibnetRetry::default(),
+            skip_version_check: false,
+            overcommit: false,
+        }
+    }
+}
+
+/// Create falcon's own logger. Its level is taken from `RUST_LOG`, which is set
+/// to `info` if it is not set already.
+fn default_logger(
+    format: LogFormat,
+    keys: slog::OwnedKV<impl slog::SendSyncRefUnwindSafeKV + 'static>,
+) -> Logger {
+    match std::env::var("RUST_LOG") {
+        Ok(s) => {
+            if s.is_empty() {
+                unsafe {
+                    std::env::set_var("RUST_LOG", "info");
+                }
+            }
+        }
+        _ => unsafe {
+            std::env::set_var("RUST_LOG", "info");
+        },
+    }
+
+    match format {
+        LogFormat::Term => {
+            let decorator = slog_term::TermDecorator::new().build();
+            let drain = slog_term::FullFormat::new(decorator).build().fuse();
+            let drain = slog_envlogger::new(drain).fuse();
+            let drain = slog_async::Async::new(drain).build().fuse();
+            slog::Logger::root(drain, keys)
         }
+        LogFormat::Json => {
+            let drain = slog_json::Json::default(std::io::stderr()).fuse();
+            let drain = slog_envlogger::new(drain).fuse();
+            let drain = slog_async::Async::new(drain).build().fuse();
+            slog::Logger::root(drain, keys)
+        }
+    }
+}
+
+impl Runner {
+    pub fn new(name: &str) -> Self {
+        RunnerBuilder::new(name).build()
+    }
+
+    /// Create a runner that logs through `log`. Unlike [`Runner::new`], this
+    /// leaves the `RUST_LOG` environment variable alone, so falcon can be
+    /// embedded in applications that already have logging set up.
+    pub fn with_logger(name: &str, log: Logger) -> Self {
+        RunnerBuilder::new(name).log(log).build()
+    }
+
+    /// Create a runner for the deployment described by a topology file, e.g.
+    /// one written by `preflight` or authored by another tool. The runner has
+    /// the same settings as one created by [`Runner::new`].
+    pub fn from_topology(path: &Utf8Path) -> Result<Runner, Error> {
+        let deployment = Deployment::from_file(path)?;
+        deployment.validate()?;
+        let mut r = Runner::new(&deployment.name);
+        r.deployment = deployment;
+        Ok(r)
+    }
+
+    /// Start building a runner, see [`RunnerBuilder`].
+    pub fn builder(name: &str) -> RunnerBuilder {
+        RunnerBuilder::new(name)
     }
 
     /// Create a new node within this deployment with the given name. Names must
@@ -321,6 +1339,23 @@ impl Runner {
             reserved: 20,
             primary_disk_backing: PrimaryDiskBacking::Zvol,
             vnc_port: None,
+            vnc: false,
+            host_hooks: HostHooks::default(),
+            settle_time: Duration::ZERO,
+            host_disks: Vec::new(),
+            extra_disks: Vec::new(),
+            enable_pcie: false,
+            cpuid: None,
+            login: None,
+            isos: Vec::new(),
+            init_script: None,
+            boot_priority: 0,
+            image_version: None,
+            instance_metadata: None,
+            softnpu: None,
+            guest_os: serial::GuestOs::default(),
+            env: BTreeMap::new(),
+            propolis_binary: None,
         };
         self.deployment.nodes.push(n);
         r
@@ -344,14 +1379,35 @@ impl Runner {
         &self.deployment.nodes[r.index]
     }
 
-    pub fn do_setup(&mut self, r: NodeRef, value: bool) {
-        self.deployment.nodes[r.index].do_setup = value;
+    pub fn all_links(&self) -> Vec<LinkRef> {
+        (0..self.deployment.links.len())
+            .map(|_index| LinkRef { _index })
+            .collect()
     }
 
-    pub fn bump_radix(&mut self, node: NodeRef) -> usize {
-        let current = self.deployment.nodes[node.index].radix;
-        self.deployment.nodes[node.index].radix += 1;
-        current
+    pub fn get_link(&self, r: LinkRef) -> &Link {
+        &self.deployment.links[r._index]
+    }
+
+    /// Find the link between nodes `a` and `b`, in either direction. If more
+    /// than one link connects the two nodes, the one created first is
+    /// returned, use [`Runner::all_links`] to get at the others.
+    pub fn find_link(&self, a: NodeRef, b: NodeRef) -> Option<LinkRef> {
+        let _index = self.deployment.links.iter().position(|l| {
+            let [x, y] = [l.endpoints[0].node.index, l.endpoints[1].node.index];
+            (x, y) == (a.index, b.index) || (y, x) == (a.index, b.index)
+        })?;
+        Some(LinkRef { _index })
+    }
+
+    pub fn do_setup(&mut self, r: NodeRef, value: bool) {
+        self.deployment.nodes[r.index].do_setup = value;
+    }
+
+    pub fn bump_radix(&mut self, node: NodeRef) -> usize {
+        let current = self.deployment.nodes[node.index].radix;
+        self.deployment.nodes[node.index].radix += 1;
+        current
     }
 
     /// Create a new link within this deployment between the referenced nodes.
@@ -372,6 +1428,8 @@ impl Runner {
                     kind: EndpointKind::Viona(None),
                 },
             ],
+            props: LinkProperties::default(),
+            addrs: Vec::new(),
         };
         self.deployment.links.push(l);
         self.deployment.nodes[a.index].radix += 1;
@@ -379,6 +1437,83 @@ impl Runner {
         r
     }
 
+    /// Create a new bridge within this deployment connecting all of the
+    /// referenced nodes to a single shared segment.
+    pub fn bridge(&mut self, nodes: &[NodeRef]) -> BridgeRef {
+        let r = BridgeRef {
+            _index: self.deployment.bridges.len(),
+        };
+        let endpoints = nodes
+            .iter()
+            .map(|n| Endpoint {
+                node: *n,
+                index: self.bump_radix(*n),
+                kind: EndpointKind::Viona(None),
+            })
+            .collect();
+        self.deployment.bridges.push(Bridge { endpoints });
+        r
+    }
+
+    /// Set the latency and bandwidth of the simulated wire of a link. See
+    /// [`LinkProperties`] for how each property is realized. The properties
+    /// are saved in the topology and applied when the link is created.
+    pub fn set_link_properties(
+        &mut self,
+        l: LinkRef,
+        latency_ms: Option<u32>,
+        bandwidth_mbps: Option<u64>,
+    ) {
+        self.deployment.links[l._index].props = LinkProperties {
+            latency_ms,
+            bandwidth_mbps,
+        };
+    }
+
+    /// Configure `addr` on the guest interface of `endpoint` (0 or 1) of a link
+    /// when its node is launched. Both IPv4 and IPv6 addresses are supported,
+    /// and an endpoint may have more than one address. See
+    /// [`Deployment::guest_ifname`] for how the guest interface is found.
+    pub fn set_link_addr(
+        &mut self,
+        l: LinkRef,
+        endpoint: usize,
+        addr: impl Into<IpNet>,
+    ) -> Result<(), Error> {
+        let link = &mut self.deployment.links[l._index];
+        match link.endpoints.get(endpoint) {
+            Some(e) if matches!(e.kind, EndpointKind::Viona(_)) => {}
+            Some(_) => {
+                return Err(Error::Exec(format!(
+                    "link endpoint {endpoint} is not a viona endpoint"
+                )))
+            }
+            None => {
+                return Err(Error::NotFound(format!(
+                    "link endpoint {endpoint}"
+                )))
+            }
+        }
+        link.addrs.push(LinkAddr {
+            endpoint,
+            addr: addr.into(),
+        });
+        Ok(())
+    }
+
+    /// The predicted name of the guest interface of node `n` on link `l`, e.g.
+    /// `vioif0`. This is based on the PCI slots viona devices are assigned in
+    /// `preflight`, and assumes the guest enumerates interfaces in PCI slot
+    /// order. Returns `None` if the node is not on the link or its endpoint is
+    /// not a viona endpoint.
+    pub fn guest_ifname(&self, n: NodeRef, l: LinkRef) -> Option<String> {
+        self.deployment.links[l._index]
+            .endpoints
+            .iter()
+            .find(|e| e.node.index == n.index)
+            .and_then(|e| self.deployment.guest_ifname(e))
+    }
+
     /// Create a sidecar controller link with the provided radix.
     ///
     /// The sidecar node will get a regular bhyve/viona endpoint. The controller
@@ -406,6 +1541,8 @@ impl Runner {
                     kind: EndpointKind::Sidemux(radix, macs),
                 },
             ],
+            props: LinkProperties::default(),
+            addrs: Vec::new(),
         };
         self.deployment.links.push(l);
         r
@@ -434,6 +1571,8 @@ impl Runner {
                     kind: EndpointKind::Viona(node_mac),
                 },
             ],
+            props: LinkProperties::default(),
+            addrs: Vec::new(),
         };
         self.deployment.links.push(l);
         self.deployment.nodes[softnpu_node.index].radix += 1;
@@ -464,6 +1603,8 @@ impl Runner {
                     kind: EndpointKind::SoftNPU(mac2),
                 },
             ],
+            props: LinkProperties::default(),
+            addrs: Vec::new(),
         };
         self.deployment.links.push(l);
         self.deployment.nodes[node1.index].radix += 1;
@@ -471,6 +1612,583 @@ impl Runner {
         r
     }
 
+    /// Shut a node down gracefully. The propolis instance is asked to stop,
+    /// and is killed once it has stopped or `timeout` has passed, whichever
+    /// comes first.
+    pub async fn shutdown(
+        &self,
+        n: NodeRef,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let name = &self.deployment.nodes[n.index].name;
+        graceful_stop(&self.log, name, &self.falcon_dir, timeout).await;
+        hyperstop(&self.log, name, &self.falcon_dir)
+    }
+
+    /// Roll the disk of a node back to `snapshot`, so it starts from that state
+    /// on its next `hyperstart`. The node's propolis instance is stopped first.
+    /// `snapshot` is either a zfs snapshot of the node's disk, or an image
+    /// created with `snapshot` that the disk is re-cloned from. Only nodes with
+    /// a zvol backed disk can be restored.
+    pub fn restore(&self, n: NodeRef, snapshot: &str) -> Result<(), Error> {
+        let node = &self.deployment.nodes[n.index];
+        if !matches!(node.primary_disk_backing, PrimaryDiskBacking::Zvol) {
+            return Err(Error::Exec(format!(
+                "{}: only zvol backed disks can be restored",
+                node.name
+            )));
+        }
+
+        hyperstop(&self.log, &node.name, &self.falcon_dir)?;
+
+        let disk = format!(
+            "{}/topo/{}/{}",
+            node.dataset, self.deployment.name, node.name
+        );
+        let disk_snapshot = format!("{}@{}", disk, snapshot);
+        let image_snapshot = format!("{}/img/{}@base", node.dataset, snapshot);
+
+        if zfs::exists(&disk_snapshot)? {
+            info!(
+                self.log,
+                "rolling back to {}", disk_snapshot; "node" => &node.name
+            );
+            zfs::rollback(&disk_snapshot)?;
+        } else if zfs::exists(&image_snapshot)? {
+            info!(
+                self.log,
+                "re-cloning from {}", image_snapshot; "node" => &node.name
+            );
+            zfs::destroy(&disk)?;
+            zfs::clone(&image_snapshot, &disk)?;
+            zfs::set_prop(&disk, "volsize", &format!("{}G", node.reserved))?;
+        } else {
+            return Err(Error::NotFound(format!(
+                "snapshot {} of {}: neither {} nor {} exist",
+                snapshot, node.name, disk_snapshot, image_snapshot
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Install the raw disk image at `path` as the base image `name`, so nodes
+    /// can use it without it being downloaded. Images compressed with xz are
+    /// recognized by a `.xz` extension and are extracted next to `path` first.
+    /// It is an error to import an image that already exists.
+    pub fn import_image(
+        &self,
+        name: &str,
+        path: impl AsRef<Utf8Path>,
+    ) -> Result<(), Error> {
+        let path = path.as_ref();
+        let snapshot = format!("{}/img/{}@base", self.dataset, name);
+        if zfs::exists(&snapshot)? {
+            return Err(Error::Exec(format!("image {name} already exists")));
+        }
+
+        let (source, fsize) = if path.extension() == Some("xz") {
+            let extracted = path.with_extension("");
+            let fsize = Node::try_extract_image(
+                &self.log,
+                path.as_str(),
+                extracted.as_str(),
+                &self.multi_progress(),
+            )?;
+            (extracted, fsize)
+        } else {
+            let fsize = fs::metadata(path)?.len().try_into()?;
+            (path.to_owned(), fsize)
+        };
+
+        info!(self.log, "importing {} as image {}", path, name);
+        Node::try_create_zfs_volume_for_image(
+            &self.log,
+            &self.dataset,
+            name,
+            fsize,
+            source.as_str(),
+            &self.zfs_opts,
+            &self.multi_progress(),
+        )
+    }
+
+    /// The names of the base images installed under `{dataset}/img`.
+    fn installed_images(&self) -> Result<Vec<String>, Error> {
+        let img = format!("{}/img", self.dataset);
+        if !zfs::exists(&img)? {
+            return Ok(Vec::new());
+        }
+        let out = zfs::run(&[
+            "list",
+            "-H",
+            "-o",
+            "name",
+            "-t",
+            "volume",
+            "-d",
+            "1",
+            img.as_str(),
+        ])?;
+        let prefix = format!("{img}/");
+        Ok(out
+            .lines()
+            .filter_map(|x| x.strip_prefix(&prefix))
+            .map(String::from)
+            .collect())
+    }
+
+    /// List the base images installed under `{dataset}/img`, i.e. the images
+    /// nodes can use without them being downloaded first.
+    pub fn list_images(&self) -> Result<Vec<ImageInfo>, Error> {
+        let img = format!("{}/img", self.dataset);
+        if !zfs::exists(&img)? {
+            return Ok(Vec::new());
+        }
+        let out = zfs::run(&[
+            "list",
+            "-H",
+            "-p",
+            "-o",
+            "name,referenced,creation",
+            "-t",
+            "snapshot",
+            "-r",
+            img.as_str(),
+        ])?;
+        let mut images = parse_image_list(&img, &out);
+        for image in &mut images {
+            image.used = self
+                .deployment
+                .nodes
+                .iter()
+                .any(|n| n.image_name() == image.name);
+        }
+        Ok(images)
+    }
+
+    /// The installed base images that are neither used by a node of the
+    /// deployment nor named in `keep`, see [`Runner::prune_images`].
+    pub fn prunable_images(&self, keep: &[&str]) -> Result<Vec<String>, Error> {
+        let used: Vec<String> = self
+            .deployment
+            .nodes
+            .iter()
+            .map(|n| n.image_name())
+            .collect();
+        Ok(self
+            .installed_images()?
+            .into_iter()
+            .filter(|x| !used.contains(x) && !keep.contains(&x.as_str()))
+            .collect())
+    }
+
+    /// Delete the installed base images that are neither used by a node of the
+    /// deployment nor named in `keep`, along with their downloads in `/tmp`.
+    /// Returns the names of the deleted images. Nothing is deleted if any of
+    /// these images still has clones depending on it, such as the disks of
+    /// nodes of another deployment. The error lists the dependent clones.
+    pub fn prune_images(&self, keep: &[&str]) -> Result<Vec<String>, Error> {
+        let images = self.prunable_images(keep)?;
+
+        let mut dependents = Vec::new();
+        for image in &images {
+            let zpath = format!("{}/img/{}", self.dataset, image);
+            let out = zfs::run(&[
+                "list",
+                "-H",
+                "-o",
+                "clones",
+                "-t",
+                "snapshot",
+                "-r",
+                zpath.as_str(),
+            ])?;
+            let clones = parse_zfs_clones(&out);
+            if !clones.is_empty() {
+                dependents.push(format!("{} ({})", image, clones.join(", ")));
+            }
+        }
+        if !dependents.is_empty() {
+            return Err(Error::Zfs(format!(
+                "images have dependent clones: {}",
+                dependents.join("; ")
+            )));
+        }
+
+        // downloads of images that are still in use are left alone, a pinned
+        // version "0" shares its downloads with the unpinned image
+        let used: Vec<String> = self
+            .deployment
+            .nodes
+            .iter()
+            .map(|n| n.image_asset())
+            .collect();
+        for image in &images {
+            let zpath = format!("{}/img/{}", self.dataset, image);
+            info!(self.log, "destroying image {}", zpath);
+            zfs::destroy(&zpath)?;
+            for asset in [format!("{image}_0"), image.clone()] {
+                if used.contains(&asset) {
+                    continue;
+                }
+                for ext in ["raw.xz", "raw", "meta.toml"] {
+                    let path = format!("/tmp/{asset}.{ext}");
+                    if Path::new(&path).exists() {
+                        info!(self.log, "removing {}", path);
+                        fs::remove_file(&path)?;
+                    }
+                }
+            }
+        }
+
+        Ok(images)
+    }
+
+    /// Set how long each stage of starting a propolis instance may take. This
+    /// bounds both waiting for propolis to report the port it is listening on,
+    /// and waiting for it to accept the instance. The default is 30 seconds, or
+    /// the number of seconds in the `FALCON_LAUNCH_TIMEOUT` environment
+    /// variable if set. Heavily loaded hosts may need more. The timeout is
+    /// recorded in the topology, so `hyperstart` uses it too.
+    pub fn set_launch_timeout(&mut self, timeout: Duration) {
+        self.deployment.launch_timeout = timeout;
+    }
+
+    /// Set how the request asking a new propolis instance to create its vm is
+    /// retried. By default it is retried every second until the launch timeout.
+    /// If every attempt fails, the error says how many were made over how long
+    /// and what they failed with. The retry settings are recorded in the
+    /// topology, so `hyperstart` uses them too.
+    pub fn set_ensure_retry(&mut self, retry: EnsureRetry) {
+        self.deployment.ensure_retry = retry;
+    }
+
+    /// Set whether everything received from the serial console of each node,
+    /// from boot output to the output of commands, is logged to
+    /// `{node}.console.log` in the falcon directory. Logs are rotated at
+    /// [`serial::CONSOLE_LOG_MAX`] bytes.
+    pub fn set_serial_logging(&mut self, on: bool) {
+        self.serial_logging = on;
+    }
+
+    /// Set whether `/etc/hosts` of every node gets an entry for each static
+    /// link address of the other nodes, so nodes can reach each other by name.
+    /// Entries are derived from the addresses set with
+    /// [`Runner::set_link_addr`], see [`Deployment::peer_hosts`]. Off by
+    /// default.
+    pub fn enable_peer_hosts(&mut self, on: bool) {
+        self.peer_hosts = on;
+    }
+
+    /// Set whether progress bars are drawn for image downloads, extraction and
+    /// copies. By default they are drawn when stdout is a terminal, unless the
+    /// `FALCON_NO_PROGRESS` environment variable is set. Turning them off
+    /// keeps CI logs free of progress bar redraws.
+    pub fn set_progress(&mut self, on: bool) {
+        self.progress = on;
+    }
+
+    /// The progress bar container for image operations, hidden if progress
+    /// bars are turned off.
+    fn multi_progress(&self) -> MultiProgress {
+        if self.progress {
+            MultiProgress::new()
+        } else {
+            MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+        }
+    }
+
+    /// Set the credentials used to log into the serial console of every node
+    /// that does not have its own set with [`Runner::set_node_login`]. The
+    /// default is root with an empty password. Node setup such as mounts and
+    /// setting the hostname assumes a privileged user.
+    pub fn set_login(&mut self, username: &str, password: Option<&str>) {
+        self.login = serial::Credentials {
+            username: username.into(),
+            password: password.map(Into::into),
+        };
+    }
+
+    /// Set the credentials used to log into the serial console of a node.
+    pub fn set_node_login(
+        &mut self,
+        n: NodeRef,
+        username: &str,
+        password: Option<&str>,
+    ) {
+        self.deployment.nodes[n.index].login = Some(serial::Credentials {
+            username: username.into(),
+            password: password.map(Into::into),
+        });
+    }
+
+    /// Set environment variables that are exported once after logging into a
+    /// node, so every command run with `exec` sees them, e.g. proxy settings.
+    /// Values are double quoted in the guest shell, so `$VAR` references are
+    /// expanded, e.g. `/opt/ooce/bin:$PATH`, while `"`, `\` and `` ` `` are
+    /// taken literally. Names must be valid shell variable names.
+    pub fn set_node_env(
+        &mut self,
+        n: NodeRef,
+        env: HashMap<String, String>,
+    ) -> Result<(), Error> {
+        let re = regex::Regex::new("^[A-Za-z_][A-Za-z0-9_]*$").unwrap();
+        let invalid: Vec<String> = env
+            .keys()
+            .filter(|k| !re.is_match(k))
+            .map(|k| format!("invalid environment variable name `{k}`"))
+            .collect();
+        if !invalid.is_empty() {
+            return Err(Error::Validation(invalid));
+        }
+        self.deployment.nodes[n.index].env = env.into_iter().collect();
+        Ok(())
+    }
+
+    /// Set the URL base images are downloaded from, e.g. to use a mirror.
+    /// Passing `None` restores the default, which is the value of the
+    /// `FALCON_ASSET_BASE_URL` environment variable if set, or falcon's asset
+    /// bucket otherwise.
+    pub fn set_asset_base_url(&mut self, url: Option<String>) {
+        self.asset_base_url = url.unwrap_or_else(asset_base_url);
+    }
+
+    /// Set how link operations that fail are retried. The last error is
+    /// returned once every attempt has failed, and each failure is logged at
+    /// debug level.
+    pub fn set_libnet_retry(&mut self, retry: LibnetRetry) {
+        self.libnet_retry = retry;
+    }
+
+    /// Set the ZFS properties of the zvols created for base images and node
+    /// disks, e.g. to enable `lz4` compression. The values are checked here so
+    /// a typo is reported up front rather than as a zfs error during launch.
+    /// Images that are already installed keep their properties.
+    pub fn set_zfs_opts(&mut self, opts: ZfsOpts) -> Result<(), Error> {
+        opts.validate()?;
+        self.zfs_opts = opts;
+        Ok(())
+    }
+
+    /// Launch a node with its own propolis-server binary instead of the
+    /// deployment's, e.g. to compare a propolis change against a known good
+    /// build within one topology. `hyperstart` uses it too, and preflight
+    /// checks the version of every binary nodes are launched with.
+    pub fn set_node_propolis_binary(&mut self, n: NodeRef, binary: String) {
+        self.deployment.nodes[n.index].propolis_binary = Some(binary);
+    }
+
+    /// Set extra arguments to pass to propolis-server when launching nodes,
+    /// e.g. to try out a new propolis flag. They are appended after the
+    /// standard arguments, which are `run`, the instance config, the listen
+    /// address, and the VNC listen address for nodes that have a VNC server.
+    /// They are recorded in the topology, so `hyperstart` passes them too.
+    pub fn set_propolis_args(&mut self, args: Vec<String>) {
+        self.deployment.propolis_args = args;
+    }
+
+    /// Set the log filter propolis-server is run with, e.g. `debug`, by
+    /// setting `RUST_LOG` in its environment. This only changes what propolis
+    /// writes to `{node}.out`, not falcon's own logging. The filter is recorded
+    /// in the topology, so `hyperstart` uses it too. Falcon finds the port
+    /// propolis listens on in an info message of that log. The filter is
+    /// passed on unchecked, so it must keep that message: filters such as
+    /// `warn`, or ones naming only some modules such as
+    /// `propolis_server=trace`, may hide it and make launching time out.
+    pub fn set_propolis_log(&mut self, filter: String) {
+        self.deployment.propolis_log = Some(filter);
+    }
+
+    /// Set whether preflight accepts a propolis-server binary that reports a
+    /// revision other than [`PROPOLIS_REV`]. By default such a binary is
+    /// refused, as falcon's propolis client and config may not match its API.
+    /// This is for developers intentionally testing another propolis build.
+    pub fn set_skip_version_check(&mut self, skip: bool) {
+        self.skip_version_check = skip;
+    }
+
+    /// Set whether preflight accepts nodes that together need more memory
+    /// than the host has available. By default preflight fails rather than
+    /// risk the host running out of memory, which can take down other work.
+    pub fn set_overcommit(&mut self, overcommit: bool) {
+        self.overcommit = overcommit;
+    }
+
+    /// Set the directory file backed disks are created in, see
+    /// [`PrimaryDiskBacking::File`]. Each deployment gets its own
+    /// subdirectory, which `destroy` removes. The default is `/var/falcon/dsk`,
+    /// or the `FALCON_FILE_DIR` environment variable if set. Like the dataset,
+    /// this must be the same when a deployment is destroyed as when it was
+    /// launched, so setting the environment variable is the easiest way to
+    /// keep separate invocations consistent.
+    pub fn set_file_dir(&mut self, dir: impl Into<String>) {
+        self.file_dir = dir.into();
+    }
+
+    /// Set whether downloaded base images are checked against the sha256
+    /// digest published next to them as `{image}.sha256.txt`. A download that
+    /// does not match is removed and fetched again. Verification is on unless
+    /// the `FALCON_NO_VERIFY` environment variable is set. Turning it off is
+    /// useful for mirrors and local image builds that publish no digests.
+    pub fn set_verify_images(&mut self, verify: bool) {
+        self.verify_images = verify;
+    }
+
+    /// Derive the MAC addresses of viona endpoints that were not given an
+    /// explicit MAC from `prefix`. The remaining three octets are the index of
+    /// the node in the deployment as two octets, followed by the index of the
+    /// endpoint on the node, so addresses are unique within the deployment and
+    /// stable across launches. The prefix should be locally administered and
+    /// unicast, e.g. `[0xa8, 0x40, 0x25]`. Explicit MACs passed to `link`
+    /// style methods always take precedence over derived ones.
+    pub fn set_mac_prefix(&mut self, prefix: [u8; 3]) {
+        self.deployment.mac_prefix = Some(prefix);
+    }
+
+    /// Set whether a node's chipset exposes PCIe. Propolis only emulates the
+    /// i440FX chipset, so this enables PCIe extended configuration space on it
+    /// rather than selecting a Q35 board. It is off by default. Guests whose
+    /// kernels predate PCIe support, or that expect a Q35 topology, may not
+    /// enumerate devices correctly with it on.
+    pub fn set_chipset(&mut self, n: NodeRef, pcie: bool) {
+        self.deployment.nodes[n.index].enable_pcie = pcie;
+    }
+
+    /// Present `cpuid` to a node's guest instead of the CPUID table propolis
+    /// derives from the host. This is useful for masking CPU features to
+    /// exercise fallback code paths in the guest.
+    pub fn set_cpuid(&mut self, n: NodeRef, cpuid: cpuid::CpuidEntries) {
+        self.deployment.nodes[n.index].cpuid = Some(cpuid);
+    }
+
+    /// Run `script` in a node's guest when it is launched, after falcon's own
+    /// setup has completed. The script is copied into the guest and run with
+    /// `sh`. Its output is logged, and the launch fails if it exits non-zero.
+    /// The script runs once on first boot: once it has succeeded, a marker is
+    /// left at `/var/lib/falcon/init.done` in the guest, and launches of the
+    /// same node disk skip the script while the marker is there. The script
+    /// is only run by `launch`, not by `hyperstart`, and not at all for nodes
+    /// with `do_setup` turned off.
+    pub fn set_init_script(&mut self, n: NodeRef, script: String) {
+        self.deployment.nodes[n.index].init_script = Some(script);
+    }
+
+    /// Pin the version of a node's image, so reruns keep using the same image
+    /// when a newer one is published. A pinned image is downloaded as
+    /// `{image}_{version}.raw.xz` and installed as
+    /// `{dataset}/img/{image}_{version}`, so several versions of an image can
+    /// be installed side by side. Unpinned images are downloaded as
+    /// `{image}_0.raw.xz` and installed as `{dataset}/img/{image}`, as before.
+    pub fn set_image_version(&mut self, n: NodeRef, version: &str) {
+        self.deployment.nodes[n.index].image_version = Some(version.into());
+    }
+
+    /// Load the compiled P4 program `program` into the SoftNPU of node `n`
+    /// once it has booted, and then run the script `config` in the node, e.g.
+    /// to add initial rules with `scadm`. The program and the script are
+    /// copied into the node over the serial console the same way
+    /// [`Runner::push_file`] copies files, and the program is pushed to
+    /// propolis through the SoftNPU p9 device with `p9kp`. This needs a
+    /// propolis-server built with the `softnpu` feature, such as
+    /// [`PROPOLIS_REV`] with [`PROPOLIS_FEATURES`] that `get-propolis.sh`
+    /// installs, and a guest image that has `p9kp` and `base64`. The node must
+    /// have at least one SoftNPU port, and no mounts, as `p9kp` only talks to
+    /// the guest's first p9 device. The program is only loaded by `launch`,
+    /// not by `hyperstart`.
+    pub fn set_softnpu_config(
+        &mut self,
+        n: NodeRef,
+        program: Utf8PathBuf,
+        config: Option<Utf8PathBuf>,
+    ) -> Result<(), Error> {
+        let canonical = |file: &Utf8Path| {
+            if !file.is_file() {
+                return Err(Error::NotFound(format!("softnpu file {file}")));
+            }
+            file.canonicalize_utf8().map_err(|error| {
+                Error::PathError(format!(
+                    "{}: canonicalization error: {}",
+                    file, error
+                ))
+            })
+        };
+        let program = canonical(program.as_path())?;
+        let config = config.as_deref().map(canonical).transpose()?;
+        self.deployment.nodes[n.index].softnpu =
+            Some(SoftNpuConfig { program, config });
+        Ok(())
+    }
+
+    /// Give node `n` a VNC server for its framebuffer on `port`, or on a free
+    /// port picked when the node is launched if `port` is `None`. The port a
+    /// node's VNC server listens on is recorded in `{node}.vnc` in the falcon
+    /// directory, see [`Runner::node_vnc_port`].
+    pub fn enable_vnc(&mut self, n: NodeRef, port: Option<u16>) {
+        let node = &mut self.deployment.nodes[n.index];
+        node.vnc = true;
+        node.vnc_port = port;
+    }
+
+    /// Set the metadata propolis is given for the instance of a node, e.g. the
+    /// project, silo and sled ids of a system the node stands in for. By
+    /// default the ids are nil and the sled model and serial are `falcon`. The
+    /// metadata is kept in the topology, so `hyperstart` uses it too.
+    pub fn set_instance_metadata(
+        &mut self,
+        n: NodeRef,
+        metadata: InstanceMetadata,
+    ) {
+        self.deployment.nodes[n.index].instance_metadata = Some(metadata);
+    }
+
+    /// Set the operating system of a node's image, illumos by default. This
+    /// selects how the node is logged into and how its hostname and static
+    /// addresses are set up on launch. Directories mounted into Linux nodes
+    /// should use [`Runner::mount_linux`].
+    pub fn set_guest_os(&mut self, n: NodeRef, os: serial::GuestOs) {
+        self.deployment.nodes[n.index].guest_os = os;
+    }
+
+    /// Set the boot priority of a node, which is 0 by default. `launch` and
+    /// `hyperstart --all` start nodes in order of descending priority, waiting
+    /// for every node of a priority to be up, and for `launch` set up, before
+    /// starting the nodes of the next. Nodes with the same priority still start
+    /// concurrently. This is useful when some nodes depend on others, e.g. a
+    /// router that should be running before its clients boot.
+    pub fn set_boot_priority(&mut self, n: NodeRef, priority: u32) {
+        self.deployment.nodes[n.index].boot_priority = priority;
+    }
+
+    /// Set how many cores a node has. For a node that has already been
+    /// launched, this takes effect the next time it is started with the
+    /// `hyperstart` command, which keeps the node's disk as is.
+    pub fn set_cores(&mut self, n: NodeRef, cores: u8) -> Result<(), Error> {
+        let node = &mut self.deployment.nodes[n.index];
+        if cores == 0 {
+            return Err(Error::Validation(vec![format!(
+                "node {} has no cores",
+                node.name
+            )]));
+        }
+        node.cores = cores;
+        Ok(())
+    }
+
+    /// Set how much memory a node has in MB. This takes effect the same way
+    /// as [`Runner::set_cores`].
+    pub fn set_memory(&mut self, n: NodeRef, memory: u64) -> Result<(), Error> {
+        let node = &mut self.deployment.nodes[n.index];
+        if memory == 0 {
+            return Err(Error::Validation(vec![format!(
+                "node {} has no memory",
+                node.name
+            )]));
+        }
+        node.memory = memory;
+        Ok(())
+    }
+
     pub fn reserve(&mut self, n: NodeRef, gb: usize) {
         self.deployment.nodes[n.index].reserved = gb;
     }
@@ -479,6 +2197,84 @@ impl Runner {
         self.deployment.nodes[n.index].primary_disk_backing = backing
     }
 
+    /// Set whether ANSI escape sequences and other terminal control characters
+    /// echoed by the guest console are stripped from the output returned by
+    /// `exec`. This is on by default, as the raw output of a command is rarely
+    /// what automation wants to parse. The interactive serial console is never
+    /// filtered.
+    pub fn set_strip_ansi(&mut self, strip: bool) {
+        self.strip_ansi = strip;
+    }
+
+    /// Set how the end of a command's output is detected by `exec`. The
+    /// default relies on `PROMPT_COMMAND`, which restricted or non-bash shells
+    /// may not support. See [`serial::ExecStrategy`] for the alternatives.
+    pub fn set_exec_strategy(&mut self, strategy: serial::ExecStrategy) {
+        self.exec_strategy = strategy;
+    }
+
+    /// Set the format `preflight` writes the topology in, RON by default.
+    /// `hyperstart` and `snapshot` read either format.
+    pub fn set_topology_format(&mut self, format: TopologyFormat) {
+        self.topology_format = format;
+    }
+
+    /// Set how long to wait for each prompt while logging into a node's
+    /// serial console, 10 seconds by default.
+    pub fn set_login_timeout(&mut self, timeout: Duration) {
+        self.login_timeout = timeout;
+    }
+
+    /// Set how many more times logging into a node is tried when a prompt
+    /// does not appear in time, none by default. Before each retry ENTER is
+    /// sent to coax a new login prompt, which helps with slow booting guests.
+    pub fn set_login_retries(&mut self, retries: u32) {
+        self.login_retries = retries;
+    }
+
+    /// Enable or disable guest panic detection using the illumos panic
+    /// markers in [`serial::DEFAULT_PANIC_PATTERNS`]. When a panic is seen on
+    /// a node's serial console, `exec` and launch fail with
+    /// [`Error::GuestPanic`] rather than waiting until they time out.
+    pub fn enable_panic_detection(&mut self, on: bool) {
+        self.panic_patterns = if on {
+            serial::DEFAULT_PANIC_PATTERNS
+                .iter()
+                .map(|x| x.to_string())
+                .collect()
+        } else {
+            Vec::new()
+        };
+    }
+
+    /// Enable guest panic detection with custom regular expressions, e.g.
+    /// `Kernel panic - not syncing` for Linux guests. An empty set disables
+    /// panic detection.
+    pub fn set_panic_patterns(
+        &mut self,
+        patterns: &[&str],
+    ) -> Result<(), Error> {
+        serial::panic_regexes(patterns)?;
+        self.panic_patterns = patterns.iter().map(|x| x.to_string()).collect();
+        Ok(())
+    }
+
+    /// Set how long to wait after first logging into the node before running
+    /// any setup commands. This is zero by default. Some images need a moment
+    /// after presenting a login prompt before background services or
+    /// first-boot provisioning are done. Polling for the actual condition the
+    /// node needs is preferred where possible, but a settle time is a
+    /// pragmatic fallback for flaky first commands.
+    pub fn set_settle_time(&mut self, n: NodeRef, settle: Duration) {
+        self.deployment.nodes[n.index].settle_time = settle
+    }
+
+    /// Set host-side commands to run before and after the node is launched.
+    /// See [`HostHooks`] for the execution context of the commands.
+    pub fn set_node_hooks(&mut self, n: NodeRef, hooks: HostHooks) {
+        self.deployment.nodes[n.index].host_hooks = hooks
+    }
+
     /// Create an external link attached to `host_ifx`.
     pub fn ext_link(&mut self, host_ifx: impl AsRef<str>, n: NodeRef) {
         let endpoint = Endpoint {
@@ -502,6 +2298,40 @@ impl Runner {
         n: NodeRef,
         mechanism: GuestMountMechanism,
     ) -> Result<(), Error> {
+        let opts = MountOptions {
+            mechanism,
+            ..Default::default()
+        };
+        self.mount_with_opts(src, dst, n, opts)
+    }
+
+    /// Provide the host folder `src` as a p9fs mount to the guest at `dst`,
+    /// using the given mount options.
+    pub fn mount_with_opts(
+        &mut self,
+        src: impl AsRef<Utf8Path>,
+        dst: impl AsRef<Utf8Path>,
+        n: NodeRef,
+        opts: MountOptions,
+    ) -> Result<(), Error> {
+        if opts.chunk_size == 0 {
+            return Err(Error::Validation(vec![format!(
+                "{}: p9fs chunk size must be non-zero",
+                self.deployment.nodes[n.index].name
+            )]));
+        }
+        // p9kp pulls from the guest's first p9fs device
+        let node = &self.deployment.nodes[n.index];
+        if opts.mechanism == GuestMountMechanism::P9kp
+            && !node.mounts.is_empty()
+        {
+            return Err(Error::Validation(vec![format!(
+                "{}: only the first mount of a node can use p9kp, mount {} \
+                 with mount_linux instead",
+                node.name,
+                dst.as_ref()
+            )]));
+        }
         let src = src.as_ref();
         let src = src.canonicalize_utf8().map_err(|error| {
             Error::PathError(format!(
@@ -513,12 +2343,87 @@ impl Runner {
         self.deployment.nodes[n.index].mounts.push(Mount {
             source: src,
             destination: dst.as_ref().to_owned(),
-            mechanism,
+            mechanism: opts.mechanism,
+            chunk_size: opts.chunk_size,
         });
 
         Ok(())
     }
 
+    /// Attach existing host storage at `host_path` to a node as an additional
+    /// virtio block device. This can be a disk image file, or a raw block
+    /// device such as a zvol or lofi device. Unlike the primary disk, this
+    /// storage is used in place and is left untouched when the deployment is
+    /// destroyed.
+    pub fn attach_host_disk(
+        &mut self,
+        n: NodeRef,
+        host_path: impl AsRef<Utf8Path>,
+        read_only: bool,
+    ) -> Result<(), Error> {
+        let path = host_path.as_ref();
+        let path = path.canonicalize_utf8().map_err(|error| {
+            Error::PathError(format!(
+                "{}: canonicalization error: {}",
+                path, error
+            ))
+        })?;
+
+        self.deployment.nodes[n.index]
+            .host_disks
+            .push(HostDisk { path, read_only });
+
+        Ok(())
+    }
+
+    /// Attach the ISO image at `path` to a node, e.g. to boot an installer or
+    /// live image. The propolis server configuration has no cdrom device, so
+    /// the ISO is attached as a read-only `pci-virtio-block` device backed by
+    /// the file, after the node's host disks. OVMF finds the El Torito boot
+    /// image on it like it would on a cdrom. There is no boot order setting
+    /// either: OVMF tries boot devices in PCI slot order, and the main disk
+    /// always has the lowest slot, so the node boots from the ISO only when
+    /// nothing on its main disk is bootable. Like host disks, the ISO is used
+    /// in place and is left untouched when the deployment is destroyed. Nodes
+    /// booting an ISO usually need `do_setup` turned off, as there is no
+    /// falcon compatible login to set them up.
+    pub fn attach_iso(
+        &mut self,
+        n: NodeRef,
+        path: &Utf8Path,
+    ) -> Result<(), Error> {
+        let path = path.canonicalize_utf8().map_err(|error| {
+            Error::PathError(format!(
+                "{}: canonicalization error: {}",
+                path, error
+            ))
+        })?;
+
+        self.deployment.nodes[n.index].isos.push(path);
+
+        Ok(())
+    }
+
+    /// Add an additional empty disk of `size_gb` to a node. The disk is
+    /// attached as a virtio block device after the node's mounts, and its
+    /// backing is created when the deployment is launched. The backing of the
+    /// `i`th extra disk is named `{node}_disk{i}`, so no other node may have
+    /// that name.
+    pub fn add_disk(
+        &mut self,
+        n: NodeRef,
+        size_gb: usize,
+        backing: PrimaryDiskBacking,
+    ) {
+        self.deployment.nodes[n.index]
+            .extra_disks
+            .push(ExtraDisk { size_gb, backing });
+    }
+
+    /// Provide the host folder `src` to the guest at `dst`, copying it in with
+    /// `p9kp pull` once the node has booted. `p9kp` only reads from the
+    /// guest's first p9fs device, so this must be the node's first mount;
+    /// further directories can be mounted with [`Runner::mount_linux`].
     pub fn mount(
         &mut self,
         src: impl AsRef<Utf8Path>,
@@ -537,14 +2442,83 @@ impl Runner {
         self.do_mount(src, dst, n, GuestMountMechanism::Mount)
     }
 
-    /// Launch the deployment. This will clone the necessary image zvols, create
-    /// the propolis VM instances, create the point to point network interfaces,
-    /// set up the serial console for each VM and, run any user defined exec
-    /// statements.
+    /// The propolis configuration node `n` is launched with, built by the same
+    /// logic as in `preflight` but without creating disks or writing anything
+    /// to the falcon directory. This is the config of the node in
+    /// [`Runner::plan`], e.g. to check the PCI slots of its devices or to
+    /// launch it some other way.
+    pub fn node_spec(
+        &self,
+        n: NodeRef,
+    ) -> Result<propolis_server_config::Config, Error> {
+        self.deployment.validate()?;
+        self.deployment.nodes[n.index].planned_config(self)
+    }
+
+    /// Compute what launching the deployment would do, without changing
+    /// anything on the host.
+    pub fn plan(&self) -> Result<Plan, Error> {
+        self.deployment.validate()?;
+        let d = &self.deployment;
+
+        let mut images = Vec::new();
+        let mut disks = Vec::new();
+        let mut configs = BTreeMap::new();
+        for n in &d.nodes {
+            let image = format!("{}/img/{}@base", n.dataset, n.image_name());
+            if !images.contains(&image) {
+                images.push(image);
+            }
+            disks.push(n.backing_path(self));
+            disks.extend(
+                (0..n.extra_disks.len()).map(|i| n.extra_backing_path(self, i)),
+            );
+            configs.insert(n.name.clone(), n.planned_config(self)?);
+        }
+
+        let mut links = Vec::new();
+        for l in &d.links {
+            for e in &l.endpoints {
+                links.push(d.simnet_link_name(e));
+                links.push(d.vnic_link_name(e));
+            }
+        }
+        for x in &d.ext_links {
+            links.push(d.vnic_link_name(&x.endpoint));
+        }
+        for (i, b) in d.bridges.iter().enumerate() {
+            links.push(d.etherstub_name(i));
+            for e in &b.endpoints {
+                links.push(d.vnic_link_name(e));
+            }
+        }
+
+        Ok(Plan {
+            images,
+            disks,
+            links,
+            configs,
+        })
+    }
+
+    /// Launch the deployment. This will clone the necessary image zvols, create
+    /// the propolis VM instances, create the point to point network interfaces,
+    /// set up the serial console for each VM and, run any user defined exec
+    /// statements.
+    ///
+    /// The falcon directory is locked while launching, see
+    /// [`Runner::lock_falcon_dir`].
     pub async fn launch(&self) -> Result<(), Error> {
+        self.launch_detailed().await.map(drop)
+    }
+
+    /// Launch the deployment like [`Runner::launch`], returning the propolis
+    /// port, instance uuid, pid and VNC port of every node.
+    pub async fn launch_detailed(&self) -> Result<LaunchSummary, Error> {
+        let _lock = self.lock_falcon_dir()?;
         self.preflight().await?;
         match self.do_launch().await {
-            Ok(()) => Ok(()),
+            Ok(summary) => Ok(summary),
             Err(e) => {
                 error!(self.log, "launch failed: {}", e);
                 Err(e)
@@ -553,24 +2527,28 @@ impl Runner {
     }
 
     async fn preflight(&self) -> Result<(), Error> {
-        // Verify all required executables are discoverable.
-        let out = Command::new(&self.propolis_binary).args(["-V"]).output();
-        if out.is_err() {
-            return Err(Error::Exec(format!(
-                "failed to find {} on PATH",
-                &self.propolis_binary
-            )));
-        }
+        self.deployment.validate()?;
+        self.zfs_opts.validate()?;
+
+        // Verify all required executables are discoverable and usable.
+        self.check_propolis_version()?;
+        self.check_memory()?;
 
         // ensure falcon working dir
         fs::create_dir_all(&self.falcon_dir)?;
 
-        // write falcon config
-        let pretty = PrettyConfig::new().separate_tuple_members(true);
-        let out = format!("{}\n", to_string_pretty(&self.deployment, pretty)?);
-        let mut topo_path = self.falcon_dir.clone();
-        topo_path.push("topology.ron");
-        fs::write(&topo_path, out)?;
+        // write falcon config, removing a topology of the other format so
+        // the one read back is always current
+        for format in [TopologyFormat::Ron, TopologyFormat::Toml] {
+            let path = self.falcon_dir.join(format.file_name());
+            if format == self.topology_format {
+                self.deployment.write_file(&path)?;
+            } else if path.exists() {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        self.ensure_base_images().await?;
 
         for n in self.deployment.nodes.iter() {
             n.preflight(self).await?;
@@ -579,6 +2557,138 @@ impl Runner {
         Ok(())
     }
 
+    /// Check the memory of the nodes that are not running yet, plus
+    /// [`NODE_MEMORY_OVERHEAD_MB`] each, fits in the free memory of the host.
+    fn check_memory(&self) -> Result<(), Error> {
+        let required: u64 = self
+            .deployment
+            .nodes
+            .iter()
+            .filter(|n| !n.is_running(self))
+            .map(|n| n.memory + NODE_MEMORY_OVERHEAD_MB)
+            .sum();
+        let available = match host_free_memory_mb() {
+            Some(mb) => mb,
+            None => {
+                warn!(self.log, "could not determine free host memory");
+                return Ok(());
+            }
+        };
+        match check_memory(required, available) {
+            Err(e) if self.overcommit => {
+                warn!(self.log, "overcommitting memory: {}", e);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// Check each propolis-server binary nodes are launched with runs, and
+    /// that the revision it reports is [`PROPOLIS_REV`]. A binary that does
+    /// not report a revision is accepted with a warning.
+    fn check_propolis_version(&self) -> Result<(), Error> {
+        let mut bins: BTreeSet<&str> = self
+            .deployment
+            .nodes
+            .iter()
+            .map(|n| n.propolis_binary(self))
+            .collect();
+        if bins.is_empty() {
+            bins.insert(&self.propolis_binary);
+        }
+        for bin in bins {
+            self.check_propolis_binary(bin)?;
+        }
+        Ok(())
+    }
+
+    fn check_propolis_binary(&self, bin: &str) -> Result<(), Error> {
+        let out = match Command::new(bin).args(["-V"]).output() {
+            Ok(out) => out,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Error::Exec(format!(
+                    "failed to find {bin} on PATH"
+                )));
+            }
+            // e.g. a binary built for another architecture or os
+            Err(e) => {
+                return Err(Error::Exec(format!("failed to run {bin}: {e}")))
+            }
+        };
+        if !out.status.success() {
+            return Err(Error::Exec(format!(
+                "{bin} -V failed ({}): {}",
+                out.status,
+                String::from_utf8_lossy(&out.stderr).trim()
+            )));
+        }
+        let version = String::from_utf8_lossy(&out.stdout);
+        let version = version.trim();
+        match propolis_version_rev(version) {
+            Some(rev) if PROPOLIS_REV.starts_with(rev) => Ok(()),
+            Some(rev) if self.skip_version_check => {
+                warn!(
+                    self.log,
+                    "{bin} is revision {rev}, not {PROPOLIS_REV}, \
+                     skipping version check"
+                );
+                Ok(())
+            }
+            Some(rev) => Err(Error::Exec(format!(
+                "{bin} is revision {rev}, falcon requires {PROPOLIS_REV}, \
+                 see get-propolis.sh, or skip this check with \
+                 --skip-version-check"
+            ))),
+            None => {
+                warn!(
+                    self.log,
+                    "could not determine the revision of {bin} from `{version}`, \
+                     falcon requires {PROPOLIS_REV}"
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Make sure the base images of all nodes are installed. Each image is
+    /// only installed once no matter how many nodes use it, and up to
+    /// [`MAX_CONCURRENT_IMAGE_INSTALLS`] distinct images are installed at once.
+    async fn ensure_base_images(&self) -> Result<(), Error> {
+        // one node per distinct image and dataset, grouped by image so an
+        // image is only ever downloaded by a single task
+        let mut images: BTreeMap<String, Vec<&Node>> = BTreeMap::new();
+        for n in &self.deployment.nodes {
+            let nodes = images.entry(n.image_name()).or_default();
+            if !nodes.iter().any(|x| x.dataset == n.dataset) {
+                nodes.push(n);
+            }
+        }
+
+        let mp = self.multi_progress();
+        let results: Vec<Result<(), Error>> =
+            futures::stream::iter(images.into_values())
+                .map(|nodes| {
+                    let mp = &mp;
+                    async move {
+                        for n in nodes {
+                            n.try_ensure_base_image(
+                                &self.log,
+                                &self.asset_base_url,
+                                self.verify_images,
+                                &self.zfs_opts,
+                                mp,
+                            )
+                            .await?;
+                        }
+                        Ok(())
+                    }
+                })
+                .buffer_unordered(MAX_CONCURRENT_IMAGE_INSTALLS)
+                .collect()
+                .await;
+        results.into_iter().collect()
+    }
+
     async fn net_launch(&self) -> Result<(), Error> {
         info!(self.log, "creating links");
         for l in self.deployment.links.iter() {
@@ -590,23 +2700,28 @@ impl Runner {
             l.create(self)?;
         }
 
+        info!(self.log, "creating bridges");
+        for (i, b) in self.deployment.bridges.iter().enumerate() {
+            b.create(self, i)?;
+        }
+
         Ok(())
     }
 
-    async fn do_launch(&self) -> Result<(), Error> {
+    async fn do_launch(&self) -> Result<LaunchSummary, Error> {
         self.net_launch().await?;
 
         info!(self.log, "creating nodes");
 
-        let mut fs = Vec::new();
-        for n in self.deployment.nodes.iter() {
-            fs.push(n.launch(self));
-        }
-        for x in join_all(fs).await {
-            x?;
+        let mut nodes = BTreeMap::new();
+        for group in self.deployment.boot_order() {
+            let fs = group.iter().map(|n| n.launch(self));
+            for (n, x) in group.iter().zip(join_all(fs).await) {
+                nodes.insert(n.name.clone(), x?);
+            }
         }
 
-        Ok(())
+        Ok(LaunchSummary { nodes })
     }
 
     pub fn net_destroy(&self) -> Result<(), Error> {
@@ -619,12 +2734,113 @@ impl Runner {
         for l in self.deployment.ext_links.iter() {
             l.destroy(self)?;
         }
+
+        info!(self.log, "destroying bridges");
+        for (i, b) in self.deployment.bridges.iter().enumerate() {
+            b.destroy(self, i)?;
+        }
         Ok(())
     }
 
+    /// Create the simnet and vnic links for a single link of the deployment,
+    /// leaving every other link untouched. Links left dangling by an earlier
+    /// destroy are removed first, so this may be called on a link that already
+    /// exists to recreate it.
+    pub fn link_create(&self, l: LinkRef) -> Result<(), Error> {
+        self.deployment.links[l._index].create(self)
+    }
+
+    /// Destroy the simnet and vnic links for a single link of the deployment,
+    /// leaving every other link untouched.
+    pub fn link_destroy(&self, l: LinkRef) -> Result<(), Error> {
+        self.deployment.links[l._index].destroy(self)
+    }
+
+    /// Check that every node of the deployment is running and every link
+    /// exists, repairing any drift found. Missing links are recreated and
+    /// nodes whose propolis instance is no longer running are relaunched. A
+    /// relaunched node boots from the disk it already has, so first boot
+    /// provisioning is skipped: directories copied in with `p9kp` are not
+    /// pulled again, its SoftNPU program is not loaded, and its init script is
+    /// not run. Its `mount_linux` mounts, addresses and host entries are set
+    /// up again, as they do not outlive a reboot. Returns the number of
+    /// corrective actions taken.
+    ///
+    /// The falcon directory is locked for each pass, see
+    /// [`Runner::lock_falcon_dir`], so a pass fails rather than relaunch
+    /// nodes that another falcon process is stopping or destroying.
+    pub async fn reconcile(&self) -> Result<usize, Error> {
+        let _lock = self.lock_falcon_dir()?;
+        let mut actions = 0;
+        let d = &self.deployment;
+
+        for l in d.links.iter() {
+            let missing = l.endpoints.iter().any(|e| {
+                let vnic = libnet::LinkHandle::Name(d.vnic_link_name(e));
+                let sim = libnet::LinkHandle::Name(d.simnet_link_name(e));
+                libnet::get_link(&vnic).is_err()
+                    || libnet::get_link(&sim).is_err()
+            });
+            if missing {
+                warn!(
+                    self.log,
+                    "reconcile: recreating link {} <-> {}",
+                    d.nodes[l.endpoints[0].node.index].name,
+                    d.nodes[l.endpoints[1].node.index].name,
+                );
+                l.create(self)?;
+                actions += 1;
+            }
+        }
+
+        for l in d.ext_links.iter() {
+            let vnic = libnet::LinkHandle::Name(d.vnic_link_name(&l.endpoint));
+            if libnet::get_link(&vnic).is_err() {
+                warn!(
+                    self.log,
+                    "reconcile: recreating external link {}",
+                    d.vnic_link_name(&l.endpoint),
+                );
+                l.create(self)?;
+                actions += 1;
+            }
+        }
+
+        for n in d.nodes.iter() {
+            if n.is_running(self) {
+                continue;
+            }
+            warn!(self.log, "reconcile: relaunching node {}", n.name);
+            n.destroy(self)?;
+            n.relaunch(self).await?;
+            actions += 1;
+        }
+
+        Ok(actions)
+    }
+
+    /// Periodically reconcile the deployment, see [`Runner::reconcile`]. Every
+    /// corrective action is logged, and failures to reconcile are logged and
+    /// retried on the next interval. This never returns; cancel it by dropping
+    /// the future, e.g. with `tokio::select!` or `tokio::time::timeout`.
+    pub async fn reconcile_loop(&self, interval: Duration) {
+        loop {
+            match self.reconcile().await {
+                Ok(0) => debug!(self.log, "reconcile: no drift"),
+                Ok(n) => info!(self.log, "reconcile: took {} actions", n),
+                Err(e) => error!(self.log, "reconcile failed: {}", e),
+            }
+            sleep(interval).await;
+        }
+    }
+
     /// Tear down all the nodes, followed by the links and the ZFS pool
+    ///
+    /// The falcon directory is locked while destroying, see
+    /// [`Runner::lock_falcon_dir`].
     // TODO in parallel
     pub fn destroy(&self) -> Result<(), Error> {
+        let _lock = self.lock_falcon_dir()?;
         info!(self.log, "destroying nodes");
         for n in self.deployment.nodes.iter() {
             n.destroy(self)?;
@@ -637,12 +2853,12 @@ impl Runner {
 
         // destroy any zvol backed images
         let img_dir = format!("{}/topo/{}", self.dataset, self.deployment.name);
-        Command::new(ZFS_BIN)
-            .args(["destroy", "-r", img_dir.as_ref()])
-            .output()?;
+        if zfs::exists(&img_dir)? {
+            zfs::destroy(&img_dir)?;
+        }
 
         // destroy any file backed images
-        let img_dir = format!("/var/falcon/dsk/{}", self.deployment.name);
+        let img_dir = format!("{}/{}", self.file_dir, self.deployment.name);
         Command::new(RM_BIN)
             .args(["-rf", img_dir.as_ref()])
             .output()?;
@@ -654,37 +2870,687 @@ impl Runner {
         Ok(())
     }
 
+    /// Replace the deployment with the topology `preflight` recorded in the
+    /// falcon directory, if there is one, along with the dataset its nodes
+    /// were created in. This makes `destroy` and `stop` clean up what was
+    /// actually launched, even if the runner was built differently since.
+    /// Returns whether a topology was found.
+    pub fn load_topology(&mut self) -> Result<bool, Error> {
+        let path = topology_path(&self.falcon_dir);
+        if !path.exists() {
+            return Ok(false);
+        }
+        let d = Deployment::from_file(&path)?;
+        if d.name != self.deployment.name {
+            warn!(self.log, "using deployment {} recorded in {}", d.name, path);
+        }
+        if let Some(n) = d.nodes.first() {
+            self.dataset = n.dataset.clone();
+        }
+        self.deployment = d;
+        Ok(true)
+    }
+
+    /// Stop the deployment without destroying it. The propolis instance of
+    /// every node is killed and the links are destroyed, but node disks, the
+    /// topology and the state files in the falcon directory are kept, so nodes
+    /// can be started again quickly with `netcreate` followed by
+    /// `hyperstart --all`. Unlike `destroy`, this is never done when a runner
+    /// is dropped.
+    ///
+    /// The falcon directory is locked while stopping, see
+    /// [`Runner::lock_falcon_dir`].
+    pub fn stop(&self) -> Result<(), Error> {
+        let _lock = self.lock_falcon_dir()?;
+        info!(self.log, "stopping nodes");
+        for n in self.deployment.nodes.iter() {
+            hyperstop(&self.log, &n.name, &self.falcon_dir)?;
+        }
+
+        self.net_destroy()
+    }
+
+    /// Take the advisory lock of the falcon directory. Launching, stopping and
+    /// destroying a deployment, and each reconcile pass, hold this lock for
+    /// their whole duration, so they fail rather than interleave with another
+    /// process doing any of them in the same falcon directory. Commands that
+    /// only read the state files of running nodes, like `exec` and `serial`,
+    /// do not take the lock. Those files are written atomically, so readers
+    /// never see them half written.
+    fn lock_falcon_dir(&self) -> Result<util::DirLock, Error> {
+        util::lock_dir(&self.falcon_dir).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::WouldBlock {
+                Error::Exec(format!(
+                    "{} is in use by another falcon process",
+                    self.falcon_dir
+                ))
+            } else {
+                Error::IO(e)
+            }
+        })
+    }
+
     /// Run a command synchronously in the vm.
     pub async fn exec(&self, n: NodeRef, cmd: &str) -> Result<String, Error> {
         let name = self.deployment.nodes[n.index].name.clone();
-        self.do_exec(&name, cmd).await
+        self.do_exec(&name, cmd, None, &mut |_| {}).await
     }
 
-    async fn do_exec(&self, name: &str, cmd: &str) -> Result<String, Error> {
-        let mut path = self.falcon_dir.clone();
-        path.push(format!("{name}.uuid"));
-        let id = match fs::read_to_string(&path) {
-            Ok(u) => u,
-            Err(e) => {
-                return Err(Error::NotFound(format!(
-                    "propolis uuid for {}: {}",
-                    name, e
-                )));
+    /// Run a command synchronously in the vm, calling `on_line` with each line
+    /// of its output as it arrives. Returns the whole output like
+    /// [`Runner::exec`]. This is for long running commands, such as builds,
+    /// whose progress should be visible before they complete.
+    pub async fn exec_streaming<F>(
+        &self,
+        n: NodeRef,
+        cmd: &str,
+        mut on_line: F,
+    ) -> Result<String, Error>
+    where
+        F: FnMut(&str) + Send,
+    {
+        let name = self.deployment.nodes[n.index].name.clone();
+        self.do_exec(&name, cmd, None, &mut on_line).await
+    }
+
+    /// Run a command synchronously in the vm, returning its output along with
+    /// its exit status.
+    pub async fn exec_status(
+        &self,
+        n: NodeRef,
+        cmd: &str,
+    ) -> Result<(String, i32), Error> {
+        let name = &self.deployment.nodes[n.index].name;
+        let out = self
+            .do_exec(name, &serial::with_exit_status(cmd), None, &mut |_| {})
+            .await?;
+        serial::split_exit_status(name, &out)
+    }
+
+    /// Run a multi-line shell script synchronously in the vm, returning its
+    /// output like [`Runner::exec`]. Commands are typed into the serial console
+    /// as a single line, so a script with embedded newlines cannot be passed
+    /// to `exec`. Instead the script is copied to a temporary file in the
+    /// guest the same way [`Runner::push_file`] copies files, run with `sh`,
+    /// and removed. The guest must have `base64`.
+    pub async fn exec_script(
+        &self,
+        n: NodeRef,
+        script: &str,
+    ) -> Result<String, Error> {
+        let name = &self.deployment.nodes[n.index].name;
+        let path =
+            format!("/tmp/falcon-script-{}.sh", uuid::Uuid::new_v4().simple());
+
+        let (mut sc, mut ws) = self.serial_session(name).await?;
+        write_guest_file(&mut sc, &mut ws, &path, script.as_bytes()).await?;
+        sc.logout(&mut ws).await?;
+
+        // keep the script's exit status as the status of the whole command;
+        // a plain `exit` would end the console's login shell
+        let cmd = format!("sh {path}; rc=$?; rm -f {path}; (exit $rc)");
+        self.do_exec(name, &cmd, None, &mut |_| {}).await
+    }
+
+    /// Measure the TCP throughput from node `a` to node `b`, which must be
+    /// reachable from `a` at `addr`, e.g. to check a bandwidth limit set on
+    /// the link between them. An `iperf3` server that exits after one test is
+    /// started on `b`, and an `iperf3` client on `a` sends to it for ten
+    /// seconds. Both guests must have `iperf3` installed, e.g. with `pkg
+    /// install iperf` on helios or `apt install iperf3` on debian.
+    pub async fn measure_throughput(
+        &self,
+        a: NodeRef,
+        b: NodeRef,
+        addr: Ipv4Addr,
+    ) -> Result<ThroughputReport, Error> {
+        let (out, rc) = self.exec_status(b, "iperf3 -s -1 -D").await?;
+        if rc != 0 {
+            return Err(Error::Exec(format!(
+                "{}: start iperf3 server: {out}",
+                self.deployment.nodes[b.index].name
+            )));
+        }
+        // give the daemonized server a moment to start listening
+        sleep(Duration::from_secs(1)).await;
+
+        let client = format!("iperf3 -J -c {addr} -t {THROUGHPUT_SECS}");
+        let timeout = Duration::from_secs(THROUGHPUT_SECS + 30);
+        let out = self.exec_timeout(a, &client, timeout).await?;
+        parse_iperf3(&self.deployment.nodes[a.index].name, &out)
+    }
+
+    /// Run a command in the vm repeatedly until `predicate` accepts its output,
+    /// e.g. to wait for a service to come online, returning the accepted
+    /// output. The command is run up to `attempts` times, `interval` apart. An
+    /// [`Error::Exec`] with the last output is returned if it is never
+    /// accepted.
+    pub async fn wait_for<P>(
+        &self,
+        n: NodeRef,
+        cmd: &str,
+        predicate: P,
+        interval: Duration,
+        attempts: usize,
+    ) -> Result<String, Error>
+    where
+        P: Fn(&str) -> bool,
+    {
+        let name = &self.deployment.nodes[n.index].name;
+        let mut out = String::new();
+        for attempt in 0..attempts {
+            if attempt > 0 {
+                sleep(interval).await;
+            }
+            out = self.exec(n, cmd).await?;
+            if predicate(&out) {
+                return Ok(out);
+            }
+            debug!(self.log, "{name}: waiting on `{cmd}`: {out}");
+        }
+        Err(Error::Exec(format!(
+            "{name}: `{cmd}` not ready after {attempts} attempts: {out}"
+        )))
+    }
+
+    /// Run a command synchronously in the vm, failing if it has not completed
+    /// within `timeout`. A command that times out is interrupted.
+    pub async fn exec_timeout(
+        &self,
+        n: NodeRef,
+        cmd: &str,
+        timeout: Duration,
+    ) -> Result<String, Error> {
+        let name = self.deployment.nodes[n.index].name.clone();
+        self.do_exec(&name, cmd, Some(timeout), &mut |_| {}).await
+    }
+
+    /// Run a command on every node concurrently, returning the output of each
+    /// node keyed by node name. If the command fails on any node, an
+    /// [`Error::ExecAll`] is returned that carries both the errors of the
+    /// failed nodes and the output of the nodes that succeeded.
+    pub async fn exec_all(
+        &self,
+        cmd: &str,
+    ) -> Result<BTreeMap<String, String>, Error> {
+        let nodes = self.all_nodes();
+        let results = join_all(nodes.iter().map(|n| self.exec(*n, cmd))).await;
+
+        let mut output = BTreeMap::new();
+        let mut failed = BTreeMap::new();
+        for (n, result) in nodes.iter().zip(results) {
+            let name = self.get_node(*n).name.clone();
+            match result {
+                Ok(out) => {
+                    output.insert(name, out);
+                }
+                Err(e) => {
+                    failed.insert(name, e);
+                }
+            }
+        }
+
+        if failed.is_empty() {
+            Ok(output)
+        } else {
+            Err(Error::ExecAll(error::ExecAllError { output, failed }))
+        }
+    }
+
+    /// Get the IP addresses configured on the interfaces of a running node.
+    /// Linux guests are queried with `ip`, all other guests with `ipadm`.
+    /// Addresses that are not yet assigned are not included.
+    pub async fn guest_addrs(&self, n: NodeRef) -> Result<Vec<IfAddr>, Error> {
+        let os = self.exec(n, "uname -s").await?;
+        if os.trim() == "Linux" {
+            let out = self.exec(n, "ip -o addr show").await?;
+            Ok(parse_linux_addrs(&out))
+        } else {
+            let out = self.exec(n, "ipadm show-addr -po addrobj,addr").await?;
+            Ok(parse_illumos_addrs(&out))
+        }
+    }
+
+    /// Verify that a node's disk was cloned from the image the topology says it
+    /// uses, by comparing the origin of the node's zvol clone against
+    /// `{dataset}/img/{image}@base`. This catches stale clones or manual zfs
+    /// changes that result in a node booting an unexpected image. Only nodes
+    /// with a zvol primary disk backing can be verified.
+    pub fn verify_boot_image(&self, n: NodeRef) -> Result<(), Error> {
+        let node = &self.deployment.nodes[n.index];
+        if !matches!(node.primary_disk_backing, PrimaryDiskBacking::Zvol) {
+            return Err(Error::NotImplemented(format!(
+                "{}: boot image verification of file backed disks",
+                node.name
+            )));
+        }
+
+        let clone = format!(
+            "{}/topo/{}/{}",
+            node.dataset, self.deployment.name, node.name
+        );
+        let expected =
+            format!("{}/img/{}@base", node.dataset, node.image_name());
+
+        let origin = zfs::get_prop(&clone, "origin")?;
+
+        if origin != expected {
+            return Err(Error::Zfs(format!(
+                "{}: disk {} has origin {}, expected {}",
+                node.name, clone, origin, expected
+            )));
+        }
+        Ok(())
+    }
+
+    /// Find propolis-server processes that were launched by falcon, but are no
+    /// longer referenced by the falcon directory they were launched from. A
+    /// process is considered to be launched by falcon if it was run with a
+    /// `{falcon_dir}/{node}.toml` config. It is considered orphaned if that
+    /// directory no longer has a `{node}.pid` file containing its pid. Other
+    /// propolis-server processes are never reported.
+    pub fn find_orphaned_propolis(&self) -> Result<Vec<OrphanInfo>, Error> {
+        let mut result = Vec::new();
+        for (pid, config) in self.falcon_propolis_processes()? {
+            let (dir, node) = match (config.parent(), config.file_stem()) {
+                (Some(dir), Some(node)) => (dir, node),
+                _ => continue,
+            };
+            let owned = fs::read_to_string(dir.join(format!("{node}.pid")))
+                .ok()
+                .and_then(|x| x.trim().parse::<i32>().ok())
+                == Some(pid);
+            if owned {
+                continue;
+            }
+            let uuid = fs::read_to_string(dir.join(format!("{node}.uuid")))
+                .ok()
+                .and_then(|x| x.trim().parse().ok());
+            result.push(OrphanInfo { pid, config, uuid });
+        }
+        Ok(result)
+    }
+
+    /// The pid and absolute config path of each propolis-server process that
+    /// looks like it was launched by falcon.
+    fn falcon_propolis_processes(
+        &self,
+    ) -> Result<Vec<(i32, Utf8PathBuf)>, Error> {
+        let out = Command::new(PS_BIN).args(["-eo", "pid,args"]).output()?;
+        if !out.status.success() {
+            return Err(Error::Exec(format!(
+                "{PS_BIN} failed: {}",
+                String::from_utf8_lossy(&out.stderr)
+            )));
+        }
+        let ps = String::from_utf8_lossy(&out.stdout);
+
+        let mut result = Vec::new();
+        for (pid, config) in parse_propolis_processes(&ps) {
+            let config = if config.is_relative() {
+                match process_cwd(pid) {
+                    Some(cwd) => cwd.join(config),
+                    None => {
+                        warn!(self.log, "could not get cwd of pid {pid}");
+                        continue;
+                    }
+                }
+            } else {
+                config
+            };
+            result.push((pid, config));
+        }
+        Ok(result)
+    }
+
+    /// Find links and node disk datasets that follow falcon's naming scheme
+    /// but belong to no known deployment, e.g. after a crash. Known
+    /// deployments are this runner's deployment and those whose topology is
+    /// in this runner's falcon directory, any of `falcon_dirs`, or the falcon
+    /// directory of a running propolis-server process launched by falcon.
+    /// Anything that might belong to one of them is left out, so names that
+    /// extend a known deployment name are never reported.
+    pub fn find_garbage(
+        &self,
+        falcon_dirs: &[Utf8PathBuf],
+    ) -> Result<Garbage, Error> {
+        let mut dirs = vec![self.falcon_dir.clone()];
+        dirs.extend(falcon_dirs.iter().cloned());
+        for (_, config) in self.falcon_propolis_processes()? {
+            if let Some(dir) = config.parent() {
+                dirs.push(dir.to_path_buf());
+            }
+        }
+
+        let mut known = BTreeSet::new();
+        known.insert(self.deployment.name.clone());
+        for dir in dirs {
+            let path = topology_path(&dir);
+            if !path.exists() {
+                continue;
+            }
+            match Deployment::from_file(&path) {
+                Ok(d) => {
+                    known.insert(d.name);
+                }
+                Err(e) => {
+                    return Err(Error::Exec(format!(
+                        "cannot tell what {path} owns: {e}"
+                    )))
+                }
             }
+        }
+
+        let out = Command::new(DLADM_BIN)
+            .args(["show-link", "-p", "-o", "link"])
+            .output()?;
+        if !out.status.success() {
+            return Err(Error::Exec(format!(
+                "{DLADM_BIN} failed: {}",
+                String::from_utf8_lossy(&out.stderr)
+            )));
+        }
+        let links = String::from_utf8_lossy(&out.stdout);
+
+        let topo = format!("{}/topo", self.dataset);
+        let datasets = if zfs::exists(&topo)? {
+            zfs::run(&["list", "-H", "-o", "name", "-d", "1", topo.as_str()])?
+        } else {
+            String::new()
         };
-        path.pop();
 
-        path.push(format!("{name}.port"));
-        let port = match fs::read_to_string(&path) {
-            Ok(p) => p.as_str().parse::<u16>()?,
-            Err(e) => {
-                return Err(Error::NotFound(format!(
-                    "get propolis port for {}: {}",
-                    name, e
-                )));
+        Ok(find_garbage(links.lines(), datasets.lines(), &topo, &known))
+    }
+
+    /// Remove garbage found by [`Runner::find_garbage`]. Vnics are deleted
+    /// before the simnets and etherstubs they are on.
+    pub fn gc(&self, garbage: &Garbage) -> Result<(), Error> {
+        for link in garbage.vnics.iter().chain(garbage.simnets.iter()) {
+            info!(self.log, "deleting link {}", link);
+            let h = libnet::LinkHandle::Name(link.clone());
+            libnet_retry(self, &format!("deleting {link}"), || {
+                libnet::delete_link(&h, libnet::LinkFlags::Active)
+            })?;
+        }
+        for link in &garbage.etherstubs {
+            info!(self.log, "deleting etherstub {}", link);
+            dladm(&["delete-etherstub", link.as_str()])?;
+        }
+        for dataset in &garbage.datasets {
+            info!(self.log, "destroying dataset {}", dataset);
+            zfs::destroy(dataset)?;
+        }
+        Ok(())
+    }
+
+    /// Terminate an orphaned propolis-server process and destroy its bhyve
+    /// instance. The process is sent SIGTERM, and if it has not exited after
+    /// 5 seconds, SIGKILL.
+    pub async fn reap_orphan(&self, orphan: &OrphanInfo) -> Result<(), Error> {
+        info!(self.log, "terminating propolis pid {}", orphan.pid);
+        unsafe {
+            libc::kill(orphan.pid, libc::SIGTERM);
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while unsafe { libc::kill(orphan.pid, 0) } == 0 {
+            if Instant::now() >= deadline {
+                warn!(self.log, "killing propolis pid {}", orphan.pid);
+                unsafe {
+                    libc::kill(orphan.pid, libc::SIGKILL);
+                }
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+
+        if let Some(uuid) = orphan.uuid {
+            info!(self.log, "destroying bhyve vm {}", uuid);
+            let vm_arg = format!("--vm={}", uuid);
+            let out = Command::new("bhyvectl")
+                .args(["--destroy", vm_arg.as_ref()])
+                .output()?;
+            if !out.status.success() {
+                warn!(
+                    self.log,
+                    "delete bhyve vm {}: {}",
+                    uuid,
+                    String::from_utf8_lossy(&out.stderr)
+                );
             }
+        }
+
+        Ok(())
+    }
+
+    /// Copy the local file `local` into a running node at `remote` over the
+    /// serial console. The file is transferred base64 encoded in chunks, and
+    /// its sha256 digest is verified once the transfer is complete. This is
+    /// slow for large files, for which a mount should be preferred. The guest
+    /// must have `base64` and either `sha256sum` or `digest`.
+    pub async fn push_file(
+        &self,
+        n: NodeRef,
+        local: &Utf8Path,
+        remote: &Utf8Path,
+    ) -> Result<(), Error> {
+        let name = &self.deployment.nodes[n.index].name;
+        let data = fs::read(local)?;
+        let remote_q = util::shell_quote(remote.as_str());
+
+        let (mut sc, mut ws) = self.serial_session(name).await?;
+        write_guest_file(&mut sc, &mut ws, &remote_q, &data).await?;
+        let remote_digest =
+            sc.exec(&mut ws, guest_sha256_cmd(&remote_q)).await?;
+        sc.logout(&mut ws).await?;
+
+        check_digest(name, remote, &data, &remote_digest)
+    }
+
+    /// Copy the file `remote` from a running node to `local` over the serial
+    /// console. This is the inverse of [`Runner::push_file`], with the same
+    /// guest requirements.
+    pub async fn pull_file(
+        &self,
+        n: NodeRef,
+        remote: &Utf8Path,
+        local: &Utf8Path,
+    ) -> Result<(), Error> {
+        let name = &self.deployment.nodes[n.index].name;
+        let remote_q = util::shell_quote(remote.as_str());
+
+        let (mut sc, mut ws) = self.serial_session(name).await?;
+        let encoded = sc.exec(&mut ws, format!("base64 {remote_q}")).await?;
+        let remote_digest =
+            sc.exec(&mut ws, guest_sha256_cmd(&remote_q)).await?;
+        sc.logout(&mut ws).await?;
+
+        let encoded: String =
+            encoded.chars().filter(|c| !c.is_whitespace()).collect();
+        let data = base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(|e| {
+                Error::Exec(format!("{name}: decode {remote}: {e}"))
+            })?;
+        check_digest(name, remote, &data, &remote_digest)?;
+        fs::write(local, data)?;
+        Ok(())
+    }
+
+    /// Wait until a node's serial console presents a login prompt, without
+    /// logging in or running anything. This is a readiness signal for a node
+    /// that is still booting after `launch` returns. An [`Error::Exec`] is
+    /// returned if no login prompt is seen within `timeout`.
+    pub async fn wait_online(
+        &self,
+        n: NodeRef,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let name = &self.deployment.nodes[n.index].name;
+        let mut sc = self.serial_commander(name)?;
+        let wait = async {
+            let mut ws = sc.connect().await?;
+            sc.wait_for_login_prompt(&mut ws, true).await
         };
-        path.pop();
+        match tokio::time::timeout(timeout, wait).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::Exec(format!(
+                "{}: no login prompt after {}s",
+                name,
+                timeout.as_secs_f64(),
+            ))),
+        }
+    }
+
+    /// Enable an IPv6 link-local address on the guest interface `ifname`, e.g.
+    /// `vioif0`, as the address object `{ifname}/v6`, and wait for duplicate
+    /// address detection to complete before returning the address. The address
+    /// object is reused if it already exists. This uses `ipadm`, so it is only
+    /// supported on illumos guests.
+    pub async fn enable_v6_linklocal(
+        &self,
+        n: NodeRef,
+        ifname: &str,
+    ) -> Result<Ipv6Addr, Error> {
+        let node = &self.deployment.nodes[n.index];
+        if node.guest_os != serial::GuestOs::Illumos {
+            return Err(Error::NotImplemented(format!(
+                "{}: link-local addresses on non-illumos guests",
+                node.name
+            )));
+        }
+        let addrobj = util::shell_quote(&format!("{ifname}/v6"));
+
+        let state = format!("ipadm show-addr -po state {addrobj}");
+        let (_, status) = self.exec_status(n, &state).await?;
+        if status != 0 {
+            let create = format!("ipadm create-addr -t -T addrconf {addrobj}");
+            let (out, status) = self.exec_status(n, &create).await?;
+            if status != 0 {
+                return Err(Error::Exec(format!("{}: {out}", node.name)));
+            }
+        }
+
+        let start = Instant::now();
+        loop {
+            let (out, _) = self.exec_status(n, &state).await?;
+            if out.trim() == "ok" {
+                break;
+            }
+            if start.elapsed() >= V6_DAD_TIMEOUT {
+                return Err(Error::Exec(format!(
+                    "{}: timed out after {}s waiting for {ifname}/v6, \
+                     state is '{out}'",
+                    node.name,
+                    V6_DAD_TIMEOUT.as_secs(),
+                )));
+            }
+            sleep(Duration::from_secs(1)).await;
+        }
+
+        let addr = format!("ipadm show-addr -po addr {addrobj}");
+        let out = self.exec(n, &addr).await?;
+        parse_ipadm_v6(&out).ok_or_else(|| {
+            Error::Exec(format!(
+                "{}: bad address for {ifname}/v6: {out}",
+                node.name
+            ))
+        })
+    }
+
+    /// A serial commander for a running node, configured like the ones `exec`
+    /// uses. Use [`serial::SerialCommander::start_raw`] to drive the console
+    /// without logging in, e.g. for images without a login prompt.
+    pub fn serial_commander_for(
+        &self,
+        n: NodeRef,
+    ) -> Result<serial::SerialCommander, Error> {
+        self.serial_commander(&self.deployment.nodes[n.index].name)
+    }
+
+    /// Log into the serial console of the named node, returning the commander
+    /// and websocket for the session.
+    async fn serial_session(
+        &self,
+        name: &str,
+    ) -> Result<
+        (
+            serial::SerialCommander,
+            WebSocketStream<MaybeTlsStream<TcpStream>>,
+        ),
+        Error,
+    > {
+        let mut sc = self.serial_commander(name)?;
+        let ws = sc.start(true).await?;
+        Ok((sc, ws))
+    }
+
+    /// The serial console credentials of the named node.
+    fn node_login(&self, name: &str) -> serial::Credentials {
+        self.deployment
+            .nodes
+            .iter()
+            .find(|n| n.name == name)
+            .and_then(|n| n.login.clone())
+            .unwrap_or_else(|| self.login.clone())
+    }
+
+    /// The console log of the named node, if serial logging is on.
+    fn console_log_path(&self, name: &str) -> Option<Utf8PathBuf> {
+        self.serial_logging
+            .then(|| self.falcon_dir.join(format!("{name}.console.log")))
+    }
+
+    /// The port the propolis server of a running node listens on.
+    pub fn node_port(&self, n: NodeRef) -> Result<u16, Error> {
+        self.propolis_port(&self.deployment.nodes[n.index].name)
+    }
+
+    /// The uuid of the propolis instance of a running node.
+    pub fn node_uuid(&self, n: NodeRef) -> Result<uuid::Uuid, Error> {
+        self.propolis_uuid(&self.deployment.nodes[n.index].name)
+    }
+
+    fn propolis_port(&self, name: &str) -> Result<u16, Error> {
+        let path = self.falcon_dir.join(format!("{name}.port"));
+        match fs::read_to_string(path) {
+            Ok(p) => Ok(p.trim_end().parse::<u16>()?),
+            Err(e) => Err(Error::NotFound(format!(
+                "get propolis port for {}: {}",
+                name, e
+            ))),
+        }
+    }
+
+    /// The port the VNC server of a running node listens on.
+    pub fn node_vnc_port(&self, n: NodeRef) -> Result<u16, Error> {
+        let name = &self.deployment.nodes[n.index].name;
+        let path = self.falcon_dir.join(format!("{name}.vnc"));
+        match fs::read_to_string(path) {
+            Ok(p) => Ok(p.trim_end().parse::<u16>()?),
+            Err(e) => {
+                Err(Error::NotFound(format!("vnc port for {}: {}", name, e)))
+            }
+        }
+    }
+
+    fn propolis_uuid(&self, name: &str) -> Result<uuid::Uuid, Error> {
+        let path = self.falcon_dir.join(format!("{name}.uuid"));
+        match fs::read_to_string(path) {
+            Ok(u) => Ok(u.trim_end().parse()?),
+            Err(e) => Err(Error::NotFound(format!(
+                "propolis uuid for {}: {}",
+                name, e
+            ))),
+        }
+    }
+
+    /// Create a serial commander for the propolis instance of the named node.
+    fn serial_commander(
+        &self,
+        name: &str,
+    ) -> Result<serial::SerialCommander, Error> {
+        let id = self.propolis_uuid(name)?.to_string();
+        let port = self.propolis_port(name)?;
 
         let addr = SocketAddr::new(
             IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
@@ -697,8 +3563,55 @@ impl Runner {
             name.into(),
             self.log.clone(),
         );
-        let mut ws = sc.start(true).await?;
-        let out = sc.exec(&mut ws, cmd.to_string()).await?;
+        sc.strip_ansi = self.strip_ansi;
+        sc.exec_strategy = self.exec_strategy;
+        sc.login_timeout = self.login_timeout;
+        sc.login_retries = self.login_retries;
+        sc.panic_patterns = serial::panic_regexes(&self.panic_patterns)?;
+        sc.console_log = self.console_log_path(name);
+        sc.credentials = self.node_login(name);
+        sc.guest_os = self
+            .deployment
+            .nodes
+            .iter()
+            .find(|n| n.name == name)
+            .map(|n| n.guest_os)
+            .unwrap_or_default();
+        sc.env = self
+            .deployment
+            .nodes
+            .iter()
+            .find(|n| n.name == name)
+            .map(|n| n.env.clone())
+            .unwrap_or_default();
+        Ok(sc)
+    }
+
+    async fn do_exec(
+        &self,
+        name: &str,
+        cmd: &str,
+        timeout: Option<Duration>,
+        on_line: &mut (dyn FnMut(&str) + Send),
+    ) -> Result<String, Error> {
+        let (mut sc, mut ws) = self.serial_session(name).await?;
+        let deadline_ms = timeout.map(|t| t.as_millis() as u64);
+        let out = match sc
+            .exec_streaming(&mut ws, cmd.to_string(), deadline_ms, on_line)
+            .await
+        {
+            Ok(out) => out,
+            Err(e) => {
+                // Try to leave the console at a login prompt for whoever
+                // uses it next.
+                if let Err(e) = sc.interrupt(&mut ws).await {
+                    warn!(self.log, "interrupt failed: {}", e; "node" => name);
+                } else if let Err(e) = sc.logout(&mut ws).await {
+                    warn!(self.log, "logout failed: {}", e; "node" => name);
+                }
+                return Err(e);
+            }
+        };
         sc.logout(&mut ws).await?;
         Ok(out)
     }
@@ -714,7 +3627,349 @@ impl Deployment {
             nodes: Vec::new(),
             links: Vec::new(),
             ext_links: Vec::new(),
+            bridges: Vec::new(),
+            mac_prefix: None,
+            propolis_log: None,
+            propolis_args: Vec::new(),
+            launch_timeout: launch_timeout(),
+            ensure_retry: EnsureRetry::default(),
+        }
+    }
+
+    /// Read a deployment from a topology file, such as the one `preflight`
+    /// writes to the falcon directory. The format is determined by the
+    /// extension of the file, see [`TopologyFormat::of`].
+    pub fn from_file(path: &Utf8Path) -> Result<Deployment, Error> {
+        let topo = fs::read_to_string(path)?;
+        match TopologyFormat::of(path) {
+            TopologyFormat::Ron => Deployment::from_ron(&topo),
+            TopologyFormat::Toml => Deployment::from_toml(&topo),
+        }
+    }
+
+    /// Write the deployment to a topology file in the format determined by
+    /// its extension.
+    pub(crate) fn write_file(&self, path: &Utf8Path) -> Result<(), Error> {
+        let out = match TopologyFormat::of(path) {
+            TopologyFormat::Ron => self.to_ron()?,
+            TopologyFormat::Toml => self.to_toml()?,
+        };
+        util::write_atomic(path, out)?;
+        Ok(())
+    }
+
+    pub fn to_ron(&self) -> Result<String, Error> {
+        let pretty = PrettyConfig::new().separate_tuple_members(true);
+        Ok(format!("{}\n", to_string_pretty(self, pretty)?))
+    }
+
+    pub fn from_ron(s: &str) -> Result<Deployment, Error> {
+        Ok(ron::de::from_str(s)?)
+    }
+
+    /// Serialize the deployment as TOML. TOML has no null, so the deployment
+    /// goes through a JSON value where absent struct fields are left out and
+    /// other nulls, such as the MAC of `Viona(None)`, become [`TOML_NONE`].
+    pub fn to_toml(&self) -> Result<String, Error> {
+        match json_to_toml(serde_json::to_value(self)?) {
+            Some(value) => Ok(toml::to_string(&value)?),
+            None => Err(Error::Wrap("deployment is not a TOML table".into())),
+        }
+    }
+
+    pub fn from_toml(s: &str) -> Result<Deployment, Error> {
+        let value: toml::Value = toml::from_str(s)?;
+        Ok(serde_json::from_value(toml_to_json(value))?)
+    }
+
+    /// The nodes of this deployment grouped by boot priority, highest priority
+    /// first. Within a group, nodes are in the order they were created.
+    pub fn boot_order(&self) -> Vec<Vec<&Node>> {
+        let mut groups: BTreeMap<Reverse<u32>, Vec<&Node>> = BTreeMap::new();
+        for n in &self.nodes {
+            groups.entry(Reverse(n.boot_priority)).or_default().push(n);
+        }
+        groups.into_values().collect()
+    }
+
+    /// Render the topology of this deployment as a Graphviz DOT graph. Nodes
+    /// are drawn as boxes and links as edges labeled with the index and kind of
+    /// each endpoint. External links are drawn as an edge to an ellipse named
+    /// after the host interface.
+    pub fn to_dot(&self) -> String {
+        fn q(s: &str) -> String {
+            format!("\"{}\"", s.replace('"', "\\\""))
+        }
+        fn ep(e: &Endpoint) -> String {
+            q(&format!("{} {}", e.index, e.kind.kind_name()))
+        }
+
+        let mut out = format!("graph {} {{\n", q(&self.name));
+        out += "    node [shape=box];\n";
+        for n in &self.nodes {
+            let label = format!(
+                "{}\\n{}\\n{} cores, {} MB",
+                n.name, n.image, n.cores, n.memory
+            );
+            out += &format!("    {} [label={}];\n", q(&n.name), q(&label));
+        }
+        for l in &self.links {
+            let [a, b] = &l.endpoints;
+            out += &format!(
+                "    {} -- {} [taillabel={}, headlabel={}];\n",
+                q(&self.nodes[a.node.index].name),
+                q(&self.nodes[b.node.index].name),
+                ep(a),
+                ep(b),
+            );
+        }
+        for x in &self.ext_links {
+            let host = format!("host:{}", x.host_ifx);
+            out += &format!(
+                "    {} [shape=ellipse, label={}];\n",
+                q(&host),
+                q(&x.host_ifx),
+            );
+            out += &format!(
+                "    {} -- {} [taillabel={}];\n",
+                q(&self.nodes[x.endpoint.node.index].name),
+                q(&host),
+                ep(&x.endpoint),
+            );
+        }
+        out += "}\n";
+        out
+    }
+
+    /// The MAC address to give the vnic of an endpoint. This is the
+    /// endpoint's explicit MAC if it has one, so the MAC of a SoftNPU port is
+    /// also the MAC of the vnic backing it on the host. Viona endpoints
+    /// without one get a MAC derived from the deployment's MAC prefix if set.
+    /// `None` leaves the choice to the system.
+    fn vnic_mac(&self, e: &Endpoint) -> Result<Option<Vec<u8>>, Error> {
+        match (&e.kind, self.mac_prefix) {
+            (EndpointKind::Viona(Some(mac)), _)
+            | (EndpointKind::SoftNPU(Some(mac)), _) => {
+                Ok(Some(parse_mac(mac)?))
+            }
+            (EndpointKind::Viona(None), Some(prefix)) => {
+                let node: u16 = e.node.index.try_into()?;
+                let node = node.to_be_bytes();
+                let index: u8 = e.index.try_into()?;
+                Ok(Some(vec![
+                    prefix[0], prefix[1], prefix[2], node[0], node[1], index,
+                ]))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Check the deployment is internally consistent, returning an
+    /// [`Error::Validation`] listing every problem found.
+    pub fn validate(&self) -> Result<(), Error> {
+        let mut problems = Vec::new();
+
+        let mut names = BTreeMap::new();
+        for n in &self.nodes {
+            *names.entry(n.name.as_str()).or_insert(0) += 1;
+            if n.cores == 0 {
+                problems.push(format!("node {} has no cores", n.name));
+            }
+            if n.memory == 0 {
+                problems.push(format!("node {} has no memory", n.name));
+            }
+            if let Some(v) = &n.image_version {
+                let valid = !v.is_empty()
+                    && v.chars().all(|c| {
+                        c.is_ascii_alphanumeric()
+                            || matches!(c, '.' | '_' | '-')
+                    });
+                if !valid {
+                    problems.push(format!(
+                        "node {} has invalid image version `{}`",
+                        n.name, v
+                    ));
+                }
+            }
+        }
+        // extra disk backings are named after their node, next to the
+        // backings of the primary disks, which are named after the node alone
+        for n in &self.nodes {
+            for i in 0..n.extra_disks.len() {
+                let disk = format!("{}_disk{}", n.name, i);
+                if names.contains_key(disk.as_str()) {
+                    problems.push(format!(
+                        "extra disk {i} of node {} has the same backing as \
+                         node {disk}",
+                        n.name
+                    ));
+                }
+            }
+        }
+        for (name, count) in names {
+            if count > 1 {
+                problems.push(format!("node name {name} used {count} times"));
+            }
+        }
+
+        let mut endpoints: Vec<(&str, &Endpoint)> = Vec::new();
+        for l in &self.links {
+            for e in &l.endpoints {
+                endpoints.push(("link", e));
+            }
+        }
+        for x in &self.ext_links {
+            endpoints.push(("external link", &x.endpoint));
+        }
+        for b in &self.bridges {
+            for e in &b.endpoints {
+                endpoints.push(("bridge", e));
+            }
+        }
+
+        // the endpoint indices referencing each node
+        let mut used = vec![Vec::new(); self.nodes.len()];
+        let mut softnpu = vec![false; self.nodes.len()];
+        for (what, e) in endpoints {
+            if let (EndpointKind::SoftNPU(_), Some(x)) =
+                (&e.kind, softnpu.get_mut(e.node.index))
+            {
+                *x = true;
+            }
+            if let EndpointKind::Viona(Some(mac))
+            | EndpointKind::SoftNPU(Some(mac)) = &e.kind
+            {
+                if parse_mac(mac).is_err() {
+                    problems.push(format!("{what} has invalid mac `{mac}`"));
+                }
+            }
+            match used.get_mut(e.node.index) {
+                Some(indices) => indices.push(e.index),
+                None => problems.push(format!(
+                    "{what} references node {} which does not exist",
+                    e.node.index
+                )),
+            }
+        }
+        for (n, mut indices) in self.nodes.iter().zip(used) {
+            if indices.len() != n.radix {
+                problems.push(format!(
+                    "node {} has radix {} but {} endpoints",
+                    n.name,
+                    n.radix,
+                    indices.len()
+                ));
+            }
+            indices.sort_unstable();
+            for w in indices.windows(2) {
+                if w[0] == w[1] {
+                    problems.push(format!(
+                        "node {} has more than one endpoint {}",
+                        n.name, w[0]
+                    ));
+                }
+            }
+            if let Some(i) = indices.iter().find(|i| **i >= n.radix) {
+                problems.push(format!(
+                    "node {} has endpoint {} beyond its radix {}",
+                    n.name, i, n.radix
+                ));
+            }
+        }
+        for (n, has_softnpu) in self.nodes.iter().zip(softnpu) {
+            if n.softnpu.is_some() && !has_softnpu {
+                problems.push(format!(
+                    "node {} has a softnpu config but no softnpu ports",
+                    n.name
+                ));
+            }
+            if n.softnpu.is_some() && !n.mounts.is_empty() {
+                problems.push(format!(
+                    "node {} has a softnpu config and mounts, p9kp can only \
+                     load the program without mounts",
+                    n.name
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Validation(problems))
+        }
+    }
+
+    /// The `/etc/hosts` entries of node `name` for its peers when
+    /// [`Runner::enable_peer_hosts`] is on: each static link address of every
+    /// other node, along with that node's name. Addresses on links shared with
+    /// `name` come first, so peers resolve to an address that is directly
+    /// reachable where there is one.
+    pub fn peer_hosts(&self, name: &str) -> Vec<(IpAddr, String)> {
+        let mut shared = Vec::new();
+        let mut other = Vec::new();
+        for l in &self.links {
+            let on_link = l
+                .endpoints
+                .iter()
+                .any(|e| self.nodes[e.node.index].name == name);
+            for a in &l.addrs {
+                let peer = &self.nodes[l.endpoints[a.endpoint].node.index];
+                if peer.name == name {
+                    continue;
+                }
+                let entry = (a.addr.addr(), peer.name.clone());
+                if on_link {
+                    shared.push(entry);
+                } else {
+                    other.push(entry);
+                }
+            }
+        }
+        shared.extend(other);
+        shared
+    }
+
+    /// All endpoints of the deployment, in the order network devices are
+    /// assigned PCI slots in their nodes.
+    fn endpoints(&self) -> Vec<Endpoint> {
+        let mut endpoints = Vec::new();
+        for l in &self.links {
+            endpoints.extend_from_slice(&l.endpoints);
+        }
+        for l in &self.ext_links {
+            endpoints.push(l.endpoint.clone());
+        }
+        for b in &self.bridges {
+            endpoints.extend_from_slice(&b.endpoints);
+        }
+        endpoints
+    }
+
+    /// The predicted name of the interface of a viona endpoint inside an
+    /// illumos guest, e.g. `vioif1`. Viona devices are assigned consecutive
+    /// PCI slots for links, then external links, then bridges, each in the
+    /// order they were created. This assumes the guest enumerates vioif
+    /// instances in PCI slot order. Returns `None` for endpoints that are not
+    /// viona endpoints.
+    pub fn guest_ifname(&self, e: &Endpoint) -> Option<String> {
+        Some(format!("vioif{}", self.guest_nic(e)?))
+    }
+
+    /// The instance number of the guest NIC of a viona endpoint among the
+    /// viona NICs of its node.
+    fn guest_nic(&self, e: &Endpoint) -> Option<usize> {
+        if !matches!(e.kind, EndpointKind::Viona(_)) {
+            return None;
         }
+        self.endpoints()
+            .iter()
+            .filter(|x| x.node.index == e.node.index)
+            .filter(|x| matches!(x.kind, EndpointKind::Viona(_)))
+            .position(|x| x.index == e.index)
+    }
+
+    fn etherstub_name(&self, bridge: usize) -> String {
+        format!("{}_stub{}", self.name, bridge)
     }
 
     fn simnet_link_name(&self, e: &Endpoint) -> String {
@@ -751,15 +4006,61 @@ impl Drop for Runner {
 
 impl Node {
     async fn preflight(&self, r: &Runner) -> Result<(), Error> {
-        let mut devices = BTreeMap::new();
-        let mut block_devs = BTreeMap::new();
-
-        self.try_ensure_base_image(&r.log).await?;
+        self.check_image_compat(&r.log)?;
 
         let backing = match self.primary_disk_backing {
             PrimaryDiskBacking::Zvol => self.create_zvol_backing(r)?,
             PrimaryDiskBacking::File => self.create_file_backing(r)?,
         };
+        let mut extra_backings = Vec::new();
+        for (i, disk) in self.extra_disks.iter().enumerate() {
+            extra_backings.push(match disk.backing {
+                PrimaryDiskBacking::Zvol => {
+                    self.create_extra_zvol_backing(r, i, disk.size_gb)?
+                }
+                PrimaryDiskBacking::File => {
+                    self.create_extra_file_backing(r, i, disk.size_gb)?
+                }
+            });
+        }
+
+        let propolis_config =
+            self.propolis_config(r, backing, extra_backings)?;
+
+        // write propolis instance config to <falcon_dir>/<node-name>.toml
+
+        let config_toml = toml::to_string(&propolis_config)?;
+
+        let mut path = r.falcon_dir.clone();
+        path.push(format!("{}.toml", self.name));
+        fs::write(&path, config_toml)?;
+
+        Ok(())
+    }
+
+    /// Build the propolis configuration of this node with the backing paths
+    /// `preflight` creates its disks at.
+    fn planned_config(
+        &self,
+        r: &Runner,
+    ) -> Result<propolis_server_config::Config, Error> {
+        let extra_backings = (0..self.extra_disks.len())
+            .map(|i| self.extra_backing_path(r, i))
+            .collect();
+        self.propolis_config(r, self.backing_path(r), extra_backings)
+    }
+
+    /// Build the propolis configuration of this node, given the paths of the
+    /// backings of its primary disk and its extra disks.
+    fn propolis_config(
+        &self,
+        r: &Runner,
+        backing: String,
+        extra_backings: Vec<String>,
+    ) -> Result<propolis_server_config::Config, Error> {
+        let mut devices = BTreeMap::new();
+        let mut block_devs = BTreeMap::new();
+
         self.create_blockdev(backing, &mut devices, &mut block_devs);
 
         let mut pci_index = 5;
@@ -768,7 +4069,11 @@ impl Node {
         for (i, m) in self.mounts.iter().enumerate() {
             let mut opts = BTreeMap::new();
             opts.insert("source".to_string(), m.source.to_string().into());
-            opts.insert("target".to_string(), m.destination.to_string().into());
+            opts.insert("target".to_string(), mount_tag(i).into());
+            opts.insert(
+                "chunk_size".to_string(),
+                toml::Value::Integer(m.chunk_size.into()),
+            );
             opts.insert(
                 "pci-path".to_string(),
                 toml::Value::String(format!("0.{}.0", pci_index)),
@@ -784,22 +4089,131 @@ impl Node {
             pci_index += 1;
         }
 
-        // network interfaces
-        let d = &r.deployment;
-
-        //let mut links: Vec<String> = Vec::new();
-        let mut viona_index = 0;
-        let mut softnpu_index = 0;
-        let mut sidemux_index = 0;
+        // extra disks
+        for (i, backing) in extra_backings.into_iter().enumerate() {
+            let name = format!("disk{}", i);
+            let mut device_options = BTreeMap::new();
+            device_options.insert(
+                "block_dev".to_string(),
+                toml::Value::String(name.clone()),
+            );
+            device_options.insert(
+                "pci-path".to_string(),
+                toml::Value::String(format!("0.{}.0", pci_index)),
+            );
+            devices.insert(
+                format!("block{}", i + 1),
+                propolis_server_config::Device {
+                    driver: "pci-virtio-block".to_string(),
+                    options: device_options,
+                },
+            );
+            let mut blockdev_options = BTreeMap::new();
+            blockdev_options
+                .insert("path".to_string(), toml::Value::String(backing));
+            block_devs.insert(
+                name,
+                propolis_server_config::BlockDevice {
+                    bdtype: "file".to_string(),
+                    options: blockdev_options,
+                    opts: BlockOpts {
+                        block_size: None,
+                        read_only: None,
+                        skip_flush: Some(true),
+                    },
+                },
+            );
+            pci_index += 1;
+        }
 
-        let mut endpoints = Vec::new();
-        for l in &d.links {
-            endpoints.extend_from_slice(&l.endpoints);
+        // host disks
+        for (i, disk) in self.host_disks.iter().enumerate() {
+            let name = format!("host_disk{}", i);
+            let mut device_options = BTreeMap::new();
+            device_options.insert(
+                "block_dev".to_string(),
+                toml::Value::String(name.clone()),
+            );
+            device_options.insert(
+                "pci-path".to_string(),
+                toml::Value::String(format!("0.{}.0", pci_index)),
+            );
+            devices.insert(
+                format!("hostblock{}", i),
+                propolis_server_config::Device {
+                    driver: "pci-virtio-block".to_string(),
+                    options: device_options,
+                },
+            );
+            let mut blockdev_options = BTreeMap::new();
+            blockdev_options.insert(
+                "path".to_string(),
+                toml::Value::String(disk.path.to_string()),
+            );
+            block_devs.insert(
+                name,
+                propolis_server_config::BlockDevice {
+                    bdtype: "file".to_string(),
+                    options: blockdev_options,
+                    opts: BlockOpts {
+                        block_size: None,
+                        read_only: Some(disk.read_only),
+                        skip_flush: None,
+                    },
+                },
+            );
+            pci_index += 1;
         }
-        for l in &d.ext_links {
-            endpoints.push(l.endpoint.clone());
+
+        // isos
+        for (i, iso) in self.isos.iter().enumerate() {
+            let name = format!("iso{}", i);
+            let mut device_options = BTreeMap::new();
+            device_options.insert(
+                "block_dev".to_string(),
+                toml::Value::String(name.clone()),
+            );
+            device_options.insert(
+                "pci-path".to_string(),
+                toml::Value::String(format!("0.{}.0", pci_index)),
+            );
+            devices.insert(
+                format!("isoblock{}", i),
+                propolis_server_config::Device {
+                    driver: "pci-virtio-block".to_string(),
+                    options: device_options,
+                },
+            );
+            let mut blockdev_options = BTreeMap::new();
+            blockdev_options.insert(
+                "path".to_string(),
+                toml::Value::String(iso.to_string()),
+            );
+            block_devs.insert(
+                name,
+                propolis_server_config::BlockDevice {
+                    bdtype: "file".to_string(),
+                    options: blockdev_options,
+                    opts: BlockOpts {
+                        block_size: None,
+                        read_only: Some(true),
+                        skip_flush: None,
+                    },
+                },
+            );
+            pci_index += 1;
         }
 
+        // network interfaces
+        let d = &r.deployment;
+
+        //let mut links: Vec<String> = Vec::new();
+        let mut viona_index = 0;
+        let mut softnpu_index = 0;
+        let mut sidemux_index = 0;
+
+        let endpoints = d.endpoints();
+
         let has_softnpu = endpoints
             .iter()
             .any(|x| matches!(&x.kind, EndpointKind::SoftNPU(_)));
@@ -928,99 +4342,190 @@ impl Node {
             }
         }
 
-        let chipset = propolis_server_config::Chipset {
+        let mut chipset = propolis_server_config::Chipset {
             options: BTreeMap::new(),
         };
+        if self.enable_pcie {
+            chipset
+                .options
+                .insert("enable-pcie".to_string(), toml::Value::Boolean(true));
+        }
 
-        // write propolis instance config to <falcon_dir>/<node-name>.toml
+        let mut cpuid_profiles = BTreeMap::new();
+        if let Some(cpuid) = &self.cpuid {
+            cpuid_profiles.insert(self.name.clone(), cpuid.to_profile());
+            chipset.options.insert(
+                "cpuid-profile".to_string(),
+                toml::Value::String(self.name.clone()),
+            );
+        }
 
-        let propolis_config = propolis_server_config::Config {
+        Ok(propolis_server_config::Config {
             bootrom: PathBuf::from("/var/ovmf/OVMF_CODE.fd"),
             chipset,
             devices,
             block_devs,
+            cpuid_profiles,
             ..Default::default()
-        };
-
-        let config_toml = toml::to_string(&propolis_config)?;
+        })
+    }
 
-        let mut path = r.falcon_dir.clone();
-        path.push(format!("{}.toml", self.name));
-        fs::write(&path, config_toml)?;
+    /// The path of the backing of this node's primary disk.
+    fn backing_path(&self, r: &Runner) -> String {
+        match self.primary_disk_backing {
+            PrimaryDiskBacking::Zvol => format!(
+                "/dev/zvol/rdsk/{}/topo/{}/{}",
+                self.dataset, r.deployment.name, self.name,
+            ),
+            PrimaryDiskBacking::File => {
+                format!("{}/{}/{}", r.file_dir, r.deployment.name, self.name)
+            }
+        }
+    }
 
-        Ok(())
+    /// The path of the backing of this node's extra disk at `index`.
+    fn extra_backing_path(&self, r: &Runner, index: usize) -> String {
+        match self.extra_disks[index].backing {
+            PrimaryDiskBacking::Zvol => format!(
+                "/dev/zvol/rdsk/{}/topo/{}/{}_disk{}",
+                self.dataset, r.deployment.name, self.name, index
+            ),
+            PrimaryDiskBacking::File => format!(
+                "{}/{}/{}_disk{}",
+                r.file_dir, r.deployment.name, self.name, index
+            ),
+        }
     }
 
-    async fn try_ensure_base_image(&self, log: &Logger) -> Result<(), Error> {
-        match Command::new(ZFS_BIN)
-            .args([
-                "list",
-                "-t",
-                "snapshot",
-                format!("{}/img/{}@base", self.dataset, self.image).as_str(),
-            ])
-            .output()
-        {
-            Ok(output) if output.status.success() => Ok(()),
-            _ => {
-                info!(
-                    log,
-                    "base image for {} does not exist, attempting to install",
-                    self.image
-                );
-                self.try_install_base_image(log).await
-            }
+    async fn try_ensure_base_image(
+        &self,
+        log: &Logger,
+        base_url: &str,
+        verify: bool,
+        opts: &ZfsOpts,
+        mp: &MultiProgress,
+    ) -> Result<(), Error> {
+        let snapshot =
+            format!("{}/img/{}@base", self.dataset, self.image_name());
+        if zfs::exists(&snapshot)? {
+            return Ok(());
         }
+        info!(
+            log,
+            "base image for {} does not exist, attempting to install",
+            self.image_name()
+        );
+        self.try_install_base_image(log, base_url, verify, opts, mp)
+            .await
     }
 
-    async fn try_install_base_image(&self, log: &Logger) -> Result<(), Error> {
-        let iname = format!("{}_0.raw.xz", self.image);
+    async fn try_install_base_image(
+        &self,
+        log: &Logger,
+        base_url: &str,
+        verify: bool,
+        opts: &ZfsOpts,
+        mp: &MultiProgress,
+    ) -> Result<(), Error> {
+        let iname = format!("{}.raw.xz", self.image_asset());
         let path = format!("/tmp/{iname}");
-        let extracted = path.strip_suffix(".xz").unwrap();
-        self.try_download_base_image(log, iname.as_str(), path.as_str())
+        let extracted = path.strip_suffix(".xz").unwrap().to_string();
+        let url = asset_url(base_url, &iname);
+        self.try_download_base_image(log, &url, path.as_str(), mp)
             .await?;
-        let fsize = Self::try_extract_image(log, path.as_str(), extracted)?;
-        self.try_create_zfs_volume_for_image(log, fsize, extracted)?;
-        Ok(())
+        if verify && !Self::try_verify_image(log, &url, &path).await? {
+            // a previous extraction may have come from the bad download
+            warn!(log, "{path} is corrupt, downloading it again");
+            fs::remove_file(&path)?;
+            let _ = fs::remove_file(&extracted);
+            self.try_download_base_image(log, &url, path.as_str(), mp)
+                .await?;
+            if !Self::try_verify_image(log, &url, &path).await? {
+                return Err(Error::Exec(format!(
+                    "{path}: digest does not match {url}.sha256.txt"
+                )));
+            }
+        }
+        if let Err(e) = self.try_download_image_metadata(log, base_url).await {
+            warn!(log, "failed to get metadata for {}: {}", self.image, e);
+        }
+
+        // extraction and copying are blocking, keep them off the runtime
+        let (log, mp) = (log.clone(), mp.clone());
+        let (dataset, image) = (self.dataset.clone(), self.image_name());
+        let opts = opts.clone();
+        tokio::task::spawn_blocking(move || {
+            let fsize = Self::try_extract_image(&log, &path, &extracted, &mp)?;
+            Self::try_create_zfs_volume_for_image(
+                &log, &dataset, &image, fsize, &extracted, &opts, &mp,
+            )
+        })
+        .await
+        .context("image install task")?
+    }
+
+    /// Check the digest of the downloaded image at `path` against the one
+    /// published at `{url}.sha256.txt`. Returns whether they match. An image
+    /// without a published digest is not verified and passes with a warning.
+    async fn try_verify_image(
+        log: &Logger,
+        url: &str,
+        path: &str,
+    ) -> Result<bool, Error> {
+        let sum_url = format!("{url}.sha256.txt");
+        let response = reqwest::get(&sum_url)
+            .await
+            .with_context(|| format!("failed to get url {sum_url}"))?;
+        if !response.status().is_success() {
+            warn!(
+                log,
+                "no digest at {sum_url} ({}), not verifying {path}",
+                response.status()
+            );
+            return Ok(true);
+        }
+        let body = response
+            .text()
+            .await
+            .with_context(|| format!("failed to read {sum_url}"))?;
+        let expected = parse_sha256_file(&body)?;
+
+        let p = path.to_string();
+        let digest = tokio::task::spawn_blocking(move || file_sha256(&p))
+            .await
+            .context("image digest task")??;
+        if digest != expected {
+            warn!(log, "{path}: sha256 {digest}, expected {expected}");
+            return Ok(false);
+        }
+        info!(log, "verified {path}");
+        Ok(true)
     }
 
     fn try_create_zfs_volume_for_image(
-        &self,
         log: &Logger,
+        dataset: &str,
+        image: &str,
         fsize: usize,
         source: &str,
+        opts: &ZfsOpts,
+        mp: &MultiProgress,
     ) -> Result<(), Error> {
-        let zpath = format!("{}/img/{}", self.dataset, self.image);
-        let bsize = fsize + 4096 - fsize % 4096;
+        let zpath = format!("{}/img/{}", dataset, image);
+        // the volume size must be a multiple of the block size
+        let block = opts.volblocksize_bytes().ok_or_else(|| {
+            Error::Zfs(format!("invalid volblocksize {}", opts.volblocksize))
+        })?;
+        let bsize = fsize + block - fsize % block;
         info!(log, "creating zvol {zpath} of size {bsize}");
-        let out = Command::new(ZFS_BIN)
-            .args([
-                "create",
-                "-p",
-                "-V",
-                &bsize.to_string(),
-                "-o",
-                "volblocksize=4k",
-                zpath.as_str(),
-            ])
-            .output()
-            .context("zfs create volume")?;
-
-        if !out.status.success() {
-            return Err(Error::Exec(format!(
-                "zfs create vol: {} {}",
-                String::from_utf8_lossy(out.stdout.as_slice()),
-                String::from_utf8_lossy(out.stderr.as_slice())
-            )));
-        }
+        zfs::create_volume(&zpath, &bsize.to_string(), &opts.create_args())?;
 
         info!(log, "copying image data to zvol");
         let source = std::fs::File::open(source)?;
-        let dst = OpenOptions::new().write(true).open(format!(
-            "/dev/zvol/rdsk/{}/img/{}",
-            self.dataset, self.image
-        ))?;
-        let pb = Self::new_progress_bar();
+        let dst = OpenOptions::new()
+            .write(true)
+            .open(format!("/dev/zvol/rdsk/{}/img/{}", dataset, image))?;
+        let pb = Self::new_progress_bar(mp);
         pb.inc_length(dst.metadata().context("zvol dst metadata")?.len());
         let mut dst = BufWriter::with_capacity(1024 * 1024, dst);
 
@@ -1029,26 +4534,16 @@ impl Node {
 
         pb.finish();
 
-        let spath = format!("{}/img/{}@base", self.dataset, self.image);
+        let spath = format!("{}/img/{}@base", dataset, image);
         info!(log, "creating zfs snapshot {spath}");
-        let out = Command::new(ZFS_BIN)
-            .args(["snapshot", spath.as_str()])
-            .output()
-            .context("zfs create snapshot")?;
-        if !out.status.success() {
-            return Err(Error::Exec(format!(
-                "zfs create snapshot: {} {}",
-                String::from_utf8_lossy(out.stdout.as_slice()),
-                String::from_utf8_lossy(out.stderr.as_slice())
-            )));
-        }
-        Ok(())
+        zfs::snapshot(&spath)
     }
 
     fn try_extract_image(
         log: &Logger,
         from: &str,
         to: &str,
+        mp: &MultiProgress,
     ) -> Result<usize, Error> {
         if Path::new(to).exists() {
             info!(log, "image already extracted");
@@ -1061,7 +4556,7 @@ impl Node {
                 .context("file size as usize")?);
         }
         info!(log, "extracting image to {to}");
-        let pb = Self::new_progress_bar();
+        let pb = Self::new_progress_bar(mp);
         let in_file = std::fs::File::open(from)?;
         let len = in_file
             .metadata()
@@ -1069,10 +4564,30 @@ impl Node {
             .len();
         pb.inc_length(len);
         let in_file = pb.wrap_read(in_file);
-        let mut dec = XzDecoder::new(in_file);
+        // blocks of the image are decoded in parallel, this only helps for
+        // images compressed with more than one block, e.g. by `xz -T0`
+        let threads = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        let stream = MtStreamBuilder::new()
+            .threads(threads.try_into().unwrap_or(u32::MAX))
+            .memlimit_threading(XZ_THREADING_MEMLIMIT)
+            .memlimit_stop(u64::MAX)
+            .decoder()
+            .context("xz decoder")?;
+        let mut dec = XzDecoder::new_stream(in_file, stream);
         let mut outfile = std::fs::File::create(to)?;
-        std::io::copy(&mut dec, &mut outfile)?;
+        let start = Instant::now();
+        let n = std::io::copy(&mut dec, &mut outfile)?;
         pb.finish();
+        let secs = start.elapsed().as_secs_f64();
+        debug!(
+            log,
+            "extracted {to}: {:.1} MiB in {secs:.1}s ({:.1} MiB/s, {threads} \
+             threads)",
+            n as f64 / MIB,
+            n as f64 / MIB / secs.max(f64::EPSILON),
+        );
         Ok(outfile
             .metadata()
             .context("get file metadata")?
@@ -1081,8 +4596,10 @@ impl Node {
             .context("file size as usize")?)
     }
 
-    fn new_progress_bar() -> ProgressBar {
-        let pb = ProgressBar::new(0);
+    /// Create a progress bar drawn as part of `mp`, so bars of concurrent
+    /// image installs do not draw over each other.
+    fn new_progress_bar(mp: &MultiProgress) -> ProgressBar {
+        let pb = mp.add(ProgressBar::new(0));
         let sty = ProgressStyle::with_template(
             "[{elapsed_precise}] \
             {bar:40.cyan/blue} \
@@ -1095,22 +4612,25 @@ impl Node {
         pb
     }
 
+    /// Download the image at `url` to `path`. The image is downloaded to
+    /// `{path}.tmp` first and only renamed to `path` once complete. A download
+    /// that fails is retried up to [`IMAGE_DOWNLOAD_ATTEMPTS`] times in total,
+    /// and each attempt, including the first one of a later run, resumes from
+    /// where the previous one left off if the server supports range requests.
     async fn try_download_base_image(
         &self,
         log: &Logger,
-        iname: &str,
+        url: &str,
         path: &str,
+        mp: &MultiProgress,
     ) -> Result<(), Error> {
         if Path::new(path).exists() {
             info!(log, "image already downloaded");
             return Ok(());
         }
-        let url = format!(
-            "https://oxide-falcon-assets.s3.us-west-2.amazonaws.com/{iname}"
-        );
         info!(log, "trying to download {url}");
 
-        let pb = Self::new_progress_bar();
+        let pb = Self::new_progress_bar(mp);
 
         let client = reqwest::ClientBuilder::new()
             .timeout(Duration::from_secs(3600))
@@ -1118,26 +4638,90 @@ impl Node {
             .connect_timeout(Duration::from_secs(15))
             .build()
             .unwrap();
-        let response = client
-            .get(&url)
+
+        let tmp = format!("{path}.tmp");
+        let mut attempt = 1;
+        loop {
+            match Self::try_download_to(log, &client, url, &tmp, &pb).await {
+                Ok(()) => break,
+                Err(e) if attempt < IMAGE_DOWNLOAD_ATTEMPTS => {
+                    warn!(
+                        log,
+                        "download attempt {} of {} failed, retrying: {}",
+                        attempt,
+                        IMAGE_DOWNLOAD_ATTEMPTS,
+                        e
+                    );
+                    attempt += 1;
+                    sleep(Duration::from_secs(1)).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        pb.finish();
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Download `url` to `tmp`, resuming from the end of `tmp` if it exists
+    /// and the server answers the range request with partial content.
+    async fn try_download_to(
+        log: &Logger,
+        client: &reqwest::Client,
+        url: &str,
+        tmp: &str,
+        pb: &ProgressBar,
+    ) -> Result<(), Error> {
+        let offset = match tokio::fs::metadata(tmp).await {
+            Ok(md) => md.len(),
+            Err(_) => 0,
+        };
+        let mut request = client.get(url);
+        if offset > 0 {
+            request = request
+                .header(reqwest::header::RANGE, format!("bytes={offset}-"));
+        }
+        let response = request
             .send()
             .await
             .with_context(|| format!("failed to get url {url}"))?;
 
-        if !response.status().is_success() {
-            Err(anyhow::anyhow!(
-                "failed to download image: {}",
-                response.status()
-            ))?;
+        let status = response.status();
+        if status == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+            // whatever is in the temporary file does not match the image,
+            // start over on the next attempt
+            fs::remove_file(tmp)?;
+            Err(anyhow::anyhow!("failed to resume download: {}", status))?;
         }
-        pb.inc_length(
-            response
-                .content_length()
-                .ok_or_else(|| anyhow::anyhow!("Missing content length"))?,
-        );
-        let mut file = tokio::fs::File::create(path)
-            .await
-            .with_context(|| format!("failed to create {path}"))?;
+        if !status.is_success() {
+            Err(anyhow::anyhow!("failed to download image: {}", status))?;
+        }
+        let len = response
+            .content_length()
+            .ok_or_else(|| anyhow::anyhow!("Missing content length"))?;
+
+        let mut file = if offset > 0
+            && status == reqwest::StatusCode::PARTIAL_CONTENT
+        {
+            info!(log, "resuming download of {url} at {offset} bytes");
+            pb.set_length(offset + len);
+            pb.set_position(offset);
+            tokio::fs::OpenOptions::new()
+                .append(true)
+                .open(tmp)
+                .await
+                .with_context(|| format!("failed to open {tmp}"))?
+        } else {
+            if offset > 0 {
+                info!(log, "server can not resume {url}, restarting download");
+            }
+            pb.set_length(len);
+            pb.set_position(0);
+            tokio::fs::File::create(tmp)
+                .await
+                .with_context(|| format!("failed to create {tmp}"))?
+        };
+
         let mut stream = response.bytes_stream();
         while let Some(chunk) = stream.next().await {
             let chunk = chunk.with_context(|| {
@@ -1145,88 +4729,169 @@ impl Node {
             })?;
             file.write_all(&chunk)
                 .await
-                .with_context(|| format!("failed writing {path:?}"))?;
+                .with_context(|| format!("failed writing {tmp:?}"))?;
             pb.inc(chunk.len().try_into().unwrap());
         }
-        pb.finish();
+        file.flush()
+            .await
+            .with_context(|| format!("failed writing {tmp:?}"))?;
+        Ok(())
+    }
+
+    /// The name of this node's image in `{dataset}/img`, which includes the
+    /// version of the image if it is pinned.
+    fn image_name(&self) -> String {
+        match &self.image_version {
+            Some(version) => format!("{}_{}", self.image, version),
+            None => self.image.clone(),
+        }
+    }
+
+    /// The name of the assets of this node's image, without an extension.
+    fn image_asset(&self) -> String {
+        let version = self.image_version.as_deref().unwrap_or("0");
+        format!("{}_{}", self.image, version)
+    }
+
+    fn image_metadata_path(&self) -> String {
+        format!("/tmp/{}.meta.toml", self.image_asset())
+    }
+
+    /// Download the metadata sidecar for this node's image, if one has been
+    /// published.
+    async fn try_download_image_metadata(
+        &self,
+        log: &Logger,
+        base_url: &str,
+    ) -> Result<(), Error> {
+        let url =
+            asset_url(base_url, &format!("{}.meta.toml", self.image_asset()));
+        let response = reqwest::get(&url)
+            .await
+            .with_context(|| format!("failed to get url {url}"))?;
+        if !response.status().is_success() {
+            debug!(log, "no image metadata at {url}: {}", response.status());
+            return Ok(());
+        }
+        let body = response
+            .bytes()
+            .await
+            .with_context(|| format!("failed reading response from {url}"))?;
+        util::write_atomic(self.image_metadata_path(), body)?;
         Ok(())
     }
 
+    /// Check this node's image is compatible with the propolis falcon is
+    /// built for, if metadata is available for the image.
+    fn check_image_compat(&self, log: &Logger) -> Result<(), Error> {
+        let path = self.image_metadata_path();
+        let meta = match fs::read_to_string(&path) {
+            Ok(s) => s,
+            Err(_) => {
+                debug!(log, "no metadata for image {}", self.image);
+                return Ok(());
+            }
+        };
+        let meta: ImageMetadata = toml::from_str(&meta)
+            .with_context(|| format!("failed to parse {path}"))?;
+        meta.check(&self.image, log)
+    }
+
     fn create_zvol_backing(&self, r: &Runner) -> Result<String, Error> {
         //Clone base image
-
-        //TODO incorporate version into img
-        let source = format!("{}/img/{}@base", self.dataset, self.image);
+        let source = format!("{}/img/{}@base", self.dataset, self.image_name());
         let dest = format!(
             "{}/topo/{}/{}",
             self.dataset, r.deployment.name, self.name
         );
 
-        let out = Command::new(ZFS_BIN)
-            .args(["clone", "-p", source.as_ref(), dest.as_ref()])
-            .output()?;
+        zfs::clone(&source, &dest)?;
 
-        if !out.status.success() {
-            return Err(Error::Zfs(String::from_utf8(out.stderr)?));
+        let size = format!("{}G", self.reserved);
+        zfs::set_prop(&dest, "volsize", &size)?;
+        zfs::set_prop(&dest, "reservation", &size)?;
+        zfs::set_prop(&dest, "sync", &r.zfs_opts.sync)?;
+        if let Some(c) = &r.zfs_opts.compression {
+            zfs::set_prop(&dest, "compression", c)?;
         }
 
-        let volsize = format!("volsize={}G", self.reserved);
+        Ok(self.backing_path(r))
+    }
 
-        let out = Command::new(ZFS_BIN)
-            .args(["set", volsize.as_str(), dest.as_ref()])
-            .output()?;
+    fn create_file_backing(&self, r: &Runner) -> Result<String, Error> {
+        let size = format!("{}G", self.reserved);
 
-        if !out.status.success() {
-            return Err(Error::Zfs(String::from_utf8(out.stderr)?));
+        let dir = format!("{}/{}", r.file_dir, r.deployment.name);
+        if let Err(e) = fs::create_dir_all(&dir) {
+            error!(r.log, "failed to create image directory: {e}");
+            return Err(Error::IO(e));
         }
+        let backing = self.backing_path(r);
+        let source_zvol = format!(
+            "/dev/zvol/dsk/{}/img/{}@base",
+            self.dataset,
+            self.image_name()
+        );
 
-        let reserved = format!("reservation={}G", self.reserved);
-
-        let out = Command::new(ZFS_BIN)
-            .args(["set", reserved.as_str(), dest.as_ref()])
+        info!(r.log, "copying backing image for {}", self.name);
+        let dd_if = format!("if={source_zvol}");
+        let dd_of = format!("of={backing}");
+        let out = Command::new(DD_BIN)
+            .args([dd_if.as_str(), dd_of.as_str(), "bs=1024M"])
             .output()?;
-
         if !out.status.success() {
-            return Err(Error::Zfs(String::from_utf8(out.stderr)?));
+            return Err(Error::Exec(String::from_utf8(out.stderr)?));
         }
 
-        let out = Command::new(ZFS_BIN)
-            .args(["set", "sync=disabled", dest.as_ref()])
+        let out = Command::new(TRUNCATE_BIN)
+            .args(["-s", size.as_str(), backing.as_str()])
             .output()?;
-
         if !out.status.success() {
-            return Err(Error::Zfs(String::from_utf8(out.stderr)?));
+            return Err(Error::Exec(String::from_utf8(out.stderr)?));
         }
 
-        let zvol = format!(
-            "/dev/zvol/rdsk/{}/topo/{}/{}",
-            self.dataset, r.deployment.name, self.name,
+        Ok(backing)
+    }
+
+    /// Create an empty zvol for the extra disk at `index`. The zvol lives
+    /// alongside the node's primary disk under the deployment's topo dataset,
+    /// so it is removed along with it when the deployment is destroyed.
+    fn create_extra_zvol_backing(
+        &self,
+        r: &Runner,
+        index: usize,
+        size_gb: usize,
+    ) -> Result<String, Error> {
+        let dest = format!(
+            "{}/topo/{}/{}_disk{}",
+            self.dataset, r.deployment.name, self.name, index
         );
+        let volsize = format!("{}G", size_gb);
+        let mut opts =
+            vec!["-o".to_string(), format!("sync={}", r.zfs_opts.sync)];
+        opts.extend(r.zfs_opts.create_args());
 
-        Ok(zvol)
+        zfs::create_volume(&dest, &volsize, &opts)?;
+
+        Ok(self.extra_backing_path(r, index))
     }
 
-    fn create_file_backing(&self, r: &Runner) -> Result<String, Error> {
-        let size = format!("{}G", self.reserved);
+    /// Create an empty sparse file for the extra disk at `index`, in the same
+    /// directory as file backed primary disks.
+    fn create_extra_file_backing(
+        &self,
+        r: &Runner,
+        index: usize,
+        size_gb: usize,
+    ) -> Result<String, Error> {
+        let size = format!("{}G", size_gb);
 
-        let dir = format!("/var/falcon/dsk/{}", r.deployment.name);
+        let dir = format!("{}/{}", r.file_dir, r.deployment.name);
         if let Err(e) = fs::create_dir_all(&dir) {
             error!(r.log, "failed to create image directory: {e}");
             return Err(Error::IO(e));
         }
-        let backing = format!("{}/{}", dir, self.name);
-        let source_zvol =
-            format!("/dev/zvol/dsk/{}/img/{}@base", self.dataset, self.image);
-
-        info!(r.log, "copying backing image for {}", self.name);
-        let dd_if = format!("if={source_zvol}");
-        let dd_of = format!("of={backing}");
-        let out = Command::new(DD_BIN)
-            .args([dd_if.as_str(), dd_of.as_str(), "bs=1024M"])
-            .output()?;
-        if !out.status.success() {
-            return Err(Error::Exec(String::from_utf8(out.stderr)?));
-        }
+        let backing = self.extra_backing_path(r, index);
 
         let out = Command::new(TRUNCATE_BIN)
             .args(["-s", size.as_str(), backing.as_str()])
@@ -1277,19 +4942,106 @@ impl Node {
         );
     }
 
-    async fn launch(&self, r: &Runner) -> Result<(), Error> {
+    async fn launch(&self, r: &Runner) -> Result<NodeLaunch, Error> {
+        self.start(r, true).await
+    }
+
+    /// Launch a node whose disk has already been through first boot
+    /// provisioning, see [`Runner::reconcile`].
+    async fn relaunch(&self, r: &Runner) -> Result<NodeLaunch, Error> {
+        self.start(r, false).await
+    }
+
+    async fn start(
+        &self,
+        r: &Runner,
+        first_boot: bool,
+    ) -> Result<NodeLaunch, Error> {
         // launch vm
 
         let id = uuid::Uuid::new_v4();
-        let port =
-            launch_vm(&r.log, &r.propolis_binary, &id, self, &r.falcon_dir)
+        if let Some(cmd) = &self.host_hooks.pre_launch {
+            self.run_host_hook(r, "pre-launch", cmd, &id, None).await?;
+        }
+
+        let propolis = PropolisLaunch {
+            binary: self.propolis_binary(r),
+            extra_args: &r.deployment.propolis_args,
+            log_filter: r.deployment.propolis_log.as_deref(),
+            timeout: r.deployment.launch_timeout,
+            ensure_retry: r.deployment.ensure_retry,
+        };
+        let launched =
+            launch_vm(&r.log, &id, self, &r.falcon_dir, &propolis).await?;
+        let port = launched.port;
+
+        if self.do_setup {
+            self.setup(r, &id, port, first_boot).await?;
+        }
+
+        if let Some(cmd) = &self.host_hooks.post_launch {
+            self.run_host_hook(r, "post-launch", cmd, &id, Some(port))
                 .await?;
+        }
 
-        if !self.do_setup {
-            return Ok(());
+        Ok(launched)
+    }
+
+    /// The runner's logger with this node's name attached.
+    fn log(&self, r: &Runner) -> Logger {
+        r.log.new(slog::o!("node" => self.name.clone()))
+    }
+
+    async fn run_host_hook(
+        &self,
+        r: &Runner,
+        what: &str,
+        cmd: &str,
+        id: &uuid::Uuid,
+        port: Option<u16>,
+    ) -> Result<(), Error> {
+        info!(self.log(r), "running hook"; "hook" => what);
+        let mut c = tokio::process::Command::new("sh");
+        c.args(["-c", cmd])
+            .env("FALCON_DEPLOYMENT", &r.deployment.name)
+            .env("FALCON_NODE", &self.name)
+            .env("FALCON_UUID", id.to_string())
+            .kill_on_drop(true);
+        if let Some(port) = port {
+            c.env("FALCON_PORT", port.to_string());
+        }
+        let out = tokio::time::timeout(r.deployment.launch_timeout, c.output())
+            .await
+            .map_err(|_| {
+                Error::Exec(format!(
+                    "{}: {} hook timed out after {:?}",
+                    self.name, what, r.deployment.launch_timeout
+                ))
+            })?
+            .map_err(|e| {
+                Error::Exec(format!("{}: {} hook: {}", self.name, what, e))
+            })?;
+        if !out.status.success() {
+            return Err(Error::Exec(format!(
+                "{}: {} hook failed ({}): {}",
+                self.name,
+                what,
+                out.status,
+                String::from_utf8_lossy(&out.stderr),
+            )));
         }
+        Ok(())
+    }
 
+    async fn setup(
+        &self,
+        r: &Runner,
+        id: &uuid::Uuid,
+        port: u16,
+        first_boot: bool,
+    ) -> Result<(), Error> {
         // initial vm configuration
+        let log = self.log(r);
 
         let ws_sockaddr = format!("[::1]:{}", port);
 
@@ -1300,16 +5052,44 @@ impl Node {
             self.name.clone(),
             r.log.clone(),
         );
+        sc.strip_ansi = r.strip_ansi;
+        sc.exec_strategy = r.exec_strategy;
+        sc.login_timeout = r.login_timeout;
+        sc.login_retries = r.login_retries;
+        sc.panic_patterns = serial::panic_regexes(&r.panic_patterns)?;
+        sc.console_log = r.console_log_path(&self.name);
+        sc.credentials = r.node_login(&self.name);
+        sc.guest_os = self.guest_os;
+        sc.env = self.env.clone();
         let mut ws = sc.start(false).await?;
 
-        // setup mounts
-        // TODO this will only work as expected for one mount.
-        for mount in &self.mounts {
-            info!(r.log, "{}: mounting {}", self.name, mount.destination);
+        if !self.settle_time.is_zero() {
+            info!(log, "waiting {:?} for node to settle", self.settle_time);
+            sleep(self.settle_time).await;
+        }
+
+        // a relaunched node's disk already has what first boot provisioning
+        // put on it: the files pulled by p9kp, the softnpu program and whatever
+        // the init script did
+        let (softnpu, init_script) = match first_boot {
+            true => (&self.softnpu, &self.init_script),
+            false => (&None, &None),
+        };
+
+        // setup mounts, the i'th mount is the i'th p9fs device in the guest,
+        // a p9kp mount is always the first
+        for (i, mount) in self.mounts.iter().enumerate() {
+            let copied = mount.mechanism == GuestMountMechanism::P9kp;
+            if copied && !first_boot {
+                continue;
+            }
+            info!(log, "mounting"; "destination" => %mount.destination);
             let cmd = if mount.mechanism == GuestMountMechanism::Mount {
                 format!(
-                    "mkdir -p {dst}; mount -t 9p -o ro,msize=65536 {dst} {dst}",
-                    dst = mount.destination
+                    "mkdir -p {dst}; mount -t 9p -o ro,msize={msize} {tag} {dst}",
+                    dst = mount.destination,
+                    msize = mount.chunk_size,
+                    tag = mount_tag(i),
                 )
             } else {
                 format!(
@@ -1320,33 +5100,165 @@ impl Node {
             sc.exec(&mut ws, cmd).await?;
             sc.exec(&mut ws, "cd".into()).await?;
             info!(
-                r.log,
-                "{}: finished mounting {}", self.name, mount.destination
+                log, "finished mounting";
+                "destination" => %mount.destination
             );
         }
 
+        // load the softnpu dataplane, without mounts the softnpu p9 device is
+        // the first p9 device of the guest, which is the one p9kp uses
+        if let Some(softnpu) = softnpu {
+            let program = softnpu.guest_program();
+            info!(log, "copying softnpu program"; "program" => %program);
+            let cmd = format!("mkdir -p {SOFTNPU_PROGRAM_DIR}");
+            sc.exec(&mut ws, cmd).await?;
+            let program_q = util::shell_quote(program.as_str());
+            let data = fs::read(&softnpu.program)?;
+            write_guest_file(&mut sc, &mut ws, &program_q, &data).await?;
+            info!(log, "loading softnpu program"; "program" => %program);
+            let cmd = format!("p9kp push {program_q}");
+            guest_run(&mut sc, &mut ws, &self.name, &cmd).await?;
+            if let (Some(config), Some(host_config)) =
+                (softnpu.guest_config(), &softnpu.config)
+            {
+                info!(log, "copying softnpu config"; "config" => %config);
+                let cmd = format!("mkdir -p {SOFTNPU_CONFIG_DIR}");
+                sc.exec(&mut ws, cmd).await?;
+                let config_q = util::shell_quote(config.as_str());
+                let data = fs::read(host_config)?;
+                write_guest_file(&mut sc, &mut ws, &config_q, &data).await?;
+                info!(log, "running softnpu config"; "config" => %config);
+                let cmd = format!("sh {config_q}");
+                guest_run(&mut sc, &mut ws, &self.name, &cmd).await?;
+            }
+        }
+
+        // configure static link addresses
+        let d = &r.deployment;
+        let mut addr_index = 0;
+        for l in &d.links {
+            for a in &l.addrs {
+                let e = &l.endpoints[a.endpoint];
+                if d.nodes[e.node.index].name != self.name {
+                    continue;
+                }
+                let nic = match d.guest_nic(e) {
+                    Some(nic) => nic,
+                    None => continue,
+                };
+                info!(
+                    log, "adding address";
+                    "addr" => %a.addr, "nic" => nic
+                );
+                let cmd = match self.guest_os {
+                    serial::GuestOs::Illumos => format!(
+                        "ipadm create-addr -T static -a {} vioif{}/static{}",
+                        a.addr, nic, addr_index,
+                    ),
+                    serial::GuestOs::Linux => linux_addr_cmd(nic, &a.addr),
+                };
+                sc.exec(&mut ws, cmd).await?;
+                addr_index += 1;
+            }
+        }
+
         // set hostname
         let cmd = format!("hostname {}", self.name);
         sc.exec(&mut ws, cmd).await?;
-        let cmd = format!("echo '{name}' > /etc/nodename", name = self.name,);
+        let nodename = match self.guest_os {
+            serial::GuestOs::Illumos => "/etc/nodename",
+            serial::GuestOs::Linux => "/etc/hostname",
+        };
+        let cmd = format!("echo '{}' > {}", self.name, nodename);
         sc.exec(&mut ws, cmd).await?;
-        let cmd = format!(
-            "echo '::1 {name}.local {name}' >> /etc/hosts",
+        // setup runs again when a node is relaunched, so only add host
+        // entries that are not there yet
+        let cmd = hosts_entry_cmd(&format!(
+            "::1 {name}.local {name}",
             name = self.name,
-        );
+        ));
         sc.exec(&mut ws, cmd).await?;
-        let cmd = format!(
-            "echo '127.0.0.1 {name}.local {name}' >> /etc/hosts",
+        let cmd = hosts_entry_cmd(&format!(
+            "127.0.0.1 {name}.local {name}",
             name = self.name,
-        );
+        ));
         sc.exec(&mut ws, cmd).await?;
+        if r.peer_hosts {
+            for (addr, peer) in d.peer_hosts(&self.name) {
+                info!(log, "adding host entry"; "peer" => &peer, "addr" => %addr);
+                let cmd = hosts_entry_cmd(&format!("{addr} {peer}"));
+                sc.exec(&mut ws, cmd).await?;
+            }
+        }
+
+        // run the user's init script, once per node disk
+        if let Some(script) = init_script {
+            let cmd = format!("test -e {INIT_DONE_MARKER}");
+            let out = sc.exec(&mut ws, serial::with_exit_status(&cmd)).await?;
+            if serial::split_exit_status(&self.name, &out)?.1 == 0 {
+                info!(log, "init script already ran");
+            } else {
+                self.run_init_script(&log, &mut sc, &mut ws, script).await?;
+            }
+        }
+
+        // log out after finishing setup
+        info!(log, "logging out");
+        sc.logout(&mut ws).await?;
+        info!(log, "logged out");
+
+        Ok(())
+    }
+
+    /// Run the init script `script` in the guest, leaving the marker that it
+    /// has run once it succeeds.
+    async fn run_init_script(
+        &self,
+        log: &Logger,
+        sc: &mut serial::SerialCommander,
+        ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+        script: &str,
+    ) -> Result<(), Error> {
+        info!(log, "running init script");
+        let path = "/tmp/falcon-init.sh";
+        write_guest_file(sc, ws, path, script.as_bytes()).await?;
+        let out = sc
+            .exec(ws, serial::with_exit_status(&format!("sh {path}")))
+            .await?;
+        let (out, rc) = serial::split_exit_status(&self.name, &out)?;
+        info!(
+            log, "init script finished";
+            "status" => rc, "output" => &out
+        );
+        if rc != 0 {
+            return Err(Error::Exec(format!(
+                "{}: init script exited with status {}",
+                self.name, rc
+            )));
+        }
+        let cmd = format!(
+            "mkdir -p $(dirname {m}) && touch {m}",
+            m = INIT_DONE_MARKER
+        );
+        guest_run(sc, ws, &self.name, &cmd).await?;
+        Ok(())
+    }
 
-        // log out after finishing setup
-        info!(r.log, "{}: logging out", self.name);
-        sc.logout(&mut ws).await?;
-        info!(r.log, "{}: logged out", self.name);
+    /// The propolis-server binary this node is launched with.
+    fn propolis_binary<'a>(&'a self, r: &'a Runner) -> &'a str {
+        self.propolis_binary
+            .as_deref()
+            .unwrap_or(&r.propolis_binary)
+    }
 
-        Ok(())
+    /// Determine whether the propolis instance for this node is running, based
+    /// on the pid recorded in the falcon directory.
+    fn is_running(&self, r: &Runner) -> bool {
+        let path = r.falcon_dir.join(format!("{}.pid", self.name));
+        match fs::read_to_string(path).map(|x| x.trim().parse::<i32>()) {
+            Ok(Ok(pid)) => unsafe { libc::kill(pid, 0) == 0 },
+            _ => false,
+        }
     }
 
     fn destroy(&self, r: &Runner) -> Result<(), Error> {
@@ -1414,11 +5326,11 @@ impl Link {
 
             // if dangling links exists, remove them
             debug!(r.log, "destroying link {}", &vlink);
-            libnet_retry(|| {
+            libnet_retry(r, &format!("deleting {vlink}"), || {
                 libnet::delete_link(&vlink_h, libnet::LinkFlags::Active)
             })?;
             debug!(r.log, "destroying link {}", &slink);
-            libnet_retry(|| {
+            libnet_retry(r, &format!("deleting {slink}"), || {
                 libnet::delete_link(&slink_h, libnet::LinkFlags::Active)
             })?;
 
@@ -1427,40 +5339,16 @@ impl Link {
 
             info!(r.log, "creating vnic link '{}'", &vlink);
 
-            let mac = if let EndpointKind::Viona(Some(mac)) = &e.kind {
-                let parts = mac.split(':');
-                let mut v = Vec::new();
-                for p in parts {
-                    let x = u8::from_str_radix(p, 16)?;
-                    v.push(x);
-                }
-                Some(v)
-            } else {
-                None
-            };
-
             libnet::create_vnic_link(
                 &vlink,
                 &slink_h,
-                mac,
+                d.vnic_mac(e)?,
                 libnet::LinkFlags::Active,
             )?;
-            let args =
-                vec!["set-linkprop", "-p", "promisc-filtered=off", &vlink];
-            match Command::new(DLADM_BIN).args(args).output() {
-                Err(e) => {
-                    return Err(Error::Exec(format!(
-                        "failed to run {DLADM_BIN}: {e:?}"
-                    )));
-                }
-                Ok(s) => {
-                    if !s.status.success() {
-                        return Err(Error::Exec(format!(
-                            "{DLADM_BIN} failed: {:?}",
-                            s.stderr
-                        )));
-                    }
-                }
+            set_linkprop(&vlink, "promisc-filtered=off")?;
+            if let Some(mbps) = self.props.bandwidth_mbps {
+                info!(r.log, "limiting link '{}' to {} Mbps", &vlink, mbps);
+                set_linkprop(&vlink, &format!("maxbw={mbps}M"))?;
             }
 
             debug!(r.log, "link pair created");
@@ -1473,6 +5361,17 @@ impl Link {
         let slink1_h = libnet::LinkHandle::Name(slink1);
         libnet::connect_simnet_peers(&slink0_h, &slink1_h)?;
 
+        if let Some(ms) = self.props.latency_ms {
+            warn!(
+                r.log,
+                "latency of {}ms requested between {} and {}, but simnet \
+                 links do not support delay, ignoring",
+                ms,
+                d.simnet_link_name(&self.endpoints[0]),
+                d.simnet_link_name(&self.endpoints[1]),
+            );
+        }
+
         Ok(())
     }
 
@@ -1486,11 +5385,11 @@ impl Link {
             let vlink_h = libnet::LinkHandle::Name(vlink.clone());
 
             info!(r.log, "destroying link {}", &vlink);
-            libnet_retry(|| {
+            libnet_retry(r, &format!("deleting {vlink}"), || {
                 libnet::delete_link(&vlink_h, libnet::LinkFlags::Active)
             })?;
             info!(r.log, "destroying link {}", &slink);
-            libnet_retry(|| {
+            libnet_retry(r, &format!("deleting {slink}"), || {
                 libnet::delete_link(&slink_h, libnet::LinkFlags::Active)
             })?;
         }
@@ -1507,14 +5406,16 @@ impl ExtLink {
 
         // destroy any dangling links
         debug!(r.log, "destroying external link {}", &vnic_name);
-        libnet_retry(|| libnet::delete_link(&vnic, libnet::LinkFlags::Active))?;
+        libnet_retry(r, &format!("deleting {vnic_name}"), || {
+            libnet::delete_link(&vnic, libnet::LinkFlags::Active)
+        })?;
 
         // create vnic
         info!(r.log, "creating external link {}", &vnic_name);
         libnet::create_vnic_link(
             &vnic_name,
             &host_ifx,
-            None,
+            r.deployment.vnic_mac(&self.endpoint)?,
             libnet::LinkFlags::Active,
         )?;
 
@@ -1530,19 +5431,87 @@ impl ExtLink {
         let vnic_name = r.deployment.vnic_link_name(&self.endpoint);
         let vnic = libnet::LinkHandle::Name(vnic_name.clone());
         info!(r.log, "destroying external link {}", &vnic_name);
-        libnet_retry(|| libnet::delete_link(&vnic, libnet::LinkFlags::Active))?;
+        libnet_retry(r, &format!("deleting {vnic_name}"), || {
+            libnet::delete_link(&vnic, libnet::LinkFlags::Active)
+        })?;
+
+        Ok(())
+    }
+}
+
+impl Bridge {
+    fn create(&self, r: &Runner, index: usize) -> Result<(), Error> {
+        let d = &r.deployment;
+        let stub = d.etherstub_name(index);
+
+        // if a dangling bridge exists, remove it
+        self.destroy(r, index)?;
+
+        info!(r.log, "creating etherstub '{}'", &stub);
+        dladm(&["create-etherstub", "-t", &stub])?;
+
+        let stub_h = libnet::LinkHandle::Name(stub);
+        for e in self.endpoints.iter() {
+            let vlink = d.vnic_link_name(e);
+            info!(r.log, "creating vnic link '{}'", &vlink);
+            libnet::create_vnic_link(
+                &vlink,
+                &stub_h,
+                d.vnic_mac(e)?,
+                libnet::LinkFlags::Active,
+            )?;
+            set_linkprop(&vlink, "promisc-filtered=off")?;
+        }
+
+        Ok(())
+    }
+
+    fn destroy(&self, r: &Runner, index: usize) -> Result<(), Error> {
+        let d = &r.deployment;
+
+        for e in self.endpoints.iter() {
+            let vlink = d.vnic_link_name(e);
+            let vlink_h = libnet::LinkHandle::Name(vlink.clone());
+            debug!(r.log, "destroying link {}", &vlink);
+            libnet_retry(r, &format!("deleting {vlink}"), || {
+                libnet::delete_link(&vlink_h, libnet::LinkFlags::Active)
+            })?;
+        }
+
+        let stub = d.etherstub_name(index);
+        if libnet::get_link(&libnet::LinkHandle::Name(stub.clone())).is_ok() {
+            info!(r.log, "destroying etherstub {}", &stub);
+            dladm(&["delete-etherstub", &stub])?;
+        }
 
         Ok(())
     }
 }
 
+/// How [`launch_vm`] starts propolis-server.
+pub(crate) struct PropolisLaunch<'a> {
+    /// The propolis-server binary to run.
+    pub binary: &'a str,
+    /// Arguments appended after the standard ones.
+    pub extra_args: &'a [String],
+    /// The `RUST_LOG` filter to run propolis-server with, if any.
+    pub log_filter: Option<&'a str>,
+    /// How long each stage of starting the instance may take.
+    pub timeout: Duration,
+    /// How the instance ensure request is retried.
+    pub ensure_retry: EnsureRetry,
+}
+
 pub(crate) async fn launch_vm(
     log: &Logger,
-    propolis_binary: &str,
     id: &uuid::Uuid,
     node: &Node,
     falcon_dir: &Utf8Path,
-) -> Result<u16, Error> {
+    propolis: &PropolisLaunch<'_>,
+) -> Result<NodeLaunch, Error> {
+    let log = log.new(slog::o!("node" => node.name.clone()));
+    let (timeout, retry) = (propolis.timeout, &propolis.ensure_retry);
+
     // launch propolis-server
 
     let mut path = falcon_dir.to_path_buf();
@@ -1555,35 +5524,60 @@ pub(crate) async fn launch_vm(
     path.push(format!("{}.toml", node.name));
     let config = path.clone();
     let sockaddr = String::from("[::]:0");
-    let mut cmd = Command::new(propolis_binary);
+    let mut cmd = Command::new(propolis.binary);
     let mut args =
         vec!["run".to_string(), config.into_string(), sockaddr.clone()];
-    if let Some(vnc_port) = node.vnc_port {
+    let vnc_port = match node.vnc_port {
+        Some(port) => Some(port),
+        None if node.vnc => Some(free_tcp_port()?),
+        None => None,
+    };
+    if let Some(vnc_port) = vnc_port {
         args.push(format!("[::]:{}", vnc_port));
+        util::write_atomic(
+            falcon_dir.join(format!("{}.vnc", node.name)),
+            vnc_port.to_string(),
+        )?;
+    }
+    args.extend_from_slice(propolis.extra_args);
+    if let Some(filter) = propolis.log_filter {
+        cmd.env("RUST_LOG", filter);
     }
     cmd.args(&args).stdout(stdout).stderr(stderr);
-    let child = cmd.spawn()?;
+    let mut child = cmd.spawn()?;
+    let pid = child.id();
     path.pop();
 
     path.push(format!("{}.pid", node.name));
-    fs::write(&path, child.id().to_string())?;
+    util::write_atomic(&path, pid.to_string())?;
     path.pop();
 
-    let port = find_propolis_port_in_log(format!(".falcon/{}.out", node.name))
-        .await
-        .map_err(|e| anyhow::anyhow!("find propolis port in log: {e}"))?;
+    let err_path = falcon_dir.join(format!("{}.err", node.name));
+    let port = tokio::select! {
+        port = find_propolis_port_in_log(
+            falcon_dir.join(format!("{}.out", node.name)).into_string(),
+            timeout,
+        ) => port.map_err(|e| {
+            propolis_start_error(
+                &node.name,
+                &err_path,
+                format!("find propolis port in log: {e}"),
+            )
+        })?,
+        status = wait_for_exit(&mut child) => {
+            return Err(propolis_start_error(
+                &node.name,
+                &err_path,
+                format!("propolis-server exited during startup: {status}"),
+            ));
+        }
+    };
 
     path.push(format!("{}.port", node.name));
-    fs::write(&path, port.to_string())?;
+    util::write_atomic(&path, port.to_string())?;
     path.pop();
 
-    info!(
-        log,
-        "launched instance {} with pid {} on port {}",
-        node.name,
-        child.id(),
-        port,
-    );
+    info!(log, "launched instance with pid {} on port {}", pid, port);
 
     let sockaddr = format!("[::1]:{}", port);
 
@@ -1599,7 +5593,7 @@ pub(crate) async fn launch_vm(
     // https://github.com/rust-lang/rust-clippy/issues/9317
     #[allow(clippy::unnecessary_to_owned)]
     path.push(format!("{}.uuid", node.name));
-    fs::write(&path, id.to_string())?;
+    util::write_atomic(&path, id.to_string())?;
     path.pop();
 
     let properties = propolis_client::types::InstanceProperties {
@@ -1610,14 +5604,10 @@ pub(crate) async fn launch_vm(
         bootrom_id: uuid::Uuid::default(),
         memory: node.memory,
         vcpus: node.cores,
-        metadata: InstanceMetadata {
-            project_id: uuid::Uuid::nil(),
-            silo_id: uuid::Uuid::nil(),
-            sled_id: uuid::Uuid::nil(),
-            sled_model: "falcon".to_owned(),
-            sled_serial: "falcon".to_owned(),
-            sled_revision: 0,
-        },
+        metadata: node
+            .instance_metadata
+            .clone()
+            .unwrap_or_else(default_instance_metadata),
     };
     let req = propolis_client::types::InstanceEnsureRequest {
         properties,
@@ -1628,26 +5618,38 @@ pub(crate) async fn launch_vm(
     };
 
     // we just launched the instance, so wait for it to become ready
-    let mut success = false;
-    for _ in 0..30 {
-        info!(log, "instance ensure: {}", node.name);
-        match client.instance_ensure().body(&req).send().await {
-            Ok(_) => {
-                success = true;
-                break;
-            }
-            Err(e) => {
-                debug!(log, "instance ensure error: {e}, retry in 1 second");
-                sleep(Duration::from_secs(1)).await;
-                continue;
-            }
+    let start = Instant::now();
+    let mut failures = Vec::new();
+    loop {
+        info!(log, "instance ensure");
+        let e = match client.instance_ensure().body(&req).send().await {
+            Ok(_) => break,
+            Err(e) => e,
+        };
+        debug!(
+            log,
+            "instance ensure error: {e}, retry in {:?}", retry.interval
+        );
+        failures.push(e.to_string());
+        let exhausted = retry
+            .attempts
+            .is_some_and(|n| failures.len() >= n.max(1) as usize);
+        if exhausted || start.elapsed() >= timeout {
+            return Err(propolis_start_error(
+                &node.name,
+                &err_path,
+                format!(
+                    "instance ensure failed after {} attempts in {:.1}s: {}",
+                    failures.len(),
+                    start.elapsed().as_secs_f64(),
+                    summarize_failures(&failures),
+                ),
+            ));
         }
-    }
-    if !success {
-        client.instance_ensure().body(&req).send().await?;
+        sleep(retry.interval).await;
     }
 
-    info!(log, "instance run: {}", node.name);
+    info!(log, "instance run");
     // run vm instance
     client
         .instance_state_put()
@@ -1655,7 +5657,125 @@ pub(crate) async fn launch_vm(
         .send()
         .await?;
 
-    Ok(port)
+    Ok(NodeLaunch {
+        uuid: *id,
+        port,
+        pid,
+        vnc_port,
+    })
+}
+
+/// The default for how long each stage of starting a propolis instance may
+/// take.
+pub(crate) fn launch_timeout() -> Duration {
+    match std::env::var("FALCON_LAUNCH_TIMEOUT").map(|s| s.parse::<u64>()) {
+        Ok(Ok(secs)) => Duration::from_secs(secs),
+        _ => DEFAULT_LAUNCH_TIMEOUT,
+    }
+}
+
+/// The git revision in the output of `propolis-server -V`, if it reports
+/// one. This is the first word of 7 to 40 hex digits with at least one letter,
+/// so dates and plain version numbers are not mistaken for one, e.g. `fae5334`
+/// in `propolis-server 0.1.0 (fae5334)`.
+pub(crate) fn propolis_version_rev(version: &str) -> Option<&str> {
+    version
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .find(|x| {
+            (7..=40).contains(&x.len())
+                && x.chars().all(|c| c.is_ascii_hexdigit())
+                && x.chars().any(|c| c.is_ascii_alphabetic())
+        })
+}
+
+/// A TCP port that is currently free, found by binding to port 0 and letting
+/// the system pick one. The port is released again before this returns, so
+/// it is only very likely, not guaranteed, to still be free when used.
+fn free_tcp_port() -> Result<u16, Error> {
+    let listener = std::net::TcpListener::bind("[::]:0")?;
+    Ok(listener.local_addr()?.port())
+}
+
+/// Parse a MAC address of six colon separated hex octets, e.g.
+/// `a8:40:25:00:00:01`.
+pub(crate) fn parse_mac(s: &str) -> Result<Vec<u8>, Error> {
+    let mac = s
+        .split(':')
+        .map(|x| u8::from_str_radix(x, 16))
+        .collect::<Result<Vec<u8>, _>>()?;
+    if mac.len() != 6 {
+        return Err(Error::Exec(format!("mac {s} does not have 6 octets")));
+    }
+    Ok(mac)
+}
+
+/// The instance metadata of nodes that were not given any.
+fn default_instance_metadata() -> InstanceMetadata {
+    InstanceMetadata {
+        project_id: uuid::Uuid::nil(),
+        silo_id: uuid::Uuid::nil(),
+        sled_id: uuid::Uuid::nil(),
+        sled_model: "falcon".to_owned(),
+        sled_serial: "falcon".to_owned(),
+        sled_revision: 0,
+    }
+}
+
+/// Whether progress bars are drawn by default.
+fn progress() -> bool {
+    std::env::var_os("FALCON_NO_PROGRESS").is_none()
+        && std::io::stdout().is_terminal()
+}
+
+/// Whether downloaded base images are verified by default.
+fn verify_images() -> bool {
+    std::env::var_os("FALCON_NO_VERIFY").is_none()
+}
+
+/// The default URL assets are downloaded from.
+pub(crate) fn asset_base_url() -> String {
+    match std::env::var("FALCON_ASSET_BASE_URL") {
+        Ok(s) if !s.is_empty() => s,
+        _ => DEFAULT_ASSET_BASE_URL.to_string(),
+    }
+}
+
+/// The URL of the asset `name` under `base`.
+fn asset_url(base: &str, name: &str) -> String {
+    format!("{}/{}", base.trim_end_matches('/'), name)
+}
+
+/// Parse the JSON report of an `iperf3 -J` client run on `node`.
+pub(crate) fn parse_iperf3(
+    node: &str,
+    out: &str,
+) -> Result<ThroughputReport, Error> {
+    let bad = |what: &str| Error::Exec(format!("{node}: iperf3: {what}"));
+    let report: serde_json::Value = serde_json::from_str(out.trim())
+        .map_err(|e| bad(&format!("bad report: {e}")))?;
+    if let Some(e) = report["error"].as_str() {
+        return Err(bad(e));
+    }
+    let end = &report["end"];
+    let mbps = |sum: &str| {
+        end[sum]["bits_per_second"]
+            .as_f64()
+            .map(|bps| bps / 1e6)
+            .ok_or_else(|| bad(&format!("no {sum} in report")))
+    };
+    Ok(ThroughputReport {
+        sent_mbps: mbps("sum_sent")?,
+        received_mbps: mbps("sum_received")?,
+        retransmits: end["sum_sent"]["retransmits"].as_u64(),
+    })
+}
+
+/// Parse an IPv6 address as printed by `ipadm show-addr -p -o addr`, e.g.
+/// `fe80::8:20ff:fe35:1f12%vioif0/10`, without its zone and prefix length.
+pub(crate) fn parse_ipadm_v6(s: &str) -> Option<Ipv6Addr> {
+    let addr = s.trim().replace('\\', "");
+    let addr = addr.split('/').next()?;
+    addr.split('%').next()?.parse().ok()
 }
 
 pub(crate) fn dataset() -> String {
@@ -1665,32 +5785,592 @@ pub(crate) fn dataset() -> String {
     }
 }
 
-fn libnet_retry<F>(f: F) -> Result<(), Error>
+/// The default directory file backed disks are created in.
+pub(crate) fn file_dir() -> String {
+    match std::env::var("FALCON_FILE_DIR") {
+        Ok(s) if !s.is_empty() => s,
+        _ => DEFAULT_FILE_DIR.to_string(),
+    }
+}
+
+/// Write `data` to the guest file `path`, which must already be shell quoted,
+/// over a logged in serial console session. Any existing file is replaced.
+async fn write_guest_file(
+    sc: &mut serial::SerialCommander,
+    ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+    path: &str,
+    data: &[u8],
+) -> Result<(), Error> {
+    sc.exec(ws, format!("rm -f {path}")).await?;
+    for chunk in data.chunks(PUSH_CHUNK_SIZE) {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(chunk);
+        sc.exec(ws, format!("echo {encoded} | base64 -d >> {path}"))
+            .await?;
+    }
+    // an empty file still needs to be created
+    sc.exec(ws, format!("touch {path}")).await?;
+    Ok(())
+}
+
+/// Run `cmd` over a logged in serial console session, failing if it exits
+/// non-zero. Returns the output of the command.
+async fn guest_run(
+    sc: &mut serial::SerialCommander,
+    ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+    node: &str,
+    cmd: &str,
+) -> Result<String, Error> {
+    let out = sc.exec(ws, serial::with_exit_status(cmd)).await?;
+    let (out, rc) = serial::split_exit_status(node, &out)?;
+    if rc != 0 {
+        return Err(Error::Exec(format!(
+            "{node}: `{cmd}` exited with status {rc}: {out}"
+        )));
+    }
+    Ok(out)
+}
+
+/// A guest command printing the hex sha256 digest of `path`, which must already
+/// be shell quoted. Linux guests typically have `sha256sum`, illumos guests have
+/// `digest`.
+fn guest_sha256_cmd(path: &str) -> String {
+    format!(
+        "(sha256sum {path} 2>/dev/null || digest -a sha256 {path}) | \
+         cut -d' ' -f1"
+    )
+}
+
+/// Compare the sha256 digest of `data` with a digest reported by a guest.
+fn check_digest(
+    node: &str,
+    path: &Utf8Path,
+    data: &[u8],
+    guest_digest: &str,
+) -> Result<(), Error> {
+    let digest = hex_digest(&Sha256::digest(data));
+    if digest != guest_digest.trim() {
+        return Err(Error::Exec(format!(
+            "{node}: {path}: digest mismatch: local {digest}, guest {}",
+            guest_digest.trim()
+        )));
+    }
+    Ok(())
+}
+
+fn hex_digest(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// The hex sha256 digest of the file at `path`. The file is streamed through
+/// the hasher, images are too large to read into memory.
+fn file_sha256(path: &str) -> Result<String, Error> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hex_digest(&hasher.finalize()))
+}
+
+/// Parse a sha256 digest file as written by `sha256sum` or `digest -a sha256`:
+/// a hex digest, optionally followed by a file name.
+pub(crate) fn parse_sha256_file(s: &str) -> Result<String, Error> {
+    let digest = s.split_whitespace().next().unwrap_or_default();
+    if digest.len() != 64 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::Exec(format!("malformed sha256 digest: {s:?}")));
+    }
+    Ok(digest.to_ascii_lowercase())
+}
+
+/// Ask the propolis instance of the named node to stop, and wait up to
+/// `timeout` for it to do so. Returns whether the instance stopped. Failing to
+/// reach the instance is logged and counts as not stopped, so callers fall
+/// back to killing it.
+pub(crate) async fn graceful_stop(
+    log: &Logger,
+    name: &str,
+    falcon_dir: &Utf8Path,
+    timeout: Duration,
+) -> bool {
+    let log = log.new(slog::o!("node" => name.to_string()));
+    let port: u16 =
+        match fs::read_to_string(falcon_dir.join(format!("{name}.port")))
+            .map(|p| p.trim_end().parse())
+        {
+            Ok(Ok(port)) => port,
+            _ => {
+                warn!(log, "no propolis port, not stopping gracefully");
+                return false;
+            }
+        };
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+    let client = propolis_client::Client::new(&format!("http://{}", addr));
+
+    info!(log, "stopping instance");
+    if let Err(e) = client
+        .instance_state_put()
+        .body(propolis_client::types::InstanceStateRequested::Stop)
+        .send()
+        .await
+    {
+        warn!(log, "instance stop: {}", e);
+        return false;
+    }
+
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if let Ok(r) = client.instance_get().send().await {
+            if matches!(
+                r.into_inner().instance.state,
+                propolis_client::types::InstanceState::Stopped
+                    | propolis_client::types::InstanceState::Destroyed
+            ) {
+                info!(log, "instance stopped");
+                return true;
+            }
+        }
+        sleep(Duration::from_secs(1)).await;
+    }
+
+    warn!(
+        log,
+        "instance did not stop within {:?}, killing it", timeout
+    );
+    false
+}
+
+/// Stop the propolis instance of the named node and destroy its bhyve vm.
+/// Missing pid or uuid files are logged and otherwise ignored, so stopping a
+/// node that is not running is not an error.
+pub(crate) fn hyperstop(
+    log: &Logger,
+    name: &str,
+    falcon_dir: &Utf8Path,
+) -> Result<(), Error> {
+    let log = log.new(slog::o!("node" => name.to_string()));
+    let mut path = falcon_dir.to_path_buf();
+    path.push(format!("{name}.pid"));
+
+    // read pid
+    match fs::read_to_string(&path) {
+        Ok(pid) => match pid.trim_end().parse() {
+            Ok(pid) => {
+                unsafe {
+                    libc::kill(pid, libc::SIGKILL);
+                }
+                fs::remove_file(&path)?;
+            }
+            Err(e) => warn!(log, "could not parse pidfile: {}", e),
+        },
+        Err(e) => {
+            warn!(log, "could not get pidfile: {}", e);
+        }
+    };
+    path.pop();
+
+    // get instance uuid
+    path.push(format!("{name}.uuid"));
+    let uuid = match fs::read_to_string(&path) {
+        Ok(u) => u,
+        Err(e) => {
+            warn!(log, "get propolis uuid: {}", e);
+            return Ok(());
+        }
+    };
+
+    // destroy bhyve vm
+    let vm_arg = format!("--vm={}", uuid);
+    if let Err(e) = Command::new("bhyvectl")
+        .args(["--destroy", vm_arg.as_ref()])
+        .output()
+    {
+        warn!(log, "delete bhyve vm: {}", e);
+    }
+
+    Ok(())
+}
+
+/// Parse the output of `zfs list -H -p -o name,referenced,creation -t snapshot`
+/// for the image dataset `img` into the images it has a base snapshot of.
+pub(crate) fn parse_image_list(img: &str, out: &str) -> Vec<ImageInfo> {
+    let prefix = format!("{img}/");
+    let mut result = Vec::new();
+    for line in out.lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 3 {
+            continue;
+        }
+        let name = match fields[0]
+            .strip_prefix(&prefix)
+            .and_then(|x| x.strip_suffix("@base"))
+        {
+            Some(name) if !name.contains('/') => name,
+            _ => continue,
+        };
+        let (size, created) = match (fields[1].parse(), fields[2].parse()) {
+            (Ok(size), Ok(created)) => (size, created),
+            _ => continue,
+        };
+        result.push(ImageInfo {
+            name: name.to_string(),
+            size,
+            created: std::time::UNIX_EPOCH + Duration::from_secs(created),
+            used: false,
+        });
+    }
+    result
+}
+
+/// Parse the output of `zfs list -H -o clones -t snapshot`, which has the
+/// comma separated clones of each snapshot on its own line, or `-` for a
+/// snapshot without clones.
+pub(crate) fn parse_zfs_clones(out: &str) -> Vec<String> {
+    out.lines()
+        .flat_map(|x| x.trim().split(','))
+        .filter(|x| !x.is_empty() && *x != "-")
+        .map(String::from)
+        .collect()
+}
+
+/// The p9fs tag of the i'th mount of a node.
+fn mount_tag(i: usize) -> String {
+    format!("fs{}", i)
+}
+
+/// Set a property on a data link with dladm.
+fn set_linkprop(link: &str, prop: &str) -> Result<(), Error> {
+    dladm(&["set-linkprop", "-p", prop, link])
+}
+
+/// Run dladm with the given arguments.
+fn dladm(args: &[&str]) -> Result<(), Error> {
+    match Command::new(DLADM_BIN).args(args).output() {
+        Err(e) => Err(Error::Exec(format!("failed to run {DLADM_BIN}: {e:?}"))),
+        Ok(s) => {
+            if !s.status.success() {
+                return Err(Error::Exec(format!(
+                    "{DLADM_BIN} {} failed: {}",
+                    args.join(" "),
+                    String::from_utf8_lossy(&s.stderr).trim()
+                )));
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Pick the links falcon creates, `{deployment}_{node}_{vn|sn|sm}_{vnic|sim}N`
+/// and `{deployment}_stubN`, and the datasets under `topo` that do not belong
+/// to one of the `known` deployments. Deployment names may contain
+/// underscores, so a link is taken to belong to every deployment whose name
+/// followed by `_` is a prefix of it.
+pub(crate) fn find_garbage<'a>(
+    links: impl Iterator<Item = &'a str>,
+    datasets: impl Iterator<Item = &'a str>,
+    topo: &str,
+    known: &BTreeSet<String>,
+) -> Garbage {
+    let re = regex::Regex::new(r"^\w+_(?:(?:vn|sn|sm)_(vnic|sim)|stub)[0-9]+$")
+        .unwrap();
+    let owned =
+        |name: &str| known.iter().any(|d| name.starts_with(&format!("{d}_")));
+
+    let mut vnics = Vec::new();
+    let mut simnets = Vec::new();
+    let mut etherstubs = Vec::new();
+    for link in links.map(str::trim) {
+        let caps = match re.captures(link) {
+            Some(caps) => caps,
+            None => continue,
+        };
+        if owned(link) {
+            continue;
+        }
+        match caps.get(1).map(|x| x.as_str()) {
+            Some("vnic") => vnics.push(link.to_string()),
+            Some(_) => simnets.push(link.to_string()),
+            None => etherstubs.push(link.to_string()),
+        }
+    }
+
+    let prefix = format!("{topo}/");
+    let datasets = datasets
+        .map(str::trim)
+        .filter_map(|x| Some((x, x.strip_prefix(&prefix)?)))
+        .filter(|(_, d)| !d.is_empty() && !d.contains('/'))
+        .filter(|(_, d)| !known.contains(*d))
+        .map(|(x, _)| x.to_string())
+        .collect();
+
+    Garbage {
+        vnics,
+        simnets,
+        etherstubs,
+        datasets,
+    }
+}
+
+/// Memory propolis-server and the hypervisor need for each node on top of the
+/// guest memory, in MB.
+pub const NODE_MEMORY_OVERHEAD_MB: u64 = 256;
+
+/// Free physical memory of the host in MB. The ZFS ARC gives memory back
+/// when it is needed, down to its minimum size, so that part of it counts as
+/// free.
+fn host_free_memory_mb() -> Option<u64> {
+    let pages = unsafe { libc::sysconf(libc::_SC_AVPHYS_PAGES) };
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if pages < 0 || size < 0 {
+        return None;
+    }
+    let arc = Command::new(KSTAT_BIN)
+        .args(["-p", "zfs:0:arcstats:size", "zfs:0:arcstats:c_min"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| {
+            parse_arc_reclaimable(&String::from_utf8_lossy(&out.stdout))
+        })
+        .unwrap_or(0);
+    Some((pages as u64 * size as u64 + arc) / (1 << 20))
+}
+
+/// Parse the output of `kstat -p zfs:0:arcstats:size zfs:0:arcstats:c_min`
+/// into the bytes the ARC can give back.
+pub(crate) fn parse_arc_reclaimable(out: &str) -> Option<u64> {
+    let mut size = None;
+    let mut min = None;
+    for line in out.lines() {
+        let mut fields = line.split_whitespace();
+        let (stat, value) = match (fields.next(), fields.next()) {
+            (Some(stat), Some(value)) => (stat, value.parse::<u64>().ok()),
+            _ => continue,
+        };
+        if stat.ends_with(":size") {
+            size = value;
+        } else if stat.ends_with(":c_min") {
+            min = value;
+        }
+    }
+    Some(size?.saturating_sub(min?))
+}
+
+/// Fail if `required` MB of memory does not fit in `available` MB.
+pub(crate) fn check_memory(required: u64, available: u64) -> Result<(), Error> {
+    if required > available {
+        return Err(Error::Exec(format!(
+            "nodes need {required} MB of memory but the host has only \
+             {available} MB free, use overcommit to launch anyway"
+        )));
+    }
+    Ok(())
+}
+
+/// Parse the output of `ps -eo pid,args` into the pid and config file of each
+/// propolis-server process that looks like it was launched by falcon, i.e. with
+/// the arguments `run {falcon_dir}/{node}.toml ...`.
+pub(crate) fn parse_propolis_processes(out: &str) -> Vec<(i32, Utf8PathBuf)> {
+    let mut result = Vec::new();
+    for line in out.lines() {
+        let mut fields = line.split_whitespace();
+        let pid = match fields.next().and_then(|x| x.parse::<i32>().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        let args: Vec<&str> = fields.collect();
+        if args.len() < 3 {
+            continue;
+        }
+        let is_propolis = Utf8Path::new(args[0])
+            .file_name()
+            .map(|x| x.starts_with("propolis-server"))
+            .unwrap_or(false);
+        if is_propolis && args[1] == "run" && args[2].ends_with(".toml") {
+            result.push((pid, Utf8PathBuf::from(args[2])));
+        }
+    }
+    result
+}
+
+/// Get the current working directory of a process.
+fn process_cwd(pid: i32) -> Option<Utf8PathBuf> {
+    let out = Command::new(PWDX_BIN).arg(pid.to_string()).output().ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    // pwdx output is of the form `{pid}: {cwd}`
+    let out = String::from_utf8(out.stdout).ok()?;
+    let (_, cwd) = out.trim().split_once(": ")?;
+    Some(cwd.into())
+}
+
+/// Parse the output of `ipadm show-addr -po addrobj,addr`. In parsable output
+/// colons within a field are escaped with a backslash, e.g.
+///
+/// ```text
+/// lo0/v4:127.0.0.1/8
+/// vioif0/v6:fe80\:\:8\:20ff\:fe3b\:1f9a/10
+/// ```
+pub(crate) fn parse_illumos_addrs(out: &str) -> Vec<IfAddr> {
+    let mut result = Vec::new();
+    for line in out.lines() {
+        let (addrobj, addr) = match line.trim().split_once(':') {
+            Some(x) => x,
+            None => continue,
+        };
+        let ifname = match addrobj.split_once('/') {
+            Some((ifname, _)) => ifname,
+            None => addrobj,
+        };
+        let addr = addr.replace("\\:", ":");
+        if let Some(addr) = parse_ifaddr(&addr) {
+            result.push(IfAddr {
+                ifname: ifname.to_string(),
+                addr,
+            });
+        }
+    }
+    result
+}
+
+/// The command appending `entry` to `/etc/hosts` unless it is already there.
+pub(crate) fn hosts_entry_cmd(entry: &str) -> String {
+    let entry = util::shell_quote(entry);
+    format!("grep -qxF {entry} /etc/hosts || echo {entry} >> /etc/hosts")
+}
+
+/// The command adding the static address `addr` to the `nic`'th virtio NIC of
+/// a Linux guest. Linux names NICs after their PCI slot, so the interface is
+/// looked up through the virtio devices, which are numbered in PCI order.
+pub(crate) fn linux_addr_cmd(
+    nic: usize,
+    addr: &impl std::fmt::Display,
+) -> String {
+    let nics = "/sys/bus/virtio/drivers/virtio_net/virtio*/net/*";
+    format!(
+        "ifname=$(basename $(ls -d {nics} | sort -V | sed -n '{}p')) && \
+         ip link set $ifname up && ip addr add {addr} dev $ifname",
+        nic + 1,
+    )
+}
+
+/// Parse the output of `ip -o addr show`, e.g.
+///
+/// ```text
+/// 1: lo    inet 127.0.0.1/8 scope host lo\       valid_lft forever ...
+/// 2: enp0s8    inet6 fe80::8:20ff:fe3b:1f9a/64 scope link \       valid_lft ...
+/// ```
+pub(crate) fn parse_linux_addrs(out: &str) -> Vec<IfAddr> {
+    let mut result = Vec::new();
+    for line in out.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 || !matches!(fields[2], "inet" | "inet6") {
+            continue;
+        }
+        // Interfaces such as veths are displayed as `name@peer`.
+        let ifname = match fields[1].split_once('@') {
+            Some((ifname, _)) => ifname,
+            None => fields[1],
+        };
+        if let Some(addr) = parse_ifaddr(fields[3]) {
+            result.push(IfAddr {
+                ifname: ifname.to_string(),
+                addr,
+            });
+        }
+    }
+    result
+}
+
+/// Parse an `addr/prefix` string, dropping any `%zone` suffix on the address.
+fn parse_ifaddr(s: &str) -> Option<IpNet> {
+    let (addr, prefix) = s.split_once('/')?;
+    let addr = match addr.split_once('%') {
+        Some((addr, _)) => addr,
+        None => addr,
+    };
+    IpNet::new(addr.parse().ok()?, prefix.parse().ok()?).ok()
+}
+
+/// Run the link operation `f`, described by `what` in logs and errors, retrying
+/// failures as configured by [`Runner::set_libnet_retry`].
+fn libnet_retry<F>(r: &Runner, what: &str, f: F) -> Result<(), Error>
 where
     F: Fn() -> Result<(), libnet::Error>,
 {
-    for _ in 0..30 {
-        if f().is_ok() {
-            return Ok(());
+    let attempts = r.libnet_retry.attempts.max(1);
+    let mut attempt = 0;
+    loop {
+        let e = match f() {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        attempt += 1;
+        debug!(r.log, "{what}: attempt {attempt}/{attempts} failed: {e}");
+        if attempt >= attempts {
+            return Err(Error::LibnetRetry(what.into(), attempts, e));
+        }
+        std::thread::sleep(r.libnet_retry.backoff(attempt - 1));
+    }
+}
+
+/// Summarize a sequence of error messages, collapsing runs of the same message
+/// into one with a count, e.g. `connection refused (x12), then 500 Internal
+/// Server Error (x3)`.
+pub(crate) fn summarize_failures(failures: &[String]) -> String {
+    let mut runs: Vec<(&str, usize)> = Vec::new();
+    for f in failures {
+        match runs.last_mut() {
+            Some((msg, n)) if *msg == f.as_str() => *n += 1,
+            _ => runs.push((f.as_str(), 1)),
+        }
+    }
+    runs.iter()
+        .map(|(msg, n)| match n {
+            1 => msg.to_string(),
+            n => format!("{msg} (x{n})"),
+        })
+        .collect::<Vec<_>>()
+        .join(", then ")
+}
+
+/// Build an error for a propolis instance that failed to start, including the
+/// tail of the instance's stderr log, which is where propolis reports usage
+/// errors and panics.
+fn propolis_start_error(node: &str, err_path: &Utf8Path, msg: String) -> Error {
+    let stderr = fs::read_to_string(err_path).unwrap_or_default();
+    let lines: Vec<&str> = stderr.lines().collect();
+    let tail =
+        lines[lines.len().saturating_sub(PROPOLIS_ERR_TAIL_LINES)..].join("\n");
+    if tail.is_empty() {
+        Error::Exec(format!("{node}: {msg}"))
+    } else {
+        Error::Exec(format!("{node}: {msg}\n{err_path}:\n{tail}"))
+    }
+}
+
+/// Wait for a child process to exit, returning its exit status.
+async fn wait_for_exit(
+    child: &mut std::process::Child,
+) -> std::process::ExitStatus {
+    loop {
+        if let Ok(Some(status)) = child.try_wait() {
+            return status;
         }
-        std::thread::sleep(Duration::from_secs(1));
+        sleep(Duration::from_millis(100)).await;
     }
-    Ok(f()?)
 }
 
 async fn find_propolis_port_in_log(
     logfile: String,
+    timeout: Duration,
 ) -> Result<u16, anyhow::Error> {
-    let timeout = Instant::now() + Duration::from_secs(10);
-    let port =
-        tokio::time::timeout_at(timeout, do_find_propolis_port_in_log(logfile))
-            .await?
-            .map_err(|e| {
-                anyhow::anyhow!(
-                    "timed out waiting to find propolis port in its log: {e}"
-                )
-            })?;
-    Ok(port)
+    tokio::time::timeout(timeout, do_find_propolis_port_in_log(logfile))
+        .await
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "timed out after {}s waiting to find propolis port in its log",
+                timeout.as_secs_f64(),
+            )
+        })?
 }
 
 async fn do_find_propolis_port_in_log(
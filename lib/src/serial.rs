@@ -5,16 +5,23 @@
 // Copyright 2022 Oxide Computer Company
 
 use crate::error::Error;
+use camino::Utf8PathBuf;
 use futures::{SinkExt, StreamExt};
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 use slog::{debug, trace, warn, Logger};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
 use std::net::SocketAddr;
+use std::sync::OnceLock;
 use tokio::net::TcpStream;
 use tokio::time::timeout;
-use tokio::time::{sleep, Duration};
+use tokio::time::{sleep, Duration, Instant};
 use tokio_tungstenite::{
     connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream,
 };
+use uuid::Uuid;
 
 pub enum State {
     Empty,
@@ -24,19 +31,141 @@ pub enum State {
     Executing,
 }
 
+/// How the end of a command's output is detected when executing commands over
+/// the serial console.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub enum ExecStrategy {
+    /// Set `PROMPT_COMMAND` at login to echo a marker every time the prompt
+    /// is displayed. This requires a shell that honors `PROMPT_COMMAND` such
+    /// as bash.
+    #[default]
+    PromptCommand,
+    /// Append `; echo <marker>` to each command. This works with any POSIX
+    /// shell.
+    MarkerEcho,
+    /// Send the command on its own, followed by a separate `echo <marker>`
+    /// command line. This works with shells that cannot chain commands.
+    SingleCommand,
+}
+
+/// Where in the buffered console history a serial connection starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConsoleOffset {
+    /// Start this many bytes from the start of the history, replaying
+    /// buffered output such as boot messages.
+    FromStart(u64),
+    /// Start this many bytes before the most recent output.
+    MostRecent(u64),
+}
+
+impl ConsoleOffset {
+    fn query(&self) -> String {
+        match self {
+            ConsoleOffset::FromStart(n) => format!("from_start={n}"),
+            ConsoleOffset::MostRecent(n) => format!("most_recent={n}"),
+        }
+    }
+}
+
+/// The serial console websocket URL of the propolis server at `addr`. Without
+/// an offset propolis starts at the most recent output.
+pub fn console_url(addr: SocketAddr, offset: Option<ConsoleOffset>) -> String {
+    match offset {
+        Some(offset) => {
+            format!("ws://{}/instance/serial?{}", addr, offset.query())
+        }
+        None => format!("ws://{}/instance/serial", addr),
+    }
+}
+
+/// The operating system of a guest, which determines how it is logged into and
+/// set up at first boot.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub enum GuestOs {
+    #[default]
+    Illumos,
+    Linux,
+}
+
+/// Credentials used to log into the serial console.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Credentials {
+    pub username: String,
+    /// The password to log in with. `None` is an empty password, and also
+    /// covers consoles that do not prompt for a password at all.
+    pub password: Option<String>,
+}
+
+impl Default for Credentials {
+    fn default() -> Self {
+        Credentials {
+            username: "root".into(),
+            password: None,
+        }
+    }
+}
+
 pub struct SerialCommander {
     pub addr: SocketAddr,
     pub instance: String,
     pub name: String,
     pub state: State,
+    /// Whether to strip ANSI escape sequences and other control characters
+    /// from the output of executed commands.
+    pub strip_ansi: bool,
+    /// How to detect the end of executed commands.
+    pub exec_strategy: ExecStrategy,
+    /// File everything received from the serial console is appended to. Once
+    /// the file grows beyond [`CONSOLE_LOG_MAX`] bytes it is moved aside to
+    /// `<file>.1`, replacing any previous one, and a new file is started.
+    pub console_log: Option<Utf8PathBuf>,
+    /// Credentials to log in with, root with an empty password by default.
+    pub credentials: Credentials,
+    /// The operating system of the guest, illumos by default.
+    pub guest_os: GuestOs,
+    /// Environment variables exported after logging in.
+    pub env: BTreeMap<String, String>,
+    /// Where in the console history to start when connecting, the most
+    /// recent output by default.
+    pub console_offset: Option<ConsoleOffset>,
+    /// How long to wait for each prompt while logging in.
+    pub login_timeout: Duration,
+    /// How many more times to try logging in, coaxing a new login prompt with
+    /// ENTER, when a prompt does not appear within `login_timeout`.
+    pub login_retries: u32,
+    /// Patterns indicating the guest has panicked. When one matches, the
+    /// console output that follows is captured for [`PANIC_CAPTURE_MS`] and
+    /// returned as [`Error::GuestPanic`]. Empty by default, which disables
+    /// panic detection.
+    pub panic_patterns: Vec<Regex>,
+    /// Marker printed after each command to detect its end. This is unique to
+    /// the session so command output cannot collide with it.
+    eoc_detector: String,
     eoc_regex: Regex,
     login_prompt_regex: Regex,
     log: Logger,
 }
 
-const EOC_DETECTOR: &str = "__FALCON_EXEC_FINISHED__";
+const RC_MARKER: &str = "__FALCON_RC__";
 const ENTER: u8 = 0x0d;
-const USERNAME: &[u8] = "root".as_bytes();
+const ETX: u8 = 0x03;
+
+/// The size at which console logs are rotated.
+pub const CONSOLE_LOG_MAX: u64 = 16 * 1024 * 1024;
+
+/// The default time to wait for each prompt while logging in.
+pub const LOGIN_TIMEOUT_MS: u64 = 10000;
+
+/// Markers the illumos kernel prints to the console when it panics.
+pub const DEFAULT_PANIC_PATTERNS: &[&str] =
+    &[r"panic\[cpu\d+\]", r"dumping to "];
+
+/// How long console output is captured after a panic is detected.
+pub const PANIC_CAPTURE_MS: u64 = 2000;
 
 impl SerialCommander {
     pub fn new(
@@ -45,32 +174,47 @@ impl SerialCommander {
         name: String,
         log: Logger,
     ) -> SerialCommander {
-        let eoc_regex = Regex::new(&format!("(?mR){EOC_DETECTOR}")).unwrap();
+        let eoc_detector =
+            format!("__FALCON_EXEC_FINISHED_{}__", Uuid::new_v4().simple());
+        let eoc_regex = Regex::new(&format!("(?mR){eoc_detector}")).unwrap();
         let login_prompt_regex = Regex::new("login:").unwrap();
         SerialCommander {
             addr,
             instance,
+            log: log.new(slog::o!("node" => name.clone())),
             name,
-            log,
             state: State::Empty,
+            strip_ansi: true,
+            exec_strategy: ExecStrategy::default(),
+            console_log: None,
+            credentials: Credentials::default(),
+            guest_os: GuestOs::default(),
+            env: BTreeMap::new(),
+            console_offset: None,
+            login_timeout: Duration::from_millis(LOGIN_TIMEOUT_MS),
+            login_retries: 0,
+            panic_patterns: Vec::new(),
+            eoc_detector,
             eoc_regex,
             login_prompt_regex,
         }
     }
 
+    /// Connect to the serial console websocket of the instance, retrying for
+    /// up to 30 seconds while propolis comes up.
     pub async fn connect(
         &mut self,
     ) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, Error> {
         self.state = State::Connecting;
-        let path = format!("ws://{}/instance/serial", self.addr);
+        let path = console_url(self.addr, self.console_offset);
 
-        debug!(self.log, "[sc] {}: connecting to {}", self.name, path);
+        debug!(self.log, "[sc] connecting to {}", path);
 
         for _ in 0..30 {
             match connect_async(path.clone()).await {
                 Ok((ws, _)) => return Ok(ws),
                 Err(e) => {
-                    warn!(self.log, "[sc] {}: {}", self.name, e);
+                    warn!(self.log, "[sc] {}", e);
                     sleep(Duration::from_secs(1)).await;
                     continue;
                 }
@@ -81,11 +225,15 @@ impl SerialCommander {
         Ok(ws)
     }
 
+    /// Connect to the serial console, wait for a login prompt and log in
+    /// with `credentials`, preparing the shell for `exec`. Unless
+    /// `coax_prompt` is set, the login prompt must appear on its own, e.g. on
+    /// first boot.
     pub async fn start(
         &mut self,
         coax_prompt: bool,
     ) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, Error> {
-        debug!(self.log, "[sc] {}: starting", self.name);
+        debug!(self.log, "[sc] starting");
 
         let mut ws = self.connect().await?;
         self.wait_for_login_prompt(&mut ws, coax_prompt).await?;
@@ -94,12 +242,26 @@ impl SerialCommander {
         Ok(ws)
     }
 
-    async fn wait_for_login_prompt(
+    /// Connect to the serial console without waiting for a login prompt or
+    /// logging in. The caller drives the console from here, e.g. a custom
+    /// handshake with `drain_match` and the websocket, or `login` followed by
+    /// `exec` once a prompt is up.
+    pub async fn start_raw(
+        &mut self,
+    ) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, Error> {
+        debug!(self.log, "[sc] starting raw");
+        let ws = self.connect().await?;
+        Ok(ws)
+    }
+
+    /// Wait for a login prompt, sending ENTER first to coax one if
+    /// `coax_prompt` is set.
+    pub async fn wait_for_login_prompt(
         &mut self,
         ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
         coax_prompt: bool,
     ) -> Result<(), Error> {
-        debug!(self.log, "[sc] {} waiting for prompt", self.name);
+        debug!(self.log, "[sc] waiting for prompt");
 
         let timeout = None;
         if coax_prompt {
@@ -111,47 +273,41 @@ impl SerialCommander {
         Ok(())
     }
 
-    pub(crate) async fn login(
+    /// Log in at a login prompt and prepare the shell for `exec`.
+    pub async fn login(
         &mut self,
         ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
     ) -> Result<(), Error> {
-        debug!(self.log, "[sc] {}: logging in", self.name);
-
-        let timeout = Some(10000);
-
-        // Send username and wait for password prompt
-        trace!(
-            self.log,
-            "[sc] {}: injecting username at expected password prompt",
-            self.name
-        );
-        let mut v = Vec::from(USERNAME);
-        v.push(ENTER);
-        ws.send(Message::binary(v)).await?;
+        debug!(self.log, "[sc] logging in");
 
-        // Some systems (such as our debian 11 image) don't take passwords.
-        // In that case, we also accept a root prompt.
-        self.drain_match(
-            ws,
-            timeout,
-            Regex::new(r"Password:|root@.+#").unwrap(),
-        )
-        .await?;
+        let timeout = Some(self.login_timeout.as_millis() as u64);
 
-        // Send empty password and wait for prompt
-        trace!(
-            self.log,
-            "[sc] {}: Sending empty password after expected password prompt",
-            self.name
-        );
-        let v = vec![ENTER];
-        ws.send(Message::binary(v)).await?;
-        self.drain_match(ws, timeout, Regex::new(r"root@.+#").unwrap())
-            .await?;
+        let mut attempt = 0;
+        while let Err(e) = self.enter_credentials(ws, timeout).await {
+            if attempt >= self.login_retries {
+                return Err(e);
+            }
+            attempt += 1;
+            warn!(
+                self.log,
+                "[sc] login failed, retrying ({}/{}): {}",
+                attempt,
+                self.login_retries,
+                e
+            );
+            // coax a fresh login prompt
+            ws.send(Message::binary(vec![ENTER, ENTER])).await?;
+            if let Err(e) = self
+                .drain_match(ws, timeout, self.login_prompt_regex.clone())
+                .await
+            {
+                warn!(self.log, "[sc] no login prompt: {}", e);
+            }
+        }
 
         // Set the terminal type.  This is non-interactive; we don't
         // want a real terminal type with actual capabilities.
-        trace!(self.log, "[sc] {}: Setting TERM=dumb", self.name);
+        trace!(self.log, "[sc] Setting TERM=dumb");
         let cmd = r"export TERM=dumb";
         let mut v = Vec::from(cmd);
         v.push(ENTER);
@@ -159,8 +315,19 @@ impl SerialCommander {
         let regex = Regex::new(&format!("{cmd}.*\\n")).unwrap();
         self.drain_match(ws, timeout, regex).await?;
 
+        // Export the environment for all following commands.
+        if let Some(cmd) = export_env(&self.env) {
+            trace!(self.log, "[sc] Exporting environment");
+            let mut v = Vec::from(cmd.as_bytes());
+            v.push(ENTER);
+            ws.send(Message::binary(v)).await?;
+            let regex =
+                Regex::new(&format!("{}.*\\n", regex::escape(&cmd))).unwrap();
+            self.drain_match(ws, timeout, regex).await?;
+        }
+
         // Put the terminal into raw mode.
-        trace!(self.log, "[sc] {}: Setting raw terminal mode", self.name);
+        trace!(self.log, "[sc] Setting raw terminal mode");
         let cmd = r"stty raw";
         let mut v = Vec::from(cmd);
         v.push(ENTER);
@@ -168,10 +335,14 @@ impl SerialCommander {
         let regex = Regex::new(&format!("{cmd}.*\\n")).unwrap();
         self.drain_match(ws, timeout, regex).await?;
 
+        if self.exec_strategy != ExecStrategy::PromptCommand {
+            return Ok(());
+        }
+
         // Set the prompt command to allow us to detect the end of each command
-        trace!(self.log, "[sc] {}: Setting PROMPT_COMMAND", self.name);
+        trace!(self.log, "[sc] Setting PROMPT_COMMAND");
         let mut v = Vec::from(
-            format!("PROMPT_COMMAND='echo {EOC_DETECTOR}'").as_bytes(),
+            format!("PROMPT_COMMAND='echo {}'", self.eoc_detector).as_bytes(),
         );
         v.push(ENTER);
         ws.send(Message::binary(v)).await?;
@@ -181,13 +352,59 @@ impl SerialCommander {
         // don't just use `self.eoc_regex`. We don't want this for the general
         // case, because in some cases (like our debian 11 image), we get output
         // prepended to the PROMPT_COMMAND.
-        let regex = Regex::new(&format!("(?mR)^{EOC_DETECTOR}")).unwrap();
+        let regex =
+            Regex::new(&format!("(?mR)^{}", self.eoc_detector)).unwrap();
         self.drain_match(ws, timeout, regex).await?;
 
         Ok(())
     }
 
-    pub(crate) async fn logout(
+    /// Send the username and password at the login prompt and wait for the
+    /// shell prompt.
+    async fn enter_credentials(
+        &mut self,
+        ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+        timeout: Option<u64>,
+    ) -> Result<(), Error> {
+        // Send username and wait for password prompt
+        trace!(
+            self.log,
+            "[sc] injecting username at expected password prompt"
+        );
+        let mut v = Vec::from(self.credentials.username.as_bytes());
+        v.push(ENTER);
+        ws.send(Message::binary(v)).await?;
+
+        // Some systems (such as our debian 11 image) don't take passwords.
+        // In that case, we also accept a shell prompt.
+        let shell_prompt =
+            shell_prompt(&self.credentials.username, self.guest_os);
+        self.drain_match(
+            ws,
+            timeout,
+            Regex::new(&format!("Password:|{shell_prompt}")).unwrap(),
+        )
+        .await?;
+
+        // Send the password and wait for prompt
+        trace!(
+            self.log,
+            "[sc] Sending password after expected password prompt"
+        );
+        let mut v = match &self.credentials.password {
+            Some(password) => Vec::from(password.as_bytes()),
+            None => Vec::new(),
+        };
+        v.push(ENTER);
+        ws.send(Message::binary(v)).await?;
+        self.drain_match(ws, timeout, Regex::new(&shell_prompt).unwrap())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Log out, waiting for the next login prompt.
+    pub async fn logout(
         &mut self,
         ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
     ) -> Result<(), Error> {
@@ -200,38 +417,166 @@ impl SerialCommander {
         Ok(())
     }
 
-    // Execute a command with a specific timeout
+    /// Execute a command, giving up if no more output arrives for
+    /// `timeout_ms` before it has finished.
     pub async fn exec_timeout(
         &mut self,
         ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
         cmd: String,
         timeout_ms: Option<u64>,
     ) -> Result<String, Error> {
-        debug!(self.log, "[sc] {}: executing command `{}`", self.name, cmd);
+        self.exec_lines(ws, cmd, timeout_ms, None, |_| {}).await
+    }
 
-        let mut v = Vec::from(cmd.as_bytes());
+    /// Execute a command, giving up if its output has not finished within
+    /// `deadline_ms` in total. Unlike [`SerialCommander::exec_timeout`], whose
+    /// timeout applies to each wait for more output, this bounds commands that
+    /// keep printing. A command that times out is left running, see
+    /// [`SerialCommander::interrupt`].
+    pub async fn exec_deadline(
+        &mut self,
+        ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+        cmd: String,
+        deadline_ms: u64,
+    ) -> Result<String, Error> {
+        self.exec_lines(ws, cmd, None, Some(deadline_ms), |_| {})
+            .await
+    }
+
+    /// Execute a command like [`SerialCommander::exec_deadline`], calling
+    /// `on_line` with each line of output as soon as the line is complete, so
+    /// the progress of long running commands can be followed. Lines are
+    /// processed the same way as the returned output, which is all of them.
+    pub async fn exec_streaming<F>(
+        &mut self,
+        ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+        cmd: String,
+        deadline_ms: Option<u64>,
+        on_line: F,
+    ) -> Result<String, Error>
+    where
+        F: FnMut(&str) + Send,
+    {
+        self.exec_lines(ws, cmd, None, deadline_ms, on_line).await
+    }
+
+    /// Execute a command, waiting at most `wait_ms` for each piece of output
+    /// and at most `deadline_ms` for all of it.
+    async fn exec_lines<F>(
+        &mut self,
+        ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+        cmd: String,
+        wait_ms: Option<u64>,
+        deadline_ms: Option<u64>,
+        mut on_line: F,
+    ) -> Result<String, Error>
+    where
+        F: FnMut(&str) + Send,
+    {
+        debug!(self.log, "[sc] executing command `{}`", cmd);
+
+        // The marker is echoed with an empty string spliced into it, so the
+        // terminal echoing the command line back does not match it.
+        let (a, b) = self.eoc_detector.split_at(self.eoc_detector.len() / 2);
+        let marker_echo = format!("echo {a}\"\"{b}");
+
+        let mut v = match self.exec_strategy {
+            ExecStrategy::PromptCommand | ExecStrategy::SingleCommand => {
+                Vec::from(cmd.as_bytes())
+            }
+            ExecStrategy::MarkerEcho => {
+                Vec::from(format!("{cmd}; {marker_echo}").as_bytes())
+            }
+        };
         v.push(ENTER);
+        if self.exec_strategy == ExecStrategy::SingleCommand {
+            v.extend_from_slice(marker_echo.as_bytes());
+            v.push(ENTER);
+        }
         ws.send(Message::binary(v)).await?;
 
-        let out = self
-            .drain_match(ws, timeout_ms, self.eoc_regex.clone())
-            .await?;
+        // the first line is the terminal echoing the command line
+        let (strip, single) = (
+            self.strip_ansi,
+            self.exec_strategy == ExecStrategy::SingleCommand,
+        );
+        let mut first = true;
+        let mut output_line = |line: &str| {
+            if std::mem::take(&mut first)
+                || (single && line.contains(&marker_echo))
+            {
+                return;
+            }
+            if strip {
+                on_line(&strip_ansi(line));
+            } else {
+                on_line(line);
+            }
+        };
+        let regex = self.eoc_regex.clone();
+        let drain =
+            self.drain_lines(ws, wait_ms, regex, true, &mut output_line);
+        let out = match deadline_ms {
+            None => drain.await?,
+            Some(ms) => match timeout(Duration::from_millis(ms), drain).await {
+                Ok(out) => out?,
+                Err(_) => {
+                    return Err(Error::Exec(format!(
+                        "{}: command `{}` timed out after {}ms",
+                        self.name, cmd, ms
+                    )));
+                }
+            },
+        };
 
-        // Iterate over all returned lines, stripping the first.
-        // This could almost certainly be made more efficient, by perhaps never
-        // adding the first line when parsing the regex.
+        // Iterate over all returned lines, stripping the first and any echo of
+        // the marker command. This could almost certainly be made more
+        // efficient, by perhaps never adding the first line when parsing the
+        // regex.
         let lines = out.lines().skip(1);
         let mut stripped = String::new();
         for line in lines {
+            if self.exec_strategy == ExecStrategy::SingleCommand
+                && line.contains(&marker_echo)
+            {
+                continue;
+            }
             stripped.push_str(line);
             stripped.push('\n');
         }
         // Remove the last `\n`
         stripped.pop();
 
+        if self.strip_ansi {
+            stripped = strip_ansi(&stripped);
+        }
+
         Ok(stripped)
     }
 
+    /// Interrupt the command currently running in the foreground, as if by
+    /// pressing ctrl-c.
+    pub async fn interrupt(
+        &mut self,
+        ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+    ) -> Result<(), Error> {
+        ws.send(Message::binary(vec![ETX, ENTER])).await?;
+        Ok(())
+    }
+
+    /// Execute a command, returning its output and exit status.
+    pub async fn exec_status(
+        &mut self,
+        ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+        command: String,
+        timeout_ms: Option<u64>,
+    ) -> Result<(String, i32), Error> {
+        let out = self
+            .exec_timeout(ws, with_exit_status(&command), timeout_ms)
+            .await?;
+        split_exit_status(&self.name, &out)
+    }
+
     // Execute a command with no timeout
     pub async fn exec(
         &mut self,
@@ -241,6 +586,30 @@ impl SerialCommander {
         self.exec_timeout(ws, command, None).await
     }
 
+    /// Append data received from the serial console to the console log, if
+    /// there is one. Failing to log is not fatal to the session.
+    fn log_console(&self, data: &[u8]) {
+        let path = match &self.console_log {
+            Some(path) => path,
+            None => return,
+        };
+        let result = (|| -> std::io::Result<()> {
+            if let Ok(md) = fs::metadata(path) {
+                if md.len() >= CONSOLE_LOG_MAX {
+                    fs::rename(path, format!("{path}.1"))?;
+                }
+            }
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?
+                .write_all(data)
+        })();
+        if let Err(e) = result {
+            warn!(self.log, "[sc] console log {}: {}", path, e);
+        }
+    }
+
     /// Drain from the websocket until we match the provided regex or timeout.
     ///
     /// Return all read data up to the regex match or an error.
@@ -250,65 +619,94 @@ impl SerialCommander {
         wait_ms: Option<u64>,
         regex: Regex,
     ) -> Result<String, Error> {
-        trace!(self.log, "[sc] {}: drain by matching regex", self.name);
+        self.drain_lines(ws, wait_ms, regex, false, &mut |_| {})
+            .await
+    }
+
+    /// Like [`SerialCommander::drain_match`], additionally calling `on_line`
+    /// with each line of the data up to the match once the line is complete.
+    /// A line is only complete once the next line has started, so a partially
+    /// received match is never passed on. If `echoed` is set, the first line is
+    /// the terminal echoing a command line and is not scanned for panics.
+    async fn drain_lines(
+        &mut self,
+        ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+        wait_ms: Option<u64>,
+        regex: Regex,
+        echoed: bool,
+        on_line: &mut (dyn FnMut(&str) + Send),
+    ) -> Result<String, Error> {
+        trace!(self.log, "[sc] drain by matching regex");
 
         // Use the largest possible timeout if we don't want a timeout
         let wait_ms = wait_ms.unwrap_or(u64::MAX);
 
         let mut result = "".to_string();
+        // how much of result has been passed to on_line
+        let mut emitted = 0;
+        // where a detected panic starts, and when to stop capturing it
+        let mut panic: Option<(usize, Instant)> = None;
         loop {
-            match timeout(Duration::from_millis(wait_ms), ws.next()).await {
+            let wait = match panic {
+                Some((_, until)) => {
+                    until.saturating_duration_since(Instant::now())
+                }
+                None => Duration::from_millis(wait_ms),
+            };
+            match timeout(wait, ws.next()).await {
                 Ok(msg) => match msg {
                     Some(Ok(Message::Binary(data))) => {
+                        self.log_console(data.as_slice());
                         let s = String::from_utf8_lossy(data.as_slice())
                             .to_string();
-                        trace!(
-                            self.log,
-                            "[sc] {}: received data: {}",
-                            self.name,
-                            s
-                        );
+                        trace!(self.log, "[sc] received data: {}", s);
                         result += &s;
+                        if panic.is_none() {
+                            if let Some(start) = find_output_panic(
+                                &self.panic_patterns,
+                                &result,
+                                echoed,
+                            ) {
+                                warn!(self.log, "[sc] guest panic detected");
+                                let until = Instant::now()
+                                    + Duration::from_millis(PANIC_CAPTURE_MS);
+                                panic = Some((start, until));
+                            }
+                        }
+                        if panic.is_some() {
+                            continue;
+                        }
                         if let Some(mat) = regex.find(&result) {
-                            trace!(
-                                self.log,
-                                "[sc] {}: drained: `{}`",
-                                self.name,
-                                &result
-                            );
+                            trace!(self.log, "[sc] drained: `{}`", &result);
                             result.truncate(mat.start());
-                            trace!(
-                                self.log,
-                                "[sc] {}: breaking on success",
-                                self.name
-                            );
+                            emit_lines(&result, &mut emitted, true, on_line);
+                            trace!(self.log, "[sc] breaking on success");
                             break;
                         }
+                        emit_lines(&result, &mut emitted, false, on_line);
                     }
                     Some(Ok(Message::Close(..))) => {
-                        trace!(
-                            self.log,
-                            "[sc] {}: breaking on close",
-                            self.name
-                        );
+                        trace!(self.log, "[sc] breaking on close");
+                        if let Some((start, _)) = panic {
+                            return Err(self.guest_panic(&result[start..]));
+                        }
                         return Err(Error::Exec(format!(
                             "[sc] {}: websocket closed",
                             self.name
                         )));
                     }
                     None => {
-                        trace!(
-                            self.log,
-                            "[sc] {}: breaking on none",
-                            self.name
-                        );
+                        trace!(self.log, "[sc] breaking on none");
+                        if let Some((start, _)) = panic {
+                            return Err(self.guest_panic(&result[start..]));
+                        }
                         return Err(Error::Exec(format!(
                             "[sc] {}: stream returned no data",
                             self.name
                         )));
                     }
                     _ => {
-                        trace!(self.log, "[sc] {}: breaking on _", self.name);
+                        trace!(self.log, "[sc] breaking on _");
                         return Err(Error::Exec(format!(
                             "[sc] {}: Unexpected websocket message",
                             self.name
@@ -318,10 +716,12 @@ impl SerialCommander {
                 Err(_) => {
                     trace!(
                         self.log,
-                        "[sc] {}: breaking on timeout: received {}",
-                        self.name,
+                        "[sc] breaking on timeout: received {}",
                         result
                     );
+                    if let Some((start, _)) = panic {
+                        return Err(self.guest_panic(&result[start..]));
+                    }
                     return Err(Error::Exec(format!(
                         "[sc] {}: timeout waiting for data",
                         self.name
@@ -332,4 +732,159 @@ impl SerialCommander {
 
         Ok(result)
     }
+
+    fn guest_panic(&self, text: &str) -> Error {
+        let text = if self.strip_ansi {
+            strip_ansi(text)
+        } else {
+            text.to_string()
+        };
+        Error::GuestPanic(self.name.clone(), text.trim_end().to_string())
+    }
+}
+
+/// A regular expression matching the shell prompt of `username` once logged
+/// into a guest running `os`. Besides `user@host` prompts, Linux guests may
+/// show a bare `host:~# ` prompt, e.g. on busybox based images.
+pub(crate) fn shell_prompt(username: &str, os: GuestOs) -> String {
+    let user = format!("{}@.+[#$]", regex::escape(username));
+    match os {
+        GuestOs::Illumos => user,
+        GuestOs::Linux => format!(r"{user}|:\S*[#$] $"),
+    }
+}
+
+/// The command exporting `env`, if there is anything to export. Values are
+/// double quoted so `$VAR` references expand in the guest shell.
+pub(crate) fn export_env(env: &BTreeMap<String, String>) -> Option<String> {
+    if env.is_empty() {
+        return None;
+    }
+    let vars: Vec<String> = env
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, double_quote_escape(v)))
+        .collect();
+    Some(format!("export {}", vars.join(" ")))
+}
+
+/// Escape the characters that are special inside double quotes, except `$`.
+fn double_quote_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '"' | '\\' | '`') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Compile panic detection patterns.
+pub(crate) fn panic_regexes<S: AsRef<str>>(
+    patterns: &[S],
+) -> Result<Vec<Regex>, Error> {
+    patterns
+        .iter()
+        .map(|p| {
+            Regex::new(p.as_ref()).map_err(|e| {
+                Error::Validation(vec![format!(
+                    "invalid panic pattern `{}`: {}",
+                    p.as_ref(),
+                    e
+                )])
+            })
+        })
+        .collect()
+}
+
+/// The start of the line containing the earliest match of any of `patterns`
+/// in `buf`, if any.
+pub(crate) fn find_panic(patterns: &[Regex], buf: &str) -> Option<usize> {
+    let start = patterns
+        .iter()
+        .filter_map(|r| r.find(buf))
+        .map(|m| m.start())
+        .min()?;
+    Some(buf[..start].rfind('\n').map(|i| i + 1).unwrap_or(0))
+}
+
+/// Like [`find_panic`], but if `echoed` is set the first line of `buf` is the
+/// echo of a command line, which is skipped so a command mentioning a panic
+/// pattern is not mistaken for a panic. Nothing is found until the echoed line
+/// is complete.
+pub(crate) fn find_output_panic(
+    patterns: &[Regex],
+    buf: &str,
+    echoed: bool,
+) -> Option<usize> {
+    let from = if echoed { buf.find('\n')? + 1 } else { 0 };
+    find_panic(patterns, &buf[from..]).map(|start| from + start)
+}
+
+/// Pass the lines of `buf` after `emitted` to `on_line`, and advance `emitted`
+/// past them. Unless `all` is set, a trailing line without a newline is held
+/// back as more of it may still arrive.
+pub(crate) fn emit_lines(
+    buf: &str,
+    emitted: &mut usize,
+    all: bool,
+    on_line: &mut (dyn FnMut(&str) + Send),
+) {
+    let rest = &buf[*emitted..];
+    let end = if all {
+        rest.len()
+    } else {
+        match rest.rfind('\n') {
+            Some(i) => i + 1,
+            None => return,
+        }
+    };
+    rest[..end].lines().for_each(&mut *on_line);
+    *emitted += end;
+}
+
+/// Extend `cmd` to print its exit status after its output, for
+/// [`split_exit_status`] to find.
+pub(crate) fn with_exit_status(cmd: &str) -> String {
+    // Like the end of command marker, the exit status marker is echoed with an
+    // empty string spliced into it, so the command line itself never matches.
+    let (a, b) = RC_MARKER.split_at(RC_MARKER.len() / 2);
+    format!("{cmd}; echo {a}\"\"{b}$?")
+}
+
+/// Split the output of a command run with [`with_exit_status`] into the output
+/// of the command itself and its exit status.
+pub(crate) fn split_exit_status(
+    name: &str,
+    out: &str,
+) -> Result<(String, i32), Error> {
+    let (out, rc) = out.rsplit_once(RC_MARKER).ok_or_else(|| {
+        Error::Exec(format!("{}: no exit status in command output", name))
+    })?;
+    let rc = rc.trim().parse::<i32>().map_err(|e| {
+        Error::Exec(format!("{}: bad exit status `{}`: {}", name, rc, e))
+    })?;
+    Ok((out.strip_suffix('\n').unwrap_or(out).to_string(), rc))
+}
+
+/// Remove ANSI escape sequences and any control characters other than tabs and
+/// newlines from `s`.
+pub fn strip_ansi(s: &str) -> String {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| {
+        Regex::new(concat!(
+            // CSI sequences e.g., colors and cursor movement
+            r"\x1b\[[0-?]*[ -/]*[@-~]",
+            // OSC sequences e.g., setting the window title
+            r"|\x1b\][^\x07\x1b]*(\x07|\x1b\\)",
+            // Character set designations
+            r"|\x1b[()*+][0-9A-Za-z]",
+            // Other two character escape sequences
+            r"|\x1b[@-Z\\-_]",
+            // Remaining control characters
+            r"|[\x00-\x08\x0b-\x1f\x7f]",
+        ))
+        .unwrap()
+    });
+    re.replace_all(s, "").into_owned()
 }
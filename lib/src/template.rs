@@ -0,0 +1,103 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+use crate::{error::Error, Runner};
+use std::collections::BTreeMap;
+
+/// Parameters a deployment template is instantiated with.
+#[derive(Debug, Clone)]
+pub struct TemplateParams {
+    /// How many nodes the template should create.
+    pub nodes: usize,
+    /// Image nodes should use.
+    pub image: String,
+    /// How many cores to give each node.
+    pub cores: u8,
+    /// How much memory to give each node in mb.
+    pub memory: u64,
+    /// Free form parameters for anything specific to a template.
+    pub extra: BTreeMap<String, String>,
+}
+
+impl Default for TemplateParams {
+    fn default() -> Self {
+        TemplateParams {
+            nodes: 1,
+            image: "helios-2.5".into(),
+            cores: 1,
+            memory: 1024,
+            extra: BTreeMap::new(),
+        }
+    }
+}
+
+/// A deployment template describes the shape of a topology once, so that it
+/// can be instantiated any number of times with different parameters. Any
+/// function or closure of the form
+/// `Fn(&mut Runner, &TemplateParams) -> Result<(), Error>`
+/// is a template.
+///
+/// # Examples
+/// ```no_run
+/// use libfalcon::{error::Error, template::TemplateParams, Runner};
+///
+/// // A line of nodes, each linked to the next.
+/// fn line(r: &mut Runner, p: &TemplateParams) -> Result<(), Error> {
+///     let mut prev = None;
+///     for i in 0..p.nodes {
+///         let n = r.node(&format!("n{i}"), &p.image, p.cores, p.memory);
+///         if let Some(prev) = prev {
+///             r.link(prev, n);
+///         }
+///         prev = Some(n);
+///     }
+///     Ok(())
+/// }
+///
+/// # fn main() -> Result<(), Error> {
+/// let small = TemplateParams { nodes: 3, ..Default::default() };
+/// let large = TemplateParams { nodes: 5, ..Default::default() };
+/// let a = Runner::instantiate("small", &line, &small)?;
+/// let b = Runner::instantiate("large", &line, &large)?;
+/// # Ok(())
+/// # }
+/// ```
+pub trait DeploymentTemplate {
+    /// Add the nodes and links described by this template to `r`.
+    fn build(
+        &self,
+        r: &mut Runner,
+        params: &TemplateParams,
+    ) -> Result<(), Error>;
+}
+
+impl<F> DeploymentTemplate for F
+where
+    F: Fn(&mut Runner, &TemplateParams) -> Result<(), Error>,
+{
+    fn build(
+        &self,
+        r: &mut Runner,
+        params: &TemplateParams,
+    ) -> Result<(), Error> {
+        self(r, params)
+    }
+}
+
+impl Runner {
+    /// Create a new runner named `name` whose deployment is built from
+    /// `template` using `params`. The result is a normal runner that can be
+    /// further modified before being launched.
+    pub fn instantiate(
+        name: &str,
+        template: &impl DeploymentTemplate,
+        params: &TemplateParams,
+    ) -> Result<Runner, Error> {
+        let mut r = Runner::new(name);
+        template.build(&mut r, params)?;
+        Ok(r)
+    }
+}
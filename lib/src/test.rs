@@ -5,6 +5,7 @@
 // Copyright 2022 Oxide Computer Company
 
 use anyhow::{anyhow, Result};
+use camino::Utf8PathBuf;
 
 /// Test that when an empty deployment is launched the correct ZFS pools get
 /// created and when a deployment is destroyd the associated zfs pools are
@@ -47,6 +48,129 @@ async fn solo_launch() -> Result<()> {
     Ok(())
 }
 
+/// Test that a multi-line script is run as a whole.
+#[tokio::test]
+async fn exec_script_launch() -> Result<()> {
+    let mut d = crate::test_support::TestDeployment::new("script");
+    let z = d.node("violin", "helios-2.5", 1, 1024);
+
+    d.launch().await?;
+
+    let script = d
+        .exec_script(z, "for x in a b; do\n  echo $x\ndone\necho 'c\nd'\n")
+        .await?;
+    assert_eq!(script, "a\nb\nc\nd");
+
+    Ok(())
+}
+
+/// Test that a command can be retried until its output is as expected.
+#[tokio::test]
+async fn wait_for_launch() -> Result<()> {
+    let mut d = crate::test_support::TestDeployment::new("waitfor");
+    let z = d.node("violin", "helios-2.5", 1, 1024);
+
+    d.launch().await?;
+
+    let online = d
+        .wait_for(
+            z,
+            "svcs -Ho state milestone/multi-user",
+            |out| out == "online",
+            std::time::Duration::from_secs(1),
+            30,
+        )
+        .await?;
+    assert_eq!(online, "online");
+
+    Ok(())
+}
+
+/// Test that a node's init script runs once setup has completed.
+#[tokio::test]
+async fn init_script_launch() -> Result<()> {
+    let mut d = crate::test_support::TestDeployment::new("init");
+    let z = d.node("violin", "helios-2.5", 1, 1024);
+    d.set_init_script(
+        z,
+        "hostname > /tmp/init_ran; echo ran >> /var/tmp/init_runs".into(),
+    );
+
+    d.launch().await?;
+
+    let init_ran = d.exec(z, "cat /tmp/init_ran").await?;
+    assert_eq!(init_ran, "violin");
+
+    // kill the node's hypervisor and let reconcile relaunch it, the script
+    // does not run again on the same disk
+    let pid = std::fs::read_to_string(d.falcon_dir.join("violin.pid"))?;
+    unsafe {
+        libc::kill(pid.trim().parse()?, libc::SIGKILL);
+    }
+    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    assert_eq!(d.reconcile().await?, 1);
+    let runs = d.exec(z, "wc -l < /var/tmp/init_runs").await?;
+    assert_eq!(runs.trim(), "1");
+
+    Ok(())
+}
+
+/// Test that exit statuses are reported alongside output.
+#[tokio::test]
+async fn exec_status_launch() -> Result<()> {
+    let mut d = crate::test_support::TestDeployment::new("status");
+    let z = d.node("violin", "helios-2.5", 1, 1024);
+
+    d.launch().await?;
+
+    let failed = d.exec_status(z, "echo nope; false").await?;
+    assert_eq!(failed, ("nope".to_string(), 1));
+    let ok = d.exec_status(z, "echo yep").await?;
+    assert_eq!(ok, ("yep".to_string(), 0));
+
+    Ok(())
+}
+
+/// Test that color codes emitted by a command are stripped from its output.
+#[tokio::test]
+async fn strip_ansi_launch() -> Result<()> {
+    let mut d = crate::test_support::TestDeployment::new("ansi");
+    let z = d.node("violin", "helios-2.5", 1, 1024);
+
+    d.launch().await?;
+
+    let colored = d.exec(z, r"printf '\033[1;31mred\033[0m\n'").await?;
+    assert_eq!(colored, "red");
+
+    Ok(())
+}
+
+/// Test that several directories mounted into the same node are each mounted
+/// at the right destination.
+#[tokio::test]
+async fn multi_mount_launch() -> Result<()> {
+    let mut d = crate::test_support::TestDeployment::new("multimount");
+    let z = d.node("violin", "debian-11.0", 1, 1024);
+    d.set_guest_os(z, crate::serial::GuestOs::Linux);
+
+    std::fs::create_dir_all("/tmp/multimount/a")?;
+    std::fs::create_dir_all("/tmp/multimount/b")?;
+    std::fs::write("/tmp/multimount/a/data", "data a")?;
+    std::fs::write("/tmp/multimount/b/data", "data b")?;
+    d.mount_linux("/tmp/multimount/a", "/opt/a", z)?;
+    d.mount_linux("/tmp/multimount/b", "/opt/b", z)?;
+
+    d.launch().await?;
+
+    let a = d.exec(z, "cat /opt/a/data").await?;
+    let b = d.exec(z, "cat /opt/b/data").await?;
+
+    assert_eq!(a, "data a");
+    assert_eq!(b, "data b");
+
+    Ok(())
+}
+
 /// Test that when a two node deployment gets launched, the corresponding
 /// simnet and vnic links get created and destroyed.
 #[tokio::test]
@@ -72,41 +196,18 @@ async fn duo_launch() -> Result<()> {
         "VIOLIN DLADM\n{}\n",
         d.exec(violin, "dladm show-link").await?
     );
-    d.exec(violin, "ipadm create-addr -t -T addrconf vioif0/v6")
-        .await?;
+    d.enable_v6_linklocal(violin, "vioif0").await?;
     println!(
         "VIOLIN IPADM\n{}\n",
         d.exec(violin, "ipadm show-addr").await?
     );
 
     println!("PIANO DLADM\n{}\n", d.exec(piano, "dladm show-link").await?);
-    d.exec(piano, "ipadm create-addr -t -T addrconf vioif0/v6")
-        .await?;
+    let piano_addr = d.enable_v6_linklocal(piano, "vioif0").await?;
     println!("PIANO IPADM\n{}\n", d.exec(piano, "ipadm show-addr").await?);
 
-    // get piano addresses
-    let piano_addr =
-        d.exec(piano, "ipadm show-addr -po addr vioif0/v6").await?;
-
-    // wait for piano address to become ready
-    let mut retries = 0;
-    loop {
-        let state =
-            d.exec(piano, "ipadm show-addr -po state vioif0/v6").await?;
-        if state == "ok" {
-            break;
-        }
-        println!("PIANO:vioif0/v6:state = '{}'", state);
-        retries += 1;
-        if retries >= 10 {
-            return Err(anyhow!("timed out waiting for vioif0/v6"));
-        }
-        std::thread::sleep(std::time::Duration::from_secs(1))
-    }
-
     // do a ping
-    let ping_cmd =
-        format!("ping {} 1", piano_addr.strip_suffix("/10").unwrap());
+    let ping_cmd = format!("ping {} 1", piano_addr);
     d.exec(violin, ping_cmd.as_str()).await?;
 
     // verify links exist
@@ -126,6 +227,1355 @@ async fn duo_launch() -> Result<()> {
     Ok(())
 }
 
+/// Test that an interrupted topology write leaves the previously written file
+/// intact and parseable.
+#[test]
+fn topology_write_atomic() -> Result<()> {
+    let dir = Utf8PathBuf::from(format!(
+        "/tmp/falcon-atomic-{}",
+        uuid::Uuid::new_v4()
+    ));
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("topology.ron");
+
+    let d = crate::Deployment::new("atomic");
+    let topo = ron::ser::to_string_pretty(&d, ron::ser::PrettyConfig::new())?;
+    crate::util::write_atomic(&path, &topo)?;
+
+    // Simulate a write that died part way through, before the rename.
+    std::fs::write(dir.join("topology.ron.tmp"), &topo[..topo.len() / 2])?;
+
+    let read: crate::Deployment =
+        ron::de::from_str(&std::fs::read_to_string(&path)?)?;
+    assert_eq!(read.name, "atomic");
+
+    // A subsequent write replaces both the stale temp file and the original.
+    let d = crate::Deployment::new("atomic2");
+    let topo = ron::ser::to_string_pretty(&d, ron::ser::PrettyConfig::new())?;
+    crate::util::write_atomic(&path, &topo)?;
+    let read: crate::Deployment =
+        ron::de::from_str(&std::fs::read_to_string(&path)?)?;
+    assert_eq!(read.name, "atomic2");
+    assert!(!dir.join("topology.ron.tmp").exists());
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn runner_from_topology() -> Result<()> {
+    let dir = Utf8PathBuf::from(format!(
+        "/tmp/falcon-from-topo-{}",
+        uuid::Uuid::new_v4()
+    ));
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("topology.ron");
+
+    let mut d = crate::Runner::new("authored");
+    let violin = d.node("violin", "helios-2.5", 2, 2048);
+    let piano = d.node("piano", "debian-11.0", 1, 1024);
+    d.link(violin, piano);
+    d.persistent = true;
+    let topo = ron::ser::to_string_pretty(
+        &d.deployment,
+        ron::ser::PrettyConfig::new(),
+    )?;
+    std::fs::write(&path, topo)?;
+
+    let mut r = crate::Runner::from_topology(&path)?;
+    r.persistent = true;
+    assert_eq!(r.deployment.name, "authored");
+    assert_eq!(r.deployment.nodes.len(), 2);
+    assert_eq!(r.deployment.nodes[0].cores, 2);
+    assert_eq!(r.deployment.nodes[1].image, "debian-11.0");
+    assert_eq!(r.deployment.links.len(), 1);
+
+    std::fs::write(&path, "not a topology")?;
+    assert!(crate::Runner::from_topology(&path).is_err());
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn topology_toml_roundtrip() -> Result<()> {
+    let mut d = crate::Runner::new("toml");
+    let violin = d.node("violin", "helios-2.5", 2, 2048);
+    let piano = d.node("piano", "debian-11.0", 1, 1024);
+    let cello = d.node("cello", "helios-2.5", 1, 1024);
+    let sw = d.node("sw", "helios-2.5", 1, 1024);
+    d.persistent = true;
+    let l = d.link(violin, piano);
+    d.set_link_properties(l, None, Some(100));
+    d.set_link_addr(l, 0, "10.0.0.1/24".parse::<oxnet::IpNet>()?)?;
+    d.softnpu_link(sw, cello, Some("a8:40:25:00:00:01".into()), None);
+    d.sidecar_link(sw, violin, 2, None);
+    d.bridge(&[violin, piano, cello]);
+    d.ext_link("igb0", piano);
+    d.set_settle_time(piano, std::time::Duration::from_millis(1500));
+    d.set_guest_os(piano, crate::serial::GuestOs::Linux);
+
+    let toml = d.deployment.to_toml()?;
+    assert!(toml.contains(crate::TOML_NONE));
+    let from_toml = crate::Deployment::from_toml(&toml)?;
+    let from_ron = crate::Deployment::from_ron(&d.deployment.to_ron()?)?;
+    assert_eq!(
+        serde_json::to_value(&from_toml)?,
+        serde_json::to_value(&from_ron)?
+    );
+    assert_eq!(
+        serde_json::to_value(&from_toml)?,
+        serde_json::to_value(&d.deployment)?
+    );
+
+    // mac_prefix is left out of the TOML while unset
+    d.set_mac_prefix([0xa8, 0x40, 0x25]);
+    let read = crate::Deployment::from_toml(&d.deployment.to_toml()?)?;
+    assert_eq!(read.mac_prefix, Some([0xa8, 0x40, 0x25]));
+
+    assert_eq!(
+        crate::TopologyFormat::of(camino::Utf8Path::new("a/topology.toml")),
+        crate::TopologyFormat::Toml
+    );
+    assert_eq!(
+        crate::TopologyFormat::of(camino::Utf8Path::new("a/topology.ron")),
+        crate::TopologyFormat::Ron
+    );
+    Ok(())
+}
+
+#[test]
+fn load_recorded_topology() -> Result<()> {
+    let dir = Utf8PathBuf::from(format!(
+        "/tmp/falcon-load-topo-{}",
+        uuid::Uuid::new_v4()
+    ));
+    std::fs::create_dir_all(&dir)?;
+
+    let mut launched = crate::Runner::builder("launched")
+        .dataset("tank/falcon")
+        .build();
+    launched.node("violin", "helios-2.5", 1, 1024);
+    launched.persistent = true;
+    launched.deployment.write_file(&dir.join("topology.ron"))?;
+
+    let mut r = crate::Runner::new("other");
+    r.persistent = true;
+    r.falcon_dir = dir.join("missing");
+    assert!(!r.load_topology()?);
+    assert_eq!(r.deployment.name, "other");
+
+    r.falcon_dir = dir.clone();
+    assert!(r.load_topology()?);
+    assert_eq!(r.deployment.name, "launched");
+    assert_eq!(r.deployment.nodes[0].name, "violin");
+    assert_eq!(r.dataset, "tank/falcon");
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn garbage() {
+    let links = "igb0\nduo_violin_vn_vnic0\nduo_violin_vn_sim0\n\
+        old_piano_sn_vnic1\nold_piano_sn_sim1\nold_stub0\n\
+        duo_x_cello_vn_vnic0\nvioif0_stub\nold_piano_vn_vnic0x\n\
+        old_stub1_vn_vnic0\n";
+    let datasets = "rpool/falcon/topo\nrpool/falcon/topo/duo\n\
+        rpool/falcon/topo/old\nrpool/falcon/topo/old/piano\n";
+    let known = ["duo".to_string()].into_iter().collect();
+
+    let g = crate::find_garbage(
+        links.lines(),
+        datasets.lines(),
+        "rpool/falcon/topo",
+        &known,
+    );
+    // links of deployments that extend a known name are kept
+    // a vnic of a node named like an etherstub is still a vnic
+    assert_eq!(g.vnics, vec!["old_piano_sn_vnic1", "old_stub1_vn_vnic0"]);
+    assert_eq!(g.simnets, vec!["old_piano_sn_sim1"]);
+    assert_eq!(g.etherstubs, vec!["old_stub0"]);
+    assert_eq!(
+        g.links().collect::<Vec<_>>(),
+        vec![
+            "old_piano_sn_vnic1",
+            "old_stub1_vn_vnic0",
+            "old_piano_sn_sim1",
+            "old_stub0"
+        ]
+    );
+    assert_eq!(g.datasets, vec!["rpool/falcon/topo/old"]);
+
+    let known = ["duo".to_string(), "old".to_string()].into_iter().collect();
+    let g = crate::find_garbage(
+        links.lines(),
+        datasets.lines(),
+        "rpool/falcon/topo",
+        &known,
+    );
+    assert!(g.is_empty());
+}
+
+#[test]
+fn node_propolis_binary() -> Result<()> {
+    let mut d = crate::Runner::builder("ab")
+        .propolis_binary("/opt/bin/propolis-server")
+        .build();
+    let violin = d.node("violin", "helios-2.5", 1, 1024);
+    let piano = d.node("piano", "helios-2.5", 1, 1024);
+    d.persistent = true;
+    d.set_node_propolis_binary(piano, "/tmp/propolis-server".into());
+
+    assert_eq!(
+        d.get_node(violin).propolis_binary(&d),
+        "/opt/bin/propolis-server"
+    );
+    assert_eq!(
+        d.get_node(piano).propolis_binary(&d),
+        "/tmp/propolis-server"
+    );
+
+    let read = crate::Deployment::from_ron(&d.deployment.to_ron()?)?;
+    assert_eq!(
+        read.nodes[1].propolis_binary.as_deref(),
+        Some("/tmp/propolis-server")
+    );
+    Ok(())
+}
+
+#[test]
+fn file_backing_dir() {
+    let mut d = crate::Runner::builder("fbd")
+        .dataset("tank/falcon")
+        .file_dir("/tank/dsk")
+        .build();
+    let violin = d.node("violin", "helios-2.5", 1, 1024);
+    let piano = d.node("piano", "helios-2.5", 1, 1024);
+    d.persistent = true;
+    d.set_backing(violin, crate::PrimaryDiskBacking::File);
+    d.add_disk(violin, 10, crate::PrimaryDiskBacking::File);
+    d.add_disk(piano, 10, crate::PrimaryDiskBacking::Zvol);
+
+    let (v, p) = (d.get_node(violin), d.get_node(piano));
+    assert_eq!(v.backing_path(&d), "/tank/dsk/fbd/violin");
+    assert_eq!(v.extra_backing_path(&d, 0), "/tank/dsk/fbd/violin_disk0");
+    assert_eq!(
+        p.backing_path(&d),
+        "/dev/zvol/rdsk/tank/falcon/topo/fbd/piano"
+    );
+    assert_eq!(
+        p.extra_backing_path(&d, 0),
+        "/dev/zvol/rdsk/tank/falcon/topo/fbd/piano_disk0"
+    );
+
+    d.set_file_dir("/var/tmp/dsk");
+    assert_eq!(
+        d.get_node(violin).backing_path(&d),
+        "/var/tmp/dsk/fbd/violin"
+    );
+}
+
+#[test]
+fn instance_metadata_roundtrip() -> Result<()> {
+    let mut d = crate::Runner::new("metadata");
+    let violin = d.node("violin", "helios-2.5", 1, 1024);
+    d.node("piano", "helios-2.5", 1, 1024);
+    d.persistent = true;
+    let sled_id = uuid::Uuid::new_v4();
+    d.set_instance_metadata(
+        violin,
+        propolis_client::types::InstanceMetadata {
+            project_id: uuid::Uuid::new_v4(),
+            silo_id: uuid::Uuid::new_v4(),
+            sled_id,
+            sled_model: "gimlet".into(),
+            sled_serial: "BRM42220001".into(),
+            sled_revision: 6,
+        },
+    );
+
+    let topo = ron::ser::to_string_pretty(
+        &d.deployment,
+        ron::ser::PrettyConfig::new(),
+    )?;
+    let read: crate::Deployment = ron::de::from_str(&topo)?;
+    let metadata = read.nodes[0].instance_metadata.as_ref().unwrap();
+    assert_eq!(metadata.sled_id, sled_id);
+    assert_eq!(metadata.sled_serial, "BRM42220001");
+    assert_eq!(metadata.sled_revision, 6);
+    assert!(read.nodes[1].instance_metadata.is_none());
+    Ok(())
+}
+
+#[test]
+fn vnc_port() -> Result<()> {
+    let dir =
+        Utf8PathBuf::from(format!("/tmp/falcon-vnc-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir)?;
+    let mut d = crate::Runner::builder("vnc").falcon_dir(&dir).build();
+    let violin = d.node("violin", "helios-2.5", 1, 1024);
+    let piano = d.node("piano", "helios-2.5", 1, 1024);
+    d.persistent = true;
+
+    d.enable_vnc(violin, None);
+    d.enable_vnc(piano, Some(5901));
+    assert!(d.deployment.nodes[0].vnc);
+    assert_eq!(d.deployment.nodes[0].vnc_port, None);
+    assert_eq!(d.deployment.nodes[1].vnc_port, Some(5901));
+
+    assert!(d.node_vnc_port(violin).is_err());
+    std::fs::write(dir.join("violin.vnc"), "5902\n")?;
+    assert_eq!(d.node_vnc_port(violin)?, 5902);
+    assert_ne!(crate::free_tcp_port()?, 0);
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn falcon_dir_lock() -> Result<()> {
+    let dir = camino::Utf8PathBuf::from("/tmp/falcon-lock-test");
+    let lock = crate::util::lock_dir(&dir)?;
+    match crate::util::lock_dir(&dir) {
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+        Err(e) => return Err(e.into()),
+        Ok(_) => return Err(anyhow!("falcon dir locked twice")),
+    }
+    drop(lock);
+    crate::util::lock_dir(&dir)?;
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn node_port_uuid() -> Result<()> {
+    let mut d = crate::Runner::new("ports");
+    let violin = d.node("violin", "helios-2.5", 1, 1024);
+    d.persistent = true;
+    d.falcon_dir = "/tmp/falcon-ports-test".into();
+    std::fs::create_dir_all(&d.falcon_dir)?;
+
+    assert!(matches!(
+        d.node_port(violin),
+        Err(crate::error::Error::NotFound(_))
+    ));
+
+    let id = uuid::Uuid::new_v4();
+    std::fs::write(d.falcon_dir.join("violin.port"), "4710")?;
+    std::fs::write(d.falcon_dir.join("violin.uuid"), id.to_string())?;
+    assert_eq!(d.node_port(violin)?, 4710);
+    assert_eq!(d.node_uuid(violin)?, id);
+
+    std::fs::remove_dir_all(&d.falcon_dir)?;
+    Ok(())
+}
+
+#[test]
+fn runner_builder() {
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let d = crate::Runner::builder("built")
+        .falcon_dir("/tmp/falcon-built")
+        .dataset("tank/falcon")
+        .propolis_binary("/opt/bin/propolis-server")
+        .log(log)
+        .persistent(true)
+        .asset_base_url("http://mirror.local")
+        .launch_timeout(std::time::Duration::from_secs(90))
+        .build();
+
+    assert_eq!(d.deployment.name, "built");
+    assert_eq!(d.falcon_dir, "/tmp/falcon-built");
+    assert_eq!(d.dataset, "tank/falcon");
+    assert_eq!(d.propolis_binary, "/opt/bin/propolis-server");
+    assert!(d.persistent);
+    assert_eq!(d.asset_base_url, "http://mirror.local");
+    assert_eq!(
+        d.deployment.launch_timeout,
+        std::time::Duration::from_secs(90)
+    );
+}
+
+#[test]
+fn parse_guest_addrs() -> Result<()> {
+    let illumos = r"lo0/v4:127.0.0.1/8
+vioif0/v4:10.0.0.1/24
+vioif0/v6:fe80\:\:8\:20ff\:fe3b\:1f9a/10
+vioif1/v6:?
+lo0/v6:\:\:1/128";
+    let addrs = crate::parse_illumos_addrs(illumos);
+    let expected = [
+        ("lo0", "127.0.0.1/8"),
+        ("vioif0", "10.0.0.1/24"),
+        ("vioif0", "fe80::8:20ff:fe3b:1f9a/10"),
+        ("lo0", "::1/128"),
+    ];
+    assert_eq!(addrs.len(), expected.len());
+    for (a, (ifname, addr)) in addrs.iter().zip(expected) {
+        assert_eq!(a.ifname, ifname);
+        assert_eq!(a.addr, addr.parse()?);
+    }
+
+    let linux = r"1: lo    inet 127.0.0.1/8 scope host lo\       valid_lft forever
+1: lo    inet6 ::1/128 scope host \       valid_lft forever
+2: enp0s8    inet 10.0.0.2/24 brd 10.0.0.255 scope global enp0s8\       valid_lft
+3: veth0@if4    inet6 fe80::8:20ff:fe3b:1f9b/64 scope link \       valid_lft";
+    let addrs = crate::parse_linux_addrs(linux);
+    let expected = [
+        ("lo", "127.0.0.1/8"),
+        ("lo", "::1/128"),
+        ("enp0s8", "10.0.0.2/24"),
+        ("veth0", "fe80::8:20ff:fe3b:1f9b/64"),
+    ];
+    assert_eq!(addrs.len(), expected.len());
+    for (a, (ifname, addr)) in addrs.iter().zip(expected) {
+        assert_eq!(a.ifname, ifname);
+        assert_eq!(a.addr, addr.parse()?);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn image_compat() -> Result<()> {
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+
+    let meta: crate::ImageMetadata = toml::from_str(&format!(
+        "propolis_revs = [\"{}\"]\nfeatures = [\"p9fs\"]",
+        crate::PROPOLIS_REV
+    ))?;
+    meta.check("good", &log)?;
+
+    // an unknown revision is only a warning
+    let meta: crate::ImageMetadata = toml::from_str("propolis_revs = [\"a\"]")?;
+    meta.check("old", &log)?;
+
+    let meta: crate::ImageMetadata = toml::from_str("features = [\"nvme\"]")?;
+    assert!(meta.check("nvme", &log).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn parse_propolis_ps() {
+    let ps = "  PID ARGS
+    1 /sbin/init
+  812 propolis-server run .falcon/violin.toml [::]:0
+  813 /usr/bin/propolis-server run /work/.falcon/piano.toml [::]:0 [::]:5900
+  814 propolis-server run config.json [::]:0
+  815 /opt/propolis-standalone vm.toml";
+    let procs = crate::parse_propolis_processes(ps);
+    assert_eq!(procs.len(), 2);
+    assert_eq!(procs[0], (812, ".falcon/violin.toml".into()));
+    assert_eq!(procs[1], (813, "/work/.falcon/piano.toml".into()));
+}
+
+#[test]
+fn parse_images() {
+    let out = "rpool/falcon/img/helios-2.5@base\t1562378240\t1700000000
+rpool/falcon/img/helios-2.5@old\t1562378240\t1690000000
+rpool/falcon/img/debian-11.0_2024@base\t987654321\t1710000000
+rpool/falcon/img/nested/thing@base\t1\t1";
+    let images = crate::parse_image_list("rpool/falcon/img", out);
+    assert_eq!(images.len(), 2);
+    assert_eq!(images[0].name, "helios-2.5");
+    assert_eq!(images[0].size, 1562378240);
+    assert_eq!(
+        images[0].created,
+        std::time::UNIX_EPOCH + std::time::Duration::from_secs(1700000000)
+    );
+    assert_eq!(images[1].name, "debian-11.0_2024");
+}
+
+#[test]
+fn parse_clones() {
+    let out = "-
+rpool/falcon/topo/duo/violin,rpool/falcon/topo/duo/piano
+-
+rpool/falcon/topo/solo/violin";
+    assert_eq!(
+        crate::parse_zfs_clones(out),
+        vec![
+            "rpool/falcon/topo/duo/violin",
+            "rpool/falcon/topo/duo/piano",
+            "rpool/falcon/topo/solo/violin",
+        ]
+    );
+    assert!(crate::parse_zfs_clones("-\n-\n").is_empty());
+}
+
+#[test]
+fn parse_sha256() {
+    let digest =
+        "9F86D081884C7D659A2FEAA0C55AD015A3BF4F1B2B0B822CD15D6C15B0F00A08";
+    assert_eq!(
+        crate::parse_sha256_file(&format!("{digest}  helios-2.5_0.raw.xz\n"))
+            .unwrap(),
+        digest.to_ascii_lowercase()
+    );
+    assert_eq!(
+        crate::parse_sha256_file(&format!("{digest}\n")).unwrap(),
+        digest.to_ascii_lowercase()
+    );
+    assert!(crate::parse_sha256_file("").is_err());
+    assert!(crate::parse_sha256_file("<html>not found</html>").is_err());
+}
+
+#[test]
+fn zfs_opts_validation() {
+    let opts = crate::ZfsOpts::default();
+    assert!(opts.validate().is_ok());
+    assert_eq!(opts.volblocksize_bytes(), Some(4096));
+
+    let opts = crate::ZfsOpts {
+        compression: Some("gzip-6".into()),
+        volblocksize: "16384".into(),
+        sync: "standard".into(),
+    };
+    assert!(opts.validate().is_ok());
+    assert_eq!(opts.volblocksize_bytes(), Some(16384));
+
+    let opts = crate::ZfsOpts {
+        compression: Some("lz5".into()),
+        volblocksize: "3k".into(),
+        sync: "sometimes".into(),
+    };
+    match opts.validate() {
+        Err(crate::error::Error::Validation(problems)) => {
+            assert_eq!(problems.len(), 3, "{problems:?}")
+        }
+        other => panic!("expected validation error, got {other:?}"),
+    }
+    assert_eq!(opts.volblocksize_bytes(), None);
+
+    let mut d = crate::Runner::new("zfsopts");
+    d.persistent = true;
+    let big = crate::ZfsOpts {
+        volblocksize: "256k".into(),
+        ..Default::default()
+    };
+    assert!(d.set_zfs_opts(big).is_err());
+    assert_eq!(d.zfs_opts, crate::ZfsOpts::default());
+}
+
+#[test]
+fn zfs_command_error() {
+    use std::os::unix::process::ExitStatusExt;
+
+    let e = crate::error::Error::ZfsCommand(crate::error::ZfsCommandError {
+        argv: vec!["/usr/sbin/zfs".into(), "snapshot".into(), "a@b".into()],
+        status: std::process::ExitStatus::from_raw(1 << 8),
+        stdout: String::new(),
+        stderr: "cannot open 'a': dataset does not exist\n".into(),
+    });
+    assert_eq!(
+        e.to_string(),
+        "`/usr/sbin/zfs snapshot a@b` failed (exit status: 1): \
+         cannot open 'a': dataset does not exist"
+    );
+}
+
+#[test]
+fn propolis_version() {
+    use crate::propolis_version_rev;
+
+    assert_eq!(
+        propolis_version_rev("propolis-server 0.1.0 (fae5334)"),
+        Some("fae5334")
+    );
+    assert_eq!(
+        propolis_version_rev(&format!(
+            "propolis-server {}",
+            crate::PROPOLIS_REV
+        )),
+        Some(crate::PROPOLIS_REV)
+    );
+    assert_eq!(propolis_version_rev("propolis-server 0.1.0"), None);
+    assert_eq!(propolis_version_rev("propolis-server 20240101"), None);
+}
+
+#[test]
+fn guest_panic_detection() -> Result<()> {
+    use crate::serial::{
+        find_output_panic, find_panic, panic_regexes, DEFAULT_PANIC_PATTERNS,
+    };
+
+    let illumos = panic_regexes(DEFAULT_PANIC_PATTERNS)?;
+    let out = "root@violin:~# ./crash\r\n\r\n\
+        panic[cpu0]/thread=fffffe00: BAD TRAP\r\n\
+        dumping to /dev/zvol/dsk/rpool/dump, offset 65536\r\n";
+    let start = find_panic(&illumos, out).unwrap();
+    assert!(out[start..].starts_with("panic[cpu0]/thread="));
+    assert_eq!(find_panic(&illumos, "no trouble here\n"), None);
+    assert_eq!(find_panic(&[], out), None);
+
+    let linux = panic_regexes(&["Kernel panic - not syncing"])?;
+    let out = "ok\n[ 1.2] Kernel panic - not syncing: Fatal exception\n";
+    assert_eq!(find_panic(&linux, out), Some(3));
+    assert_eq!(find_panic(&linux, "panic[cpu0]/thread="), None);
+
+    // a command line mentioning a panic pattern is not a panic
+    let out = "grep -c 'panic[cpu0]' /var/adm/messages\r\n0\r\n";
+    assert_eq!(find_output_panic(&illumos, out, true), None);
+    assert_eq!(find_output_panic(&illumos, out, false), Some(0));
+    assert_eq!(find_output_panic(&illumos, "panic[cpu0]/thr", true), None);
+    let out = "./crash\r\npanic[cpu0]/thread=fffffe00: BAD TRAP\r\n";
+    assert_eq!(find_output_panic(&illumos, out, true), Some(9));
+
+    let mut d = crate::Runner::new("panic");
+    d.persistent = true;
+    assert!(d.set_panic_patterns(&["panic[("]).is_err());
+    assert!(d.panic_patterns.is_empty());
+    d.enable_panic_detection(true);
+    assert_eq!(d.panic_patterns.len(), DEFAULT_PANIC_PATTERNS.len());
+    Ok(())
+}
+
+#[test]
+fn guest_os_setup() {
+    use crate::serial::{shell_prompt, GuestOs};
+    use regex::Regex;
+
+    let illumos = Regex::new(&shell_prompt("root", GuestOs::Illumos)).unwrap();
+    assert!(illumos.is_match("root@violin:~# "));
+    assert!(!illumos.is_match("violin:~# "));
+
+    let linux = Regex::new(&shell_prompt("root", GuestOs::Linux)).unwrap();
+    assert!(linux.is_match("root@violin:~# "));
+    assert!(linux.is_match("[root@violin ~]# "));
+    assert!(linux.is_match("violin:~# "));
+    assert!(!linux.is_match("violin:~# ls\r\n"));
+
+    assert_eq!(
+        crate::linux_addr_cmd(1, &"10.0.0.1/24"),
+        "ifname=$(basename $(ls -d \
+         /sys/bus/virtio/drivers/virtio_net/virtio*/net/* \
+         | sort -V | sed -n '2p')) && \
+         ip link set $ifname up && ip addr add 10.0.0.1/24 dev $ifname",
+    );
+}
+
+#[test]
+fn console_offset_url() {
+    use crate::serial::{console_url, ConsoleOffset};
+
+    let addr = "[::1]:4000".parse().unwrap();
+    assert_eq!(console_url(addr, None), "ws://[::1]:4000/instance/serial");
+    assert_eq!(
+        console_url(addr, Some(ConsoleOffset::FromStart(0))),
+        "ws://[::1]:4000/instance/serial?from_start=0"
+    );
+    assert_eq!(
+        console_url(addr, Some(ConsoleOffset::MostRecent(4096))),
+        "ws://[::1]:4000/instance/serial?most_recent=4096"
+    );
+}
+
+#[test]
+fn node_env() -> Result<()> {
+    use std::collections::HashMap;
+
+    let mut d = crate::Runner::new("env");
+    let violin = d.node("violin", "helios-2.5", 1, 1024);
+    d.persistent = true;
+
+    let env = HashMap::from([("1PATH".to_string(), "x".to_string())]);
+    assert!(d.set_node_env(violin, env).is_err());
+
+    let env = HashMap::from([
+        ("PATH".to_string(), "/opt/ooce/bin:$PATH".to_string()),
+        ("GREETING".to_string(), "say \"hi\" `id` \\o/".to_string()),
+    ]);
+    d.set_node_env(violin, env)?;
+    assert_eq!(
+        crate::serial::export_env(&d.get_node(violin).env).as_deref(),
+        Some(
+            "export GREETING=\"say \\\"hi\\\" \\`id\\` \\\\o/\" \
+             PATH=\"/opt/ooce/bin:$PATH\""
+        )
+    );
+    assert_eq!(crate::serial::export_env(&Default::default()), None);
+    Ok(())
+}
+
+#[test]
+fn host_memory() {
+    let out = "zfs:0:arcstats:size\t8589934592\n\
+        zfs:0:arcstats:c_min\t1073741824\n";
+    assert_eq!(crate::parse_arc_reclaimable(out), Some(7 << 30));
+    assert_eq!(
+        crate::parse_arc_reclaimable("zfs:0:arcstats:size\t1\n"),
+        None
+    );
+    assert_eq!(crate::parse_arc_reclaimable(""), None);
+
+    assert!(crate::check_memory(4096, 4096).is_ok());
+    assert!(crate::check_memory(4097, 4096).is_err());
+}
+
+#[test]
+fn emit_output_lines() {
+    use crate::serial::emit_lines;
+
+    let mut lines = Vec::new();
+    let mut emitted = 0;
+    let mut buf = String::from("cmd\r\nfir");
+    emit_lines(&buf, &mut emitted, false, &mut |l| {
+        lines.push(l.to_string())
+    });
+    assert_eq!(lines, vec!["cmd"]);
+
+    buf.push_str("st\nsecond\nthi");
+    emit_lines(&buf, &mut emitted, false, &mut |l| {
+        lines.push(l.to_string())
+    });
+    assert_eq!(lines, vec!["cmd", "first", "second"]);
+
+    // without a newline nothing new is complete
+    emit_lines(&buf, &mut emitted, false, &mut |l| {
+        lines.push(l.to_string())
+    });
+    assert_eq!(lines.len(), 3);
+
+    buf.push_str("rd");
+    emit_lines(&buf, &mut emitted, true, &mut |l| lines.push(l.to_string()));
+    assert_eq!(lines, vec!["cmd", "first", "second", "third"]);
+    assert_eq!(emitted, buf.len());
+}
+
+#[test]
+fn strip_ansi_output() {
+    let colored =
+        "\x1b[1;31merror\x1b[0m: \x1b]0;title\x07bad\r\n\tthing\x1b(B";
+    assert_eq!(crate::serial::strip_ansi(colored), "error: bad\n\tthing");
+    assert_eq!(crate::serial::strip_ansi("plain text"), "plain text");
+}
+
+#[test]
+fn topology_dot() {
+    let mut d = crate::Runner::new("dot");
+    let violin = d.node("violin", "helios-2.5", 2, 2048);
+    let piano = d.node("piano", "debian-11.0", 1, 1024);
+    d.link(violin, piano);
+    d.ext_link("igb0", piano);
+    d.persistent = true;
+
+    let dot = d.deployment.to_dot();
+    assert!(dot.starts_with("graph \"dot\" {\n"));
+    assert!(dot.contains(
+        "\"violin\" [label=\"violin\\nhelios-2.5\\n2 cores, 2048 MB\"];"
+    ));
+    assert!(dot.contains(
+        "\"violin\" -- \"piano\" [taillabel=\"0 viona\", headlabel=\"0 viona\"];"
+    ));
+    assert!(dot.contains("\"host:igb0\" [shape=ellipse, label=\"igb0\"];"));
+    assert!(dot.contains("\"piano\" -- \"host:igb0\" [taillabel=\"1 viona\"];"));
+    assert!(dot.ends_with("}\n"));
+}
+
+#[test]
+fn vnic_mac_prefix() -> Result<()> {
+    let mut d = crate::Runner::new("macs");
+    let violin = d.node("violin", "helios-2.5", 1, 1024);
+    let piano = d.node("piano", "helios-2.5", 1, 1024);
+    d.link(violin, piano);
+    d.softnpu_link(violin, piano, Some("a8:40:25:ff:00:01".into()), None);
+    d.persistent = true;
+
+    let links = &d.deployment.links;
+    assert_eq!(d.deployment.vnic_mac(&links[0].endpoints[1])?, None);
+
+    d.set_mac_prefix([0xa8, 0x40, 0x25]);
+    let links = &d.deployment.links;
+    let macs = [
+        d.deployment.vnic_mac(&links[0].endpoints[0])?,
+        d.deployment.vnic_mac(&links[0].endpoints[1])?,
+        d.deployment.vnic_mac(&links[1].endpoints[1])?,
+    ];
+    assert_eq!(macs[0], Some(vec![0xa8, 0x40, 0x25, 0, 0, 0]));
+    assert_eq!(macs[1], Some(vec![0xa8, 0x40, 0x25, 0, 1, 0]));
+    // explicit macs take precedence over the prefix
+    assert_eq!(macs[2], Some(vec![0xa8, 0x40, 0x25, 0xff, 0, 1]));
+
+    Ok(())
+}
+
+#[test]
+fn softnpu_mac() -> Result<()> {
+    let mut d = crate::Runner::new("snmac");
+    let scrimlet = d.node("scrimlet", "helios-2.5", 1, 1024);
+    let piano = d.node("piano", "helios-2.5", 1, 1024);
+    let cello = d.node("cello", "helios-2.5", 1, 1024);
+    d.softnpu_link(scrimlet, piano, None, Some("a8:40:25:ff:00:01".into()));
+    d.softnpu_link(scrimlet, cello, None, None);
+    d.persistent = true;
+
+    let spec = d.node_spec(scrimlet)?;
+    assert_eq!(
+        spec.devices["port0"].options["mac"],
+        toml::Value::from("a8:40:25:ff:00:01")
+    );
+    assert!(!spec.devices["port1"].options.contains_key("mac"));
+
+    // the vnic backing the port on the host carries the same mac
+    let links = &d.deployment.links;
+    assert_eq!(
+        d.deployment.vnic_mac(&links[0].endpoints[0])?,
+        Some(vec![0xa8, 0x40, 0x25, 0xff, 0, 1])
+    );
+    assert_eq!(d.deployment.vnic_mac(&links[1].endpoints[0])?, None);
+
+    d.softnpu_link(scrimlet, cello, None, Some("a8:40:25:ff:00".into()));
+    assert!(d.deployment.validate().is_err());
+    Ok(())
+}
+
+#[test]
+fn softnpu_config() -> Result<()> {
+    let dir = Utf8PathBuf::from(format!(
+        "/tmp/falcon-softnpu-{}",
+        uuid::Uuid::new_v4()
+    ));
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join("libsidecar_lite.so"), b"")?;
+    std::fs::write(dir.join("rules.sh"), b"")?;
+
+    let mut d = crate::Runner::new("snconfig");
+    let scrimlet = d.node("scrimlet", "helios-2.5", 1, 1024);
+    let piano = d.node("piano", "helios-2.5", 1, 1024);
+    d.persistent = true;
+    assert!(d
+        .set_softnpu_config(scrimlet, dir.join("missing.so"), None)
+        .is_err());
+    d.set_softnpu_config(
+        scrimlet,
+        dir.join("libsidecar_lite.so"),
+        Some(dir.join("rules.sh")),
+    )?;
+
+    // the config needs a softnpu port to load the program into
+    assert!(d.deployment.validate().is_err());
+    d.softnpu_link(scrimlet, piano, None, None);
+    d.deployment.validate()?;
+
+    let softnpu = d.deployment.nodes[0].softnpu.as_ref().unwrap();
+    assert_eq!(
+        softnpu.guest_program(),
+        "/opt/falcon/softnpu/program/libsidecar_lite.so"
+    );
+    assert_eq!(
+        softnpu.guest_config().unwrap(),
+        "/opt/falcon/softnpu/config/rules.sh"
+    );
+
+    // p9kp pushes the program to the first p9 device, the softnpu one
+    let spec = d.node_spec(scrimlet)?;
+    assert_eq!(
+        spec.devices["softnpup9"].options["pci-path"],
+        toml::Value::from("0.5.0")
+    );
+    d.mount("/tmp", "/opt/tmp", scrimlet)?;
+    assert!(d.deployment.validate().is_err());
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn cpuid_profile() -> Result<()> {
+    use crate::cpuid::{CpuidEntries, CpuidEntry, CpuidVendor};
+
+    let mut cpuid = CpuidEntries::new(
+        CpuidVendor::Amd,
+        vec![
+            CpuidEntry {
+                leaf: 1,
+                subleaf: None,
+                values: [0x00a50f00, 0, 0xfef8_3203, 0x178b_fbff],
+            },
+            CpuidEntry {
+                leaf: 7,
+                subleaf: Some(0),
+                values: [0, 0x219c_97a9, 0, 0],
+            },
+        ],
+    );
+    cpuid.disable_feature("avx")?;
+    cpuid.disable_feature("avx2")?;
+    assert!(cpuid.disable_feature("nope").is_err());
+    assert_eq!(cpuid.entries[0].values[2], 0xeef8_3203);
+    assert_eq!(cpuid.entries[1].values[1], 0x219c_9789);
+
+    let profile = cpuid.to_profile();
+    assert!(matches!(
+        profile.vendor,
+        propolis_server_config::CpuVendor::Amd
+    ));
+    assert_eq!(profile.leaf["1"][2], 0xeef8_3203);
+    assert_eq!(profile.leaf["7-0"][1], 0x219c_9789);
+
+    Ok(())
+}
+
+#[test]
+fn exit_status_output() -> Result<()> {
+    use crate::serial::split_exit_status;
+
+    let cmd = crate::serial::with_exit_status("false");
+    assert!(!cmd.contains("__FALCON_RC__"));
+
+    assert_eq!(
+        split_exit_status("n", "one\ntwo\n__FALCON_RC__0")?,
+        ("one\ntwo".to_string(), 0)
+    );
+    // output that does not end in a newline
+    assert_eq!(
+        split_exit_status("n", "partial__FALCON_RC__127")?,
+        ("partial".to_string(), 127)
+    );
+    assert_eq!(
+        split_exit_status("n", "__FALCON_RC__1")?,
+        ("".to_string(), 1)
+    );
+    assert!(split_exit_status("n", "no status").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn validate_topology() {
+    let mut d = crate::Runner::new("valid");
+    let violin = d.node("violin", "helios-2.5", 1, 1024);
+    let piano = d.node("piano", "helios-2.5", 1, 1024);
+    d.link(violin, piano);
+    d.persistent = true;
+    assert!(d.deployment.validate().is_ok());
+
+    d.node("violin", "helios-2.5", 0, 1024);
+    d.deployment.nodes[1].radix = 2;
+    match d.deployment.validate() {
+        Err(crate::error::Error::Validation(problems)) => {
+            assert_eq!(problems.len(), 3, "{:?}", problems);
+        }
+        other => panic!("expected validation error: {:?}", other),
+    }
+}
+
+#[test]
+fn validate_extra_disk_names() {
+    let mut d = crate::Runner::new("disknames");
+    let violin = d.node("violin", "helios-2.5", 1, 1024);
+    d.add_disk(violin, 10, crate::PrimaryDiskBacking::Zvol);
+    d.node("violin_disk1", "helios-2.5", 1, 1024);
+    d.persistent = true;
+    assert!(d.deployment.validate().is_ok());
+
+    d.add_disk(violin, 10, crate::PrimaryDiskBacking::Zvol);
+    match d.deployment.validate() {
+        Err(crate::error::Error::Validation(problems)) => {
+            assert_eq!(
+                problems,
+                vec!["extra disk 1 of node violin has the same backing as \
+                      node violin_disk1"
+                    .to_string()],
+            );
+        }
+        other => panic!("expected validation error: {:?}", other),
+    }
+}
+
+#[test]
+fn boot_priority_order() {
+    let mut d = crate::Runner::new("boot");
+    let violin = d.node("violin", "helios-2.5", 1, 1024);
+    let piano = d.node("piano", "helios-2.5", 1, 1024);
+    let router = d.node("router", "helios-2.5", 1, 1024);
+    d.node("cello", "helios-2.5", 1, 1024);
+    d.set_boot_priority(router, 2);
+    d.set_boot_priority(violin, 1);
+    d.set_boot_priority(piano, 1);
+    d.persistent = true;
+
+    let order: Vec<Vec<&str>> = d
+        .deployment
+        .boot_order()
+        .iter()
+        .map(|g| g.iter().map(|n| n.name.as_str()).collect())
+        .collect();
+    assert_eq!(
+        order,
+        vec![vec!["router"], vec!["violin", "piano"], vec!["cello"]]
+    );
+}
+
+#[test]
+fn plan_mount_chunk_size() -> Result<()> {
+    let mut d = crate::Runner::new("plan");
+    let violin = d.node("violin", "helios-2.5", 1, 1024);
+    let opts = crate::MountOptions {
+        chunk_size: 262144,
+        ..Default::default()
+    };
+    d.mount_with_opts("/tmp", "/opt/tmp", violin, opts)?;
+    d.persistent = true;
+
+    let plan = d.plan()?;
+    let fs = &plan.configs["violin"].devices["fs0"];
+    assert_eq!(fs.options["chunk_size"], toml::Value::Integer(262144));
+    assert_eq!(fs.options["target"], toml::Value::from("fs0"));
+    Ok(())
+}
+
+#[test]
+fn node_spec_mounts() -> Result<()> {
+    let mut d = crate::Runner::new("spec");
+    let violin = d.node("violin", "helios-2.5", 1, 1024);
+    let piano = d.node("piano", "helios-2.5", 1, 1024);
+    d.mount("/tmp", "/opt/a", violin)?;
+    // p9kp only serves the first mount
+    assert!(d.mount("/tmp", "/opt/b", violin).is_err());
+    d.mount_linux("/tmp", "/opt/b", violin)?;
+    d.persistent = true;
+
+    let spec = d.node_spec(violin)?;
+    let slot = |dev: &str| spec.devices[dev].options["pci-path"].clone();
+    assert_eq!(spec.devices["fs0"].driver, "pci-virtio-9p");
+    assert_eq!(spec.devices["fs1"].driver, "pci-virtio-9p");
+    assert_eq!(slot("fs0"), toml::Value::from("0.5.0"));
+    assert_eq!(slot("fs1"), toml::Value::from("0.6.0"));
+
+    let spec = d.node_spec(piano)?;
+    assert!(!spec.devices.contains_key("fs0"));
+    assert_eq!(
+        toml::to_string(&spec)?,
+        toml::to_string(&d.plan()?.configs["piano"])?
+    );
+    Ok(())
+}
+
+#[test]
+fn plan_image_version() -> Result<()> {
+    let mut d = crate::Runner::new("versions");
+    let violin = d.node("violin", "helios-2.5", 1, 1024);
+    let piano = d.node("piano", "helios-2.5", 1, 1024);
+    d.node("cello", "helios-2.5", 1, 1024);
+    d.set_image_version(violin, "20240101");
+    d.set_image_version(piano, "20240101");
+    d.persistent = true;
+
+    let plan = d.plan()?;
+    let dataset = &d.dataset;
+    assert_eq!(
+        plan.images,
+        vec![
+            format!("{dataset}/img/helios-2.5_20240101@base"),
+            format!("{dataset}/img/helios-2.5@base"),
+        ]
+    );
+
+    d.set_image_version(piano, "../latest");
+    assert!(d.plan().is_err());
+    Ok(())
+}
+
+#[test]
+fn plan_iso() -> Result<()> {
+    let iso = camino::Utf8PathBuf::from_path_buf(std::env::temp_dir())
+        .map_err(|p| anyhow!("non utf-8 temp dir {}", p.display()))?
+        .join("falcon-plan-test.iso");
+    std::fs::write(&iso, b"")?;
+
+    let mut d = crate::Runner::new("iso");
+    let violin = d.node("violin", "helios-2.5", 1, 1024);
+    d.attach_iso(violin, &iso)?;
+    d.persistent = true;
+
+    let plan = d.plan()?;
+    let config = &plan.configs["violin"];
+    assert_eq!(
+        config.devices["isoblock0"].options["pci-path"],
+        toml::Value::from("0.5.0")
+    );
+    assert_eq!(config.block_devs["iso0"].opts.read_only, Some(true));
+
+    std::fs::remove_file(&iso)?;
+    Ok(())
+}
+
+#[test]
+fn link_addrs() -> Result<()> {
+    let mut d = crate::Runner::new("addrs");
+    let violin = d.node("violin", "helios-2.5", 1, 1024);
+    let piano = d.node("piano", "helios-2.5", 1, 1024);
+    let cello = d.node("cello", "helios-2.5", 1, 1024);
+    d.link(violin, cello);
+    d.ext_link("igb0", piano);
+    let l = d.link(violin, piano);
+    d.persistent = true;
+
+    d.set_link_addr(l, 0, "10.0.0.1/24".parse::<oxnet::IpNet>()?)?;
+    d.set_link_addr(l, 1, "fd00::2/64".parse::<oxnet::IpNet>()?)?;
+    assert!(d
+        .set_link_addr(l, 2, "10.0.0.3/24".parse::<oxnet::IpNet>()?)
+        .is_err());
+
+    let link = &d.deployment.links[1];
+    assert_eq!(link.addrs.len(), 2);
+    let ifnames: Vec<_> = link
+        .endpoints
+        .iter()
+        .map(|e| d.deployment.guest_ifname(e))
+        .collect();
+    // links come before external links in pci slot order
+    assert_eq!(
+        ifnames,
+        vec![Some("vioif1".to_string()), Some("vioif0".to_string())]
+    );
+    assert_eq!(d.guest_ifname(piano, l), Some("vioif0".to_string()));
+    assert_eq!(d.guest_ifname(cello, l), None);
+    Ok(())
+}
+
+#[test]
+fn link_introspection() {
+    let mut d = crate::Runner::new("links");
+    let violin = d.node("violin", "helios-2.5", 1, 1024);
+    let piano = d.node("piano", "helios-2.5", 1, 1024);
+    let cello = d.node("cello", "helios-2.5", 1, 1024);
+    d.link(violin, piano);
+    d.softnpu_link(cello, piano, None, None);
+    d.persistent = true;
+
+    let links = d.all_links();
+    assert_eq!(links.len(), 2);
+    let describe = |l| -> Vec<(String, usize, &str)> {
+        d.get_link(l)
+            .endpoints
+            .iter()
+            .map(|e| {
+                let node = d.get_node(e.node()).name.clone();
+                (node, e.index(), e.kind().kind_name())
+            })
+            .collect()
+    };
+    assert_eq!(
+        describe(links[0]),
+        vec![("violin".into(), 0, "viona"), ("piano".into(), 0, "viona")]
+    );
+    assert_eq!(
+        describe(links[1]),
+        vec![("cello".into(), 0, "softnpu"), ("piano".into(), 1, "viona")]
+    );
+}
+
+#[test]
+fn find_link() {
+    let mut d = crate::Runner::new("findlink");
+    let violin = d.node("violin", "helios-2.5", 1, 1024);
+    let piano = d.node("piano", "helios-2.5", 1, 1024);
+    let cello = d.node("cello", "helios-2.5", 1, 1024);
+    d.link(violin, piano);
+    d.link(piano, cello);
+    d.link(cello, piano);
+    d.persistent = true;
+
+    let index = |l: Option<crate::LinkRef>| l.map(|l| l._index);
+    assert_eq!(index(d.find_link(violin, piano)), Some(0));
+    assert_eq!(index(d.find_link(piano, violin)), Some(0));
+    // the first of several links between the same nodes
+    assert_eq!(index(d.find_link(cello, piano)), Some(1));
+    assert_eq!(index(d.find_link(violin, cello)), None);
+}
+
+#[test]
+fn peer_hosts() -> Result<()> {
+    let mut d = crate::Runner::new("hosts");
+    let violin = d.node("violin", "helios-2.5", 1, 1024);
+    let piano = d.node("piano", "helios-2.5", 1, 1024);
+    let cello = d.node("cello", "helios-2.5", 1, 1024);
+    let vc = d.link(violin, cello);
+    let pc = d.link(piano, cello);
+    d.persistent = true;
+    d.enable_peer_hosts(true);
+
+    d.set_link_addr(vc, 0, "10.0.0.1/24".parse::<oxnet::IpNet>()?)?;
+    d.set_link_addr(vc, 1, "10.0.0.2/24".parse::<oxnet::IpNet>()?)?;
+    d.set_link_addr(pc, 0, "fd00::1/64".parse::<oxnet::IpNet>()?)?;
+    d.set_link_addr(pc, 1, "fd00::2/64".parse::<oxnet::IpNet>()?)?;
+
+    let hosts = |name| -> Vec<String> {
+        d.deployment
+            .peer_hosts(name)
+            .into_iter()
+            .map(|(addr, peer)| format!("{addr} {peer}"))
+            .collect()
+    };
+    assert_eq!(
+        hosts("violin"),
+        vec!["10.0.0.2 cello", "fd00::1 piano", "fd00::2 cello"]
+    );
+    assert_eq!(
+        hosts("piano"),
+        vec!["fd00::2 cello", "10.0.0.1 violin", "10.0.0.2 cello"]
+    );
+    assert_eq!(hosts("cello"), vec!["10.0.0.1 violin", "fd00::1 piano"]);
+
+    assert_eq!(
+        crate::hosts_entry_cmd("10.0.0.2 cello"),
+        "grep -qxF '10.0.0.2 cello' /etc/hosts || \
+         echo '10.0.0.2 cello' >> /etc/hosts",
+    );
+    Ok(())
+}
+
+#[test]
+fn ipadm_v6_addr() {
+    let addr = "fe80::8:20ff:fe35:1f12"
+        .parse::<std::net::Ipv6Addr>()
+        .unwrap();
+    for out in [
+        "fe80::8:20ff:fe35:1f12/10",
+        "fe80::8:20ff:fe35:1f12%vioif0/10\n",
+        "fe80\\:\\:8\\:20ff\\:fe35\\:1f12/10",
+    ] {
+        assert_eq!(crate::parse_ipadm_v6(out), Some(addr), "{out}");
+    }
+    assert_eq!(crate::parse_ipadm_v6(""), None);
+    assert_eq!(crate::parse_ipadm_v6("10.0.0.1/24"), None);
+}
+
+#[test]
+fn libnet_retry_backoff() {
+    use std::time::Duration;
+
+    let retry = crate::LibnetRetry::default();
+    let delays: Vec<u128> =
+        (0..8).map(|i| retry.backoff(i).as_millis()).collect();
+    assert_eq!(delays, [100, 200, 400, 800, 1600, 3200, 4000, 4000]);
+    assert_eq!(retry.backoff(40), Duration::from_secs(4));
+
+    let retry = crate::LibnetRetry {
+        attempts: 3,
+        initial_backoff: Duration::from_secs(1),
+        max_backoff: Duration::from_secs(u64::MAX),
+    };
+    assert_eq!(retry.backoff(2), Duration::from_secs(4));
+    assert_eq!(retry.backoff(40), Duration::from_secs(u64::MAX));
+}
+
+#[test]
+fn ensure_failure_summary() {
+    let failures: Vec<String> =
+        ["refused", "refused", "refused", "500", "refused"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+    assert_eq!(
+        crate::summarize_failures(&failures),
+        "refused (x3), then 500, then refused"
+    );
+    assert_eq!(crate::summarize_failures(&[]), "");
+
+    let retry = crate::EnsureRetry::default();
+    assert_eq!(retry.attempts, None);
+    assert_eq!(retry.interval, std::time::Duration::from_secs(1));
+}
+
+#[test]
+fn cli_opts_from_args() -> Result<()> {
+    use crate::cli::{Opts, SubCommand};
+    use clap::Parser;
+
+    let opts =
+        Opts::try_parse_from(["falcon", "netcreate", "--link", "a", "b"])?;
+    match opts.subcmd {
+        SubCommand::Netcreate(c) => {
+            assert_eq!(c.link, Some(vec!["a".to_string(), "b".to_string()]))
+        }
+        _ => return Err(anyhow!("expected netcreate")),
+    }
+
+    let opts = Opts::try_parse_from(["falcon", "launch", "-f", "/tmp/f"])?;
+    match opts.subcmd {
+        SubCommand::Launch(c) => {
+            assert_eq!(c.falcon_dir, Utf8PathBuf::from("/tmp/f"));
+            assert!(!c.dry_run);
+        }
+        _ => return Err(anyhow!("expected launch")),
+    }
+
+    assert!(
+        Opts::try_parse_from(["falcon", "netcreate", "--link", "a"]).is_err()
+    );
+    Ok(())
+}
+
+#[test]
+fn iperf3_report() -> Result<()> {
+    let out = r#"{
+        "start": {"connected": []},
+        "end": {
+            "sum_sent": {"bytes": 125000000, "bits_per_second": 100000000.0,
+                         "retransmits": 7},
+            "sum_received": {"bytes": 124000000, "bits_per_second": 99200000.0}
+        }
+    }"#;
+    let report = crate::parse_iperf3("violin", out)?;
+    assert_eq!(report.sent_mbps, 100.0);
+    assert_eq!(report.received_mbps, 99.2);
+    assert_eq!(report.retransmits, Some(7));
+
+    let out = r#"{"end": {"sum_sent": {"bits_per_second": 1e6},
+                           "sum_received": {"bits_per_second": 1e6}}}"#;
+    assert_eq!(crate::parse_iperf3("violin", out)?.retransmits, None);
+
+    let out = r#"{"start": {}, "end": {},
+                  "error": "unable to connect to server: Connection refused"}"#;
+    let err = crate::parse_iperf3("violin", out).unwrap_err().to_string();
+    assert!(err.contains("Connection refused"), "{err}");
+    assert!(crate::parse_iperf3("violin", "iperf3: not found").is_err());
+    Ok(())
+}
+
+#[test]
+fn propolis_log_filter() -> Result<()> {
+    let mut d = crate::Runner::new("plog");
+    d.persistent = true;
+    assert_eq!(d.deployment.propolis_log, None);
+
+    d.set_propolis_log("debug".into());
+    assert_eq!(d.deployment.propolis_log.as_deref(), Some("debug"));
+
+    // the filter is recorded in the topology for hyperstart
+    let topo = ron::ser::to_string(&d.deployment)?;
+    let read: crate::Deployment = ron::de::from_str(&topo)?;
+    assert_eq!(read.propolis_log.as_deref(), Some("debug"));
+    Ok(())
+}
+
+#[test]
+fn propolis_launch_settings_recorded() -> Result<()> {
+    let mut d = crate::Runner::new("launchset");
+    d.node("violin", "helios-2.5", 1, 1024);
+    d.persistent = true;
+
+    let retry = crate::EnsureRetry {
+        attempts: Some(5),
+        interval: std::time::Duration::from_millis(250),
+    };
+    d.set_propolis_args(vec!["--metrics-addr".into(), "[::1]:8001".into()]);
+    d.set_launch_timeout(std::time::Duration::from_secs(120));
+    d.set_ensure_retry(retry);
+
+    // hyperstart relaunches nodes from the topology with these settings
+    let topo = ron::ser::to_string(&d.deployment)?;
+    let read: crate::Deployment = ron::de::from_str(&topo)?;
+    assert_eq!(read.propolis_args, ["--metrics-addr", "[::1]:8001"]);
+    assert_eq!(read.launch_timeout, std::time::Duration::from_secs(120));
+    assert_eq!(read.ensure_retry, retry);
+    Ok(())
+}
+
 fn check_link_absent(name: &String) -> Result<()> {
     let h = libnet::LinkHandle::Name(name.clone());
     match h.id() {
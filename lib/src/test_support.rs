@@ -0,0 +1,87 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Helpers for tests that launch deployments. This module is available to
+//! other crates with the `test-support` feature.
+
+use crate::Runner;
+use camino::Utf8PathBuf;
+use slog::error;
+use std::fs;
+use std::ops::{Deref, DerefMut};
+
+/// A deployment for a single test. Its falcon directory is a fresh directory
+/// under the system temporary directory, and the propolis-server binary is
+/// taken from the `FALCON_PROPOLIS_BINARY` environment variable if set. The
+/// deployment is destroyed and its falcon directory removed when this is
+/// dropped, including when the test panics, so a failing test does not leak
+/// zvols or links. A `TestDeployment` dereferences to its [`Runner`].
+///
+/// ```no_run
+/// # async fn f() -> Result<(), libfalcon::error::Error> {
+/// use libfalcon::test_support::TestDeployment;
+///
+/// let mut d = TestDeployment::new("solo");
+/// let violin = d.node("violin", "helios-2.5", 1, 1024);
+/// d.launch().await?;
+/// d.exec(violin, "uname -a").await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct TestDeployment {
+    runner: Runner,
+}
+
+impl TestDeployment {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "falcon-{}-{}",
+            name,
+            uuid::Uuid::new_v4()
+        ));
+        let dir = Utf8PathBuf::from_path_buf(dir)
+            .expect("temporary directory is not utf-8");
+        fs::create_dir_all(&dir).expect("create test falcon directory");
+
+        let mut builder = Runner::builder(name).falcon_dir(dir);
+        if let Ok(path) = std::env::var("FALCON_PROPOLIS_BINARY") {
+            builder = builder.propolis_binary(path);
+        }
+        TestDeployment {
+            runner: builder.build(),
+        }
+    }
+}
+
+impl Deref for TestDeployment {
+    type Target = Runner;
+
+    fn deref(&self) -> &Runner {
+        &self.runner
+    }
+}
+
+impl DerefMut for TestDeployment {
+    fn deref_mut(&mut self) -> &mut Runner {
+        &mut self.runner
+    }
+}
+
+impl Drop for TestDeployment {
+    fn drop(&mut self) {
+        let r = &mut self.runner;
+        if let Err(e) = r.destroy() {
+            error!(r.log, "cleanup failed: {}", e);
+        }
+        // the deployment is gone, keep the runner from destroying it again
+        r.persistent = true;
+        if r.falcon_dir.exists() {
+            if let Err(e) = fs::remove_dir_all(&r.falcon_dir) {
+                error!(r.log, "remove {}: {}", r.falcon_dir, e);
+            }
+        }
+    }
+}
@@ -4,8 +4,58 @@
 
 // Copyright 2022 Oxide Computer Company
 
+use camino::{Utf8Path, Utf8PathBuf};
+use std::fs;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+
 pub(crate) static NAME_REGEX: &str = r"^[A-Za-z]?[A-Za-z0-9_]*$";
 
+/// Write `contents` to `path` atomically. The data is first written and synced
+/// to a temporary file next to `path` which is then renamed over `path`. If
+/// the process dies before the rename, the previous contents of `path` are left
+/// untouched.
+pub(crate) fn write_atomic(
+    path: impl AsRef<Utf8Path>,
+    contents: impl AsRef<[u8]>,
+) -> std::io::Result<()> {
+    let path = path.as_ref();
+    let tmp = Utf8PathBuf::from(format!("{path}.tmp"));
+    let mut f = fs::File::create(&tmp)?;
+    f.write_all(contents.as_ref())?;
+    f.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// An exclusive advisory lock on a falcon directory. The lock is released when
+/// this is dropped.
+pub(crate) struct DirLock {
+    _file: fs::File,
+}
+
+/// Lock the falcon directory `dir`, creating it if it does not exist. The lock
+/// is a `flock` on `<dir>/.lock`. This does not wait for a lock held by another
+/// process, it fails with `WouldBlock` instead.
+pub(crate) fn lock_dir(dir: &Utf8Path) -> std::io::Result<DirLock> {
+    fs::create_dir_all(dir)?;
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(".lock"))?;
+    let ret =
+        unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(DirLock { _file: file })
+}
+
+/// Quote `s` for use as a single word in a POSIX shell command line.
+pub(crate) fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
 #[macro_export]
 macro_rules! die {
     ($x:expr, $($xs:expr),*) => {
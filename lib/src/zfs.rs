@@ -0,0 +1,95 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Helpers running the zfs command. A failed command is reported as an
+//! [`Error::ZfsCommand`] carrying the full command line, exit status and output
+//! of zfs.
+
+use crate::error::{Error, ZfsCommandError};
+use std::process::Command;
+
+const ZFS_BIN: &str = "/usr/sbin/zfs";
+
+/// Run zfs with `args`, returning its stdout.
+pub(crate) fn run<S: AsRef<str>>(args: &[S]) -> Result<String, Error> {
+    let args: Vec<&str> = args.iter().map(AsRef::as_ref).collect();
+    let out = Command::new(ZFS_BIN).args(&args).output()?;
+    let stdout = String::from_utf8_lossy(&out.stdout).into_owned();
+    if !out.status.success() {
+        let mut argv = vec![ZFS_BIN.to_string()];
+        argv.extend(args.iter().map(|x| x.to_string()));
+        return Err(Error::ZfsCommand(ZfsCommandError {
+            argv,
+            status: out.status,
+            stdout,
+            stderr: String::from_utf8_lossy(&out.stderr).into_owned(),
+        }));
+    }
+    Ok(stdout)
+}
+
+/// Whether the dataset, volume or snapshot `name` exists.
+pub(crate) fn exists(name: &str) -> Result<bool, Error> {
+    let out = Command::new(ZFS_BIN)
+        .args(["list", "-t", "all", name])
+        .output()?;
+    Ok(out.status.success())
+}
+
+/// Create the volume `name` of `size`, along with any missing parents. `opts`
+/// are passed to zfs as is, e.g. `["-o", "sync=disabled"]`.
+pub(crate) fn create_volume<S: AsRef<str>>(
+    name: &str,
+    size: &str,
+    opts: &[S],
+) -> Result<(), Error> {
+    let mut args = vec!["create", "-p", "-V", size];
+    args.extend(opts.iter().map(AsRef::as_ref));
+    args.push(name);
+    run(&args).map(drop)
+}
+
+/// Clone `snapshot` to `dest`, along with any missing parents of `dest`.
+pub(crate) fn clone(snapshot: &str, dest: &str) -> Result<(), Error> {
+    run(&["clone", "-p", snapshot, dest]).map(drop)
+}
+
+/// Create the snapshot `name`, e.g. `rpool/falcon/img/helios-2.5@base`.
+pub(crate) fn snapshot(name: &str) -> Result<(), Error> {
+    run(&["snapshot", name]).map(drop)
+}
+
+/// Promote the clone `name`, so it no longer depends on its origin.
+pub(crate) fn promote(name: &str) -> Result<(), Error> {
+    run(&["promote", name]).map(drop)
+}
+
+/// Roll back to `snapshot`, destroying any later snapshots.
+pub(crate) fn rollback(snapshot: &str) -> Result<(), Error> {
+    run(&["rollback", "-r", snapshot]).map(drop)
+}
+
+/// Destroy `name` and all of its descendants.
+pub(crate) fn destroy(name: &str) -> Result<(), Error> {
+    run(&["destroy", "-r", name]).map(drop)
+}
+
+/// Set the property `prop` of `name` to `value`.
+pub(crate) fn set_prop(
+    name: &str,
+    prop: &str,
+    value: &str,
+) -> Result<(), Error> {
+    let prop = format!("{prop}={value}");
+    run(&["set", prop.as_str(), name]).map(drop)
+}
+
+/// The value of the property `prop` of `name`.
+pub(crate) fn get_prop(name: &str, prop: &str) -> Result<String, Error> {
+    Ok(run(&["get", "-H", "-o", "value", prop, name])?
+        .trim()
+        .to_string())
+}
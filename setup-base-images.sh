@@ -8,6 +8,8 @@ set -o nounset
 dataset=${FALCON_DATASET:-rpool/falcon}
 echo "dataset is: $dataset"
 
+base_url=${FALCON_ASSET_BASE_URL:-https://oxide-falcon-assets.s3.us-west-2.amazonaws.com}
+
 # Images follow the naming scheme
 #   <name>-<os_version>-<image_version>
 #
@@ -33,8 +35,8 @@ for img in $images; do
 
     if [[ ! -f $file ]]; then
         echo "Pulling $file"
-        echo "https://oxide-falcon-assets.s3.us-west-2.amazonaws.com/$file"
-        curl -OL https://oxide-falcon-assets.s3.us-west-2.amazonaws.com/$file
+        echo "$base_url/$file"
+        curl -OL $base_url/$file
     fi
     if [[ ! -f $img.raw ]]; then
         echo "Extracting $file"